@@ -1,10 +1,12 @@
 use vergen::{generate_cargo_keys, ConstantsFlags};
-use winres;
 
 fn main() {
     generate_cargo_keys(ConstantsFlags::all()).expect("Unable to generate the cargo keys!");
 
-    let mut res = winres::WindowsResource::new();
-    res.set_icon("compact.ico");
-    res.compile().unwrap();
+    // Icons and version resources only mean anything on Windows.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+        let mut res = winres::WindowsResource::new();
+        res.set_icon("compact.ico");
+        res.compile().unwrap();
+    }
 }
@@ -0,0 +1,870 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
+
+use crate::background::BackgroundHandle;
+use crate::compact::CompressionBackend;
+use crate::compaction::Compaction;
+use crate::compare::Comparison;
+use crate::event::Operation;
+use crate::folder::{FolderInfo, FolderScan};
+use crate::format::format_size;
+use crate::frontend::{Command, Frontend, State};
+use crate::persistence::{config, forget_incompressible};
+
+/// Drives scans, compression and decompression on behalf of a `Frontend`.
+///
+/// Settings and the incompressible-files database come from `persistence`.
+pub struct Backend<F> {
+    frontend: F,
+    msg: Receiver<Command>,
+    info: Option<FolderInfo>,
+    compactor: Arc<dyn CompressionBackend>,
+    workers: Option<usize>,
+}
+
+impl<F: Frontend> Backend<F> {
+    pub fn new(
+        frontend: F,
+        msg: Receiver<Command>,
+        compactor: Arc<dyn CompressionBackend>,
+    ) -> Self {
+        Self {
+            frontend,
+            msg,
+            info: None,
+            compactor,
+            workers: None,
+        }
+    }
+
+    /// Compress with this many threads instead of the configured number.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Process commands until the sender is dropped.
+    pub fn run(&mut self) {
+        loop {
+            match self.msg.recv() {
+                Ok(Command::ChooseFolder) => {
+                    if let Some(path) = self.frontend.choose_folder() {
+                        self.frontend.folder(&path);
+                        self.scan_loop(path);
+                    }
+                }
+                Ok(Command::Scan(path)) => {
+                    self.frontend.folder(&path);
+                    self.scan_loop(path);
+                }
+                Ok(Command::Analyse) if self.info.is_some() => {
+                    let path = self.info.take().unwrap().path;
+                    self.frontend.folder(&path);
+                    self.scan_loop(path);
+                }
+                Ok(Command::Compress) if self.info.is_some() => {
+                    self.compact_loop(Operation::Compress);
+                }
+                Ok(Command::Decompress) if self.info.is_some() => {
+                    self.compact_loop(Operation::Decompress);
+                }
+                Ok(Command::Convert) if self.info.is_some() => {
+                    self.compact_loop(Operation::Convert);
+                }
+                Ok(Command::Recompress) if self.info.is_some() => {
+                    self.compact_loop(Operation::Recompress);
+                }
+                Ok(Command::Compare) if self.info.is_some() => {
+                    self.compare_loop();
+                }
+                Ok(Command::Forget) if self.info.is_some() => {
+                    let info = self.info.take().unwrap();
+                    let msg = match forget_incompressible(&info) {
+                        Ok(n) => format!("Forgot {} incompressible files", n),
+                        Err(e) => format!("Couldn't forget incompressible files: {}", e),
+                    };
+                    self.frontend.folder(&info.path);
+                    self.scan_loop(info.path);
+                    self.frontend.status(&msg, Some(1.0));
+                }
+                Ok(msg) => {
+                    eprintln!("Backend: Ignored message: {:?}", msg);
+                }
+                Err(_) => {
+                    eprintln!("Backend: exit run loop");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn scan_loop(&mut self, path: PathBuf) {
+        let config = config().read().unwrap().current();
+        let excludes = config.globset().expect("globs");
+
+        let mut scanner = FolderScan::new(path, excludes, self.compactor.clone())
+            .with_compression(config.compression);
+        if config.project_savings {
+            scanner = scanner.with_projection(config.compression);
+        }
+        let task = BackgroundHandle::spawn(scanner);
+        let start = Instant::now();
+
+        self.frontend.status("Scanning", None);
+        loop {
+            let msg = self.msg.recv_timeout(Duration::from_millis(25));
+
+            match msg {
+                Ok(Command::Pause) => {
+                    task.pause();
+                    self.frontend.status("Paused", Some(0.5));
+                    self.frontend.state(State::Paused);
+                }
+                Ok(Command::Resume) => {
+                    task.resume();
+                    self.frontend.status("Scanning", None);
+                    self.frontend.state(State::Resumed);
+                }
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                    task.cancel();
+                }
+                Ok(msg) => {
+                    eprintln!("Ignored message: {:?}", msg);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+
+            match task.wait_timeout(Duration::from_millis(25)) {
+                Some(Ok(info)) => {
+                    let msg = match info.errors.len() {
+                        0 => format!("Scanned in {:.2?}", start.elapsed()),
+                        n => format!(
+                            "Scanned in {:.2?}, but {} paths couldn't be read",
+                            start.elapsed(),
+                            n
+                        ),
+                    };
+                    self.frontend.status(&msg, Some(1.0));
+                    self.frontend.summary(info.summary());
+                    self.frontend.state(State::Scanned);
+                    self.info = Some(info);
+                    break;
+                }
+                Some(Err(info)) => {
+                    self.frontend.status(
+                        &format!("Scan stopped after {:.2?}", start.elapsed()),
+                        Some(0.5),
+                    );
+                    self.frontend.summary(info.summary());
+                    self.frontend.state(State::Stopped);
+                    self.info = Some(info);
+                    break;
+                }
+                None => {
+                    if let Some(status) = task.status() {
+                        self.frontend
+                            .status(&format!("Scanning: {}", status.0.display()), None);
+                        self.frontend.summary(status.1);
+                    }
+                }
+            }
+        }
+    }
+
+    fn compare_loop(&mut self) {
+        let comparison = Comparison::new(self.info.as_ref().expect("fileinfo"));
+        let task = BackgroundHandle::spawn(comparison);
+        let start = Instant::now();
+        let mut progress = 0.0;
+
+        self.frontend.state(State::Comparing);
+        self.frontend.status("Comparing", Some(progress));
+        loop {
+            let msg = self.msg.recv_timeout(Duration::from_millis(25));
+
+            match msg {
+                Ok(Command::Pause) => {
+                    task.pause();
+                    self.frontend.status("Paused", Some(progress));
+                    self.frontend.state(State::Paused);
+                }
+                Ok(Command::Resume) => {
+                    task.resume();
+                    self.frontend.status("Comparing", Some(progress));
+                    self.frontend.state(State::Resumed);
+                }
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                    task.cancel();
+                }
+                Ok(msg) => {
+                    eprintln!("Ignored message: {:?}", msg);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+
+            match task.wait_timeout(Duration::from_millis(25)) {
+                Some(Some(comparison)) => {
+                    let msg = match comparison.total.recommended {
+                        Some(compression) => format!(
+                            "Compared algorithms in {:.2?}, {} recommended",
+                            start.elapsed(),
+                            compression
+                        ),
+                        None => format!(
+                            "Compared algorithms in {:.2?}, none worthwhile",
+                            start.elapsed()
+                        ),
+                    };
+                    self.frontend.status(&msg, Some(1.0));
+                    self.frontend.comparison(comparison);
+                    break;
+                }
+                Some(None) => {
+                    self.frontend.status(
+                        &format!("Comparison stopped after {:.2?}", start.elapsed()),
+                        Some(progress),
+                    );
+                    break;
+                }
+                None => {
+                    if let Some((path, pct)) = task.status() {
+                        progress = pct;
+                        self.frontend
+                            .status(&format!("Comparing: {}", path.display()), Some(progress));
+                    }
+                }
+            }
+        }
+
+        self.frontend.state(State::Scanned);
+    }
+
+    // Ph'nglui mglw'nafh Cthulhu R'lyeh wgah'nagl fhtagn.
+    fn compact_loop(&mut self, operation: Operation) {
+        let folder = self.info.take().expect("fileinfo");
+        let config = config().read().unwrap().current();
+
+        let mut compaction = Compaction::new(
+            folder,
+            operation,
+            config.compression,
+            self.workers.or(config.workers),
+            self.compactor.clone(),
+        );
+        let verb = compaction.verb();
+
+        let mut current = String::new();
+        let decimal = config.decimal;
+
+        let mut last_update = Instant::now();
+        let mut paused = false;
+        let mut stopped = false;
+
+        self.frontend.state(State::Compacting);
+
+        self.frontend.status(verb, Some(0.0));
+        loop {
+            while paused && !stopped && compaction.in_flight().len() == 0 {
+                self.frontend.status("Paused", Some(compaction.progress()));
+
+                self.frontend.summary(compaction.folder().summary());
+
+                match self.msg.recv() {
+                    Ok(Command::Pause) => {
+                        paused = true;
+                    }
+                    Ok(Command::Resume) => {
+                        self.frontend.status(verb, Some(compaction.progress()));
+                        self.frontend.state(State::Resumed);
+                        paused = false;
+                        last_update = Instant::now();
+                    }
+                    Ok(Command::Stop) => {
+                        stopped = true;
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(_) => {
+                        stopped = true;
+                        break;
+                    }
+                }
+            }
+
+            if !paused && !stopped {
+                while let Some((_, fi)) = compaction.start_next() {
+                    current = fi.path.display().to_string();
+                }
+            }
+
+            if compaction.in_flight().len() == 0 {
+                if paused && !stopped {
+                    continue;
+                }
+                break;
+            }
+
+            if let Some(result) = compaction.next_result(Duration::from_millis(25)) {
+                if let Some(err) = &result.error {
+                    self.frontend.status(
+                        &format!("Error: {}, {}", err, result.path.display()),
+                        Some(compaction.progress()),
+                    );
+                }
+
+                if operation == Operation::Recompress {
+                    current = format!(
+                        "{}, {} to {}",
+                        result.path.display(),
+                        format_size(result.old_physical_size, decimal),
+                        format_size(result.new_physical_size, decimal)
+                    );
+                }
+            }
+
+            if !paused && !stopped && last_update.elapsed() > Duration::from_millis(50) {
+                self.frontend.status(
+                    &format!("{}: {}", verb, current),
+                    Some(compaction.progress()),
+                );
+                self.frontend.summary(compaction.folder().summary());
+                last_update = Instant::now();
+            }
+
+            match self.msg.try_recv() {
+                Ok(Command::Pause) if !paused => {
+                    self.frontend.status(
+                        &format!("Pausing after {}", describe_in_flight(&compaction)),
+                        Some(compaction.progress()),
+                    );
+                    self.frontend.state(State::Paused);
+                    paused = true;
+                }
+                Ok(Command::Resume) => {
+                    self.frontend.state(State::Resumed);
+                    paused = false;
+                    stopped = false;
+                }
+                Ok(Command::Stop) if !stopped => {
+                    self.frontend.status(
+                        &format!("Stopping after {}", describe_in_flight(&compaction)),
+                        Some(compaction.progress()),
+                    );
+                    stopped = true;
+                }
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => stopped = true,
+            }
+        }
+
+        let progress = compaction.progress();
+        let (folder, summary) = compaction.finish();
+
+        self.frontend
+            .status(&summary.message(decimal), Some(progress));
+        self.frontend.summary(folder.summary());
+        self.frontend.state(State::Scanned);
+
+        self.info = Some(folder);
+    }
+}
+
+// Describe files being worked on, for status messages.
+fn describe_in_flight(compaction: &Compaction) -> String {
+    let mut in_flight = compaction.in_flight();
+    match in_flight.next() {
+        Some(fi) if in_flight.len() == 0 => fi.path.display().to_string(),
+        _ => format!("{} files", compaction.in_flight().len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io;
+    use std::panic::AssertUnwindSafe;
+    use std::path::Path;
+    use std::thread;
+
+    use crossbeam_channel::{unbounded, Sender};
+
+    use super::*;
+    use crate::compact::Compression;
+    use crate::compare::FolderComparison;
+    use crate::folder::{FolderSummary, SkipReason};
+    use crate::mock::MockBackend;
+    use crate::persistence::pathdb;
+    use crate::wof::Provider;
+    use hashfilter::Fingerprint;
+
+    #[derive(Debug, Clone)]
+    enum Recorded {
+        Status(String),
+        Summary(FolderSummary),
+        Folder(PathBuf),
+        State(State),
+        Comparison(FolderComparison),
+    }
+
+    // A Frontend that forwards everything it's told to a channel.
+    struct RecordingFrontend(Sender<Recorded>);
+
+    impl Frontend for RecordingFrontend {
+        fn status(&self, msg: &str, _pct: Option<f32>) {
+            let _ = self.0.send(Recorded::Status(msg.to_owned()));
+        }
+
+        fn summary(&self, info: FolderSummary) {
+            let _ = self.0.send(Recorded::Summary(info));
+        }
+
+        fn comparison(&self, comparison: FolderComparison) {
+            let _ = self.0.send(Recorded::Comparison(comparison));
+        }
+
+        fn folder(&self, path: &Path) {
+            let _ = self.0.send(Recorded::Folder(path.to_owned()));
+        }
+
+        fn state(&self, state: State) {
+            let _ = self.0.send(Recorded::State(state));
+        }
+
+        fn choose_folder(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    // A MockBackend that waits for permission before compressing each file.
+    #[derive(Debug)]
+    struct GatedBackend {
+        mock: MockBackend,
+        gate: AssertUnwindSafe<Receiver<()>>,
+    }
+
+    impl CompressionBackend for GatedBackend {
+        fn supports_compression(&self, path: &Path) -> io::Result<bool> {
+            self.mock.supports_compression(path)
+        }
+
+        fn detect_provider(&self, path: &Path) -> io::Result<Option<Provider>> {
+            self.mock.detect_provider(path)
+        }
+
+        fn compress(&self, path: &Path, file: &File, compression: Compression) -> io::Result<bool> {
+            self.gate
+                .recv_timeout(Duration::from_secs(10))
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "gate timed out"))?;
+            self.mock.compress(path, file, compression)
+        }
+
+        fn uncompress(&self, path: &Path, file: &File) -> io::Result<()> {
+            self.mock.uncompress(path, file)
+        }
+
+        fn clear_ntfs_compression(&self, path: &Path, file: &File) -> io::Result<()> {
+            self.mock.clear_ntfs_compression(path, file)
+        }
+
+        fn size_on_disk(&self, path: &Path) -> io::Result<u64> {
+            self.mock.size_on_disk(path)
+        }
+    }
+
+    struct Harness {
+        commands: Sender<Command>,
+        recorded: Receiver<Recorded>,
+        log: Vec<Recorded>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl Harness {
+        fn new(compactor: Arc<dyn CompressionBackend>) -> Self {
+            Self::start(compactor, None)
+        }
+
+        fn with_workers(compactor: Arc<dyn CompressionBackend>, workers: usize) -> Self {
+            Self::start(compactor, Some(workers))
+        }
+
+        fn start(compactor: Arc<dyn CompressionBackend>, workers: Option<usize>) -> Self {
+            let (commands, commands_rx) = unbounded();
+            let (recorded_tx, recorded) = unbounded();
+            let mut backend = Backend::new(RecordingFrontend(recorded_tx), commands_rx, compactor);
+            if let Some(workers) = workers {
+                backend = backend.with_workers(workers);
+            }
+            let thread = thread::spawn(move || backend.run());
+
+            Self {
+                commands,
+                recorded,
+                log: vec![],
+                thread,
+            }
+        }
+
+        fn send(&self, command: Command) {
+            self.commands.send(command).unwrap();
+        }
+
+        // Wait for the Backend to enter the given state, returning the last summary.
+        fn wait_for(&mut self, state: State) -> Option<FolderSummary> {
+            let mut summary = None;
+
+            loop {
+                let recorded = match self.recorded.recv_timeout(Duration::from_secs(10)) {
+                    Ok(recorded) => recorded,
+                    Err(e) => panic!("waiting for {:?}: {:?}, saw {:?}", state, e, self.log),
+                };
+                self.log.push(recorded.clone());
+
+                match recorded {
+                    Recorded::State(s) if s == state => return summary,
+                    Recorded::Summary(s) => summary = Some(s),
+                    _ => (),
+                }
+            }
+        }
+
+        // Wait for a status message starting with the given prefix.
+        fn wait_for_status(&mut self, prefix: &str) {
+            loop {
+                let recorded = match self.recorded.recv_timeout(Duration::from_secs(10)) {
+                    Ok(recorded) => recorded,
+                    Err(e) => panic!("waiting for {:?}: {:?}, saw {:?}", prefix, e, self.log),
+                };
+                self.log.push(recorded.clone());
+
+                if let Recorded::Status(s) = recorded {
+                    if s.starts_with(prefix) {
+                        return;
+                    }
+                }
+            }
+        }
+
+        // Shut down the Backend, returning everything it sent.
+        fn finish(mut self) -> Vec<Recorded> {
+            drop(self.commands);
+            self.thread.join().unwrap();
+            self.log.extend(self.recorded.try_iter());
+            self.log
+        }
+    }
+
+    fn states(log: &[Recorded]) -> Vec<State> {
+        log.iter()
+            .filter_map(|r| match r {
+                Recorded::State(s) => Some(*s),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn statuses(log: &[Recorded]) -> Vec<&str> {
+        log.iter()
+            .filter_map(|r| match r {
+                Recorded::Status(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn compressible_dir(files: usize) -> tempdir::TempDir {
+        let dir = tempdir::TempDir::new("compactor-backend").unwrap();
+        for i in 0..files {
+            std::fs::write(
+                dir.path().join(format!("{}.txt", i)),
+                "compress me please ".repeat(8192),
+            )
+            .unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn it_scans_compresses_and_decompresses() {
+        let dir = compressible_dir(3);
+        let mut harness = Harness::new(Arc::new(MockBackend::default()));
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressible.count, 3);
+
+        harness.send(Command::Compress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 3);
+        assert!(summary.physical_size < summary.logical_size);
+
+        harness.send(Command::Decompress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressible.count, 3);
+        assert_eq!(summary.compressed.count, 0);
+
+        let log = harness.finish();
+        assert!(matches!(&log[0], Recorded::Folder(path) if path == dir.path()));
+        assert_eq!(
+            states(&log),
+            vec![
+                State::Scanned,
+                State::Compacting,
+                State::Scanned,
+                State::Compacting,
+                State::Scanned
+            ]
+        );
+
+        let statuses = statuses(&log);
+        assert!(statuses.iter().any(|s| s.starts_with("Compacted")));
+        assert!(statuses.last().unwrap().starts_with("Expanded 3 files"));
+    }
+
+    #[test]
+    fn it_leaves_wim_backed_files_alone() {
+        let dir = compressible_dir(3);
+        let wim = dir.path().join("0.txt");
+        let backend = Arc::new(MockBackend::default());
+        backend.set_wim(&wim);
+        let mut harness = Harness::new(backend.clone());
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressible.count, 2);
+        assert_eq!(summary.compressed.count, 0);
+        assert_eq!(summary.wim.count, 1);
+
+        harness.send(Command::Compress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 2);
+        assert_eq!(summary.wim.count, 1);
+
+        harness.send(Command::Decompress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressible.count, 2);
+        assert_eq!(summary.wim.count, 1);
+        assert_eq!(backend.get(&wim), None);
+
+        let log = harness.finish();
+        assert!(statuses(&log)
+            .last()
+            .unwrap()
+            .starts_with("Expanded 2 files"));
+    }
+
+    #[test]
+    fn it_converts_ntfs_compressed_files() {
+        let dir = compressible_dir(3);
+        let backend = Arc::new(MockBackend::default());
+        backend.set_ntfs_compressed(dir.path().join("0.txt"));
+        backend.set_ntfs_compressed(dir.path().join("1.txt"));
+        let mut harness = Harness::new(backend.clone());
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        let before = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(before.ntfs_compressed.count, 2);
+        assert_eq!(before.compressible.count, 1);
+
+        harness.send(Command::Convert);
+        harness.wait_for(State::Compacting);
+        let after = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(after.ntfs_compressed.count, 0);
+        assert_eq!(after.compressed.count, 2);
+        assert_eq!(after.compressible.count, 1);
+        assert!(after.physical_size < before.physical_size);
+        assert_eq!(backend.compressed_count(), 2);
+
+        let log = harness.finish();
+        assert!(statuses(&log)
+            .last()
+            .unwrap()
+            .starts_with("Converted 2 files, saving"));
+    }
+
+    #[test]
+    fn it_recompresses_with_a_different_algorithm() {
+        let dir = compressible_dir(3);
+        let backend = Arc::new(MockBackend::default());
+        let target = config().read().unwrap().current().compression;
+        let other = match target {
+            Compression::Xpress4k => Compression::Lzx,
+            _ => Compression::Xpress4k,
+        };
+        for (name, compression) in &[("0.txt", other), ("1.txt", target)] {
+            let path = dir.path().join(name);
+            let file = File::open(&path).unwrap();
+            assert!(backend.compress(&path, &file, *compression).unwrap());
+        }
+        let mut harness = Harness::new(backend.clone());
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 2);
+        assert_eq!(summary.algorithms.len(), 2);
+
+        harness.send(Command::Recompress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 2);
+        assert_eq!(summary.compressible.count, 1);
+        assert_eq!(summary.algorithms.keys().collect::<Vec<_>>(), vec![&target]);
+        assert_eq!(
+            backend.get(dir.path().join("0.txt")).unwrap().compression,
+            target
+        );
+
+        let log = harness.finish();
+        assert!(statuses(&log)
+            .last()
+            .unwrap()
+            .starts_with("Recompressed 1 files"));
+    }
+
+    #[test]
+    fn it_records_which_algorithm_was_incompressible() {
+        let dir = compressible_dir(2);
+        let incompressible = dir.path().join("0.txt");
+        let backend = Arc::new(MockBackend::default());
+        backend.set_incompressible(&incompressible);
+        let mut harness = Harness::new(backend);
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        harness.wait_for(State::Scanned);
+        harness.send(Command::Compress);
+        harness.wait_for(State::Compacting);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.skip_reasons[&SkipReason::Incompressible].count, 1);
+        harness.finish();
+
+        let fingerprint = Fingerprint::of(&incompressible).unwrap();
+        let attempt = pathdb()
+            .read()
+            .unwrap()
+            .get_file(&incompressible, &fingerprint)
+            .and_then(|record| record.attempt)
+            .unwrap();
+        let compression = config().read().unwrap().current().compression;
+        assert_eq!(attempt.method, u32::from(compression));
+    }
+
+    #[test]
+    fn it_forgets_incompressible_files() {
+        let dir = compressible_dir(3);
+        pathdb()
+            .write()
+            .unwrap()
+            .insert_file(dir.path().join("0.txt"), None)
+            .unwrap();
+        let mut harness = Harness::new(Arc::new(MockBackend::default()));
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.skip_reasons[&SkipReason::Incompressible].count, 1);
+        assert_eq!(summary.compressible.count, 2);
+
+        harness.send(Command::Forget);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert!(summary.skip_reasons.is_empty());
+        assert_eq!(summary.compressible.count, 3);
+        harness.wait_for_status("Forgot 1 incompressible files");
+
+        let log = harness.finish();
+        assert_eq!(states(&log), vec![State::Scanned, State::Scanned]);
+    }
+
+    #[test]
+    fn it_pauses_resumes_and_stops_compression() {
+        let dir = compressible_dir(6);
+        let (gate, gate_rx) = unbounded();
+        let compactor = Arc::new(GatedBackend {
+            mock: MockBackend::default(),
+            gate: AssertUnwindSafe(gate_rx),
+        });
+        let mut harness = Harness::with_workers(compactor.clone(), 2);
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        harness.wait_for(State::Scanned);
+
+        // Pause while the first two files are in flight, finishing them first
+        harness.send(Command::Compress);
+        harness.wait_for(State::Compacting);
+        harness.send(Command::Pause);
+        harness.wait_for(State::Paused);
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        harness.wait_for_status("Paused");
+
+        // Resume, and stop during the next two
+        harness.send(Command::Resume);
+        harness.wait_for(State::Resumed);
+        harness.send(Command::Stop);
+        harness.wait_for_status("Stopping after 2 files");
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 4);
+        assert_eq!(summary.compressible.count, 2);
+        assert_eq!(compactor.mock.compressed_count(), 4);
+
+        let log = harness.finish();
+        assert_eq!(
+            states(&log),
+            vec![
+                State::Scanned,
+                State::Compacting,
+                State::Paused,
+                State::Resumed,
+                State::Scanned
+            ]
+        );
+
+        let statuses = statuses(&log);
+        assert!(statuses.contains(&"Pausing after 2 files"));
+        assert!(statuses.last().unwrap().starts_with("Compacted"));
+    }
+
+    #[test]
+    fn it_compares_algorithms() {
+        let dir = compressible_dir(2);
+        let mut harness = Harness::new(Arc::new(MockBackend::default()));
+
+        harness.send(Command::Scan(dir.path().to_owned()));
+        harness.wait_for(State::Scanned);
+
+        harness.send(Command::Compare);
+        harness.wait_for(State::Comparing);
+        harness.wait_for(State::Scanned);
+
+        // The folder is still there to compress
+        harness.send(Command::Compress);
+        let summary = harness.wait_for(State::Scanned).unwrap();
+        assert_eq!(summary.compressed.count, 2);
+
+        let log = harness.finish();
+        let comparison = log
+            .iter()
+            .find_map(|r| match r {
+                Recorded::Comparison(c) => Some(c),
+                _ => None,
+            })
+            .expect("comparison");
+        assert_eq!(comparison.total.count, 2);
+        assert!(comparison.total.recommended.is_some());
+        assert_eq!(comparison.extensions[0].name, "txt");
+
+        let statuses = statuses(&log);
+        assert!(statuses
+            .iter()
+            .any(|s| s.starts_with("Compared algorithms") && s.ends_with("recommended")));
+    }
+}
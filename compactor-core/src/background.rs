@@ -1,20 +1,22 @@
+//! Tiny thread-backed background job thing
+//!
+//! This is very similar to ffi_helper's Task
+//! <https://github.com/Michael-F-Bryan/ffi_helpers>
+
 use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
 use std::panic::{catch_unwind, UnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-/// Tiny thread-backed background job thing
-///
-/// This is very similar to ffi_helper's Task
-/// https://github.com/Michael-F-Bryan/ffi_helpers
 use std::time::Duration;
 
+/// Shared state for cancelling, pausing, and reporting status from a job.
 #[derive(Debug, Clone)]
 pub struct ControlToken<S>(Arc<ControlTokenInner<S>>);
 
 #[derive(Debug, Default)]
-pub struct ControlTokenInner<S> {
+struct ControlTokenInner<S> {
     cancel: AtomicBool,
     pause: AtomicBool,
     status: Mutex<Option<S>>,
@@ -49,10 +51,12 @@ impl<S> ControlToken<S> {
         self.0.pause.load(Ordering::SeqCst)
     }
 
+    /// Block while paused, then check if we've been cancelled.
     pub fn is_cancelled_with_pause(&self) -> bool {
         self.is_cancelled() || (self.handle_pause() && self.is_cancelled())
     }
 
+    /// Park the current thread while paused, returning true if we did.
     pub fn handle_pause(&self) -> bool {
         let mut paused = false;
 
@@ -64,16 +68,19 @@ impl<S> ControlToken<S> {
         paused
     }
 
+    /// Replace the current status, for collection with `get_status()`.
     pub fn set_status(&self, status: S) {
         let mut previous = self.0.status.lock().expect("status lock");
         previous.replace(status);
     }
 
+    /// Take the latest status, if any has been set since the last call.
     pub fn get_status(&self) -> Option<S> {
         let mut current = self.0.status.lock().expect("status lock");
         current.take()
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn result(&self) -> Result<(), ()> {
         if self.is_cancelled() {
             Err(())
@@ -89,6 +96,9 @@ impl<S> Default for ControlToken<S> {
     }
 }
 
+/// A handle to a `Background` job running on its own thread.
+///
+/// Dropping the handle cancels the job.
 pub struct BackgroundHandle<T, S> {
     result: Receiver<std::thread::Result<T>>,
     control: ControlToken<S>,
@@ -96,6 +106,7 @@ pub struct BackgroundHandle<T, S> {
 }
 
 impl<T, S> BackgroundHandle<T, S> {
+    /// Run `task` on a new thread.
     pub fn spawn<K>(task: K) -> BackgroundHandle<T, S>
     where
         K: Background<Output = T, Status = S> + UnwindSafe + Send + Sync + 'static,
@@ -176,6 +187,10 @@ impl<T, S> Drop for BackgroundHandle<T, S> {
     }
 }
 
+/// A long-running job with a final `Output`, reporting progress as `Status`.
+///
+/// Implementations should check `ControlToken::is_cancelled_with_pause()`
+/// regularly so they can be paused and cancelled.
 pub trait Background: Send + Sync {
     type Output: Send + Sync;
     type Status: Send + Sync;
@@ -184,6 +199,7 @@ pub trait Background: Send + Sync {
 }
 
 #[cfg(test)]
+#[allow(clippy::manual_range_contains)]
 mod tests {
     use super::*;
     use std::time::Duration;
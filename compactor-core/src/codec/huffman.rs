@@ -0,0 +1,210 @@
+//! Canonical Huffman codes, as used by both XPRESS and LZX.
+//!
+//! A code is described entirely by its codeword lengths: codewords are
+//! assigned in order of length and then symbol, and read most significant bit
+//! first.
+
+/// Length-limited codeword lengths for symbols with the given frequencies.
+///
+/// Unused symbols get no codeword.  A complete code needs at least two
+/// symbols, so if only one is used another is added.
+pub(crate) fn code_lengths(freqs: &[u32], max_len: u32) -> Vec<u8> {
+    let mut lens = vec![0u8; freqs.len()];
+    let mut syms = (0..freqs.len())
+        .filter(|&s| freqs[s] > 0)
+        .collect::<Vec<_>>();
+
+    match syms.len() {
+        0 => return lens,
+        1 => syms.push(if syms[0] == 0 { 1 } else { 0 }),
+        _ => (),
+    }
+
+    let weight = |s: usize| u64::from(freqs[s].max(1));
+    syms.sort_by_key(|&s| (weight(s), s));
+
+    // Build the tree with two queues: leaves in ascending weight, and
+    // internal nodes in the order they're made, which is also ascending.
+    let n = syms.len();
+    let mut weights = syms.iter().map(|&s| weight(s)).collect::<Vec<_>>();
+    let mut parent = vec![0; 2 * n - 1];
+    let (mut leaf, mut node) = (0, n);
+
+    for next in n..2 * n - 1 {
+        let mut children = [0; 2];
+        for child in &mut children {
+            if leaf < n && (node >= next || weights[leaf] <= weights[node]) {
+                *child = leaf;
+                leaf += 1;
+            } else {
+                *child = node;
+                node += 1;
+            }
+        }
+
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parent[children[0]] = next;
+        parent[children[1]] = next;
+    }
+
+    let mut depth = vec![0u32; 2 * n - 1];
+    for i in (0..2 * n - 2).rev() {
+        depth[i] = depth[parent[i]] + 1;
+    }
+
+    // Clamp to the maximum length, then lengthen shorter codewords until the
+    // code fits again.
+    let max = max_len as usize;
+    let mut counts = vec![0u32; max + 1];
+    for &d in &depth[..n] {
+        counts[(d as usize).min(max)] += 1;
+    }
+
+    let mut total: u32 = (1..=max).map(|l| counts[l] << (max - l)).sum();
+    while total > 1 << max {
+        counts[max] -= 1;
+        for l in (1..max).rev() {
+            if counts[l] > 0 {
+                counts[l] -= 1;
+                counts[l + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+
+    // Shortest codewords go to the most frequent symbols
+    let mut by_weight = syms.iter().rev();
+    for (len, &count) in counts.iter().enumerate().skip(1) {
+        for &s in by_weight.by_ref().take(count as usize) {
+            lens[s] = len as u8;
+        }
+    }
+
+    lens
+}
+
+/// The codeword for each symbol, given their lengths.
+pub(crate) fn canonical_codes(lens: &[u8]) -> Vec<u32> {
+    let mut codes = vec![0; lens.len()];
+    let mut code = 0;
+
+    for len in 1..=lens.iter().copied().max().unwrap_or(0) {
+        for (s, _) in lens.iter().enumerate().filter(|&(_, &l)| l == len) {
+            codes[s] = code;
+            code += 1;
+        }
+        code <<= 1;
+    }
+
+    codes
+}
+
+/// A lookup table from the next `bits` bits of input to a symbol.
+#[derive(Debug)]
+pub(crate) struct DecodeTable {
+    table: Vec<u16>,
+    bits: u32,
+}
+
+impl DecodeTable {
+    /// Build a table for codewords of at most `bits` bits, or `None` if the
+    /// lengths don't describe a valid code.
+    ///
+    /// Incomplete codes are allowed, but decoding an unassigned codeword
+    /// fails.
+    pub(crate) fn new(lens: &[u8], bits: u32) -> Option<Self> {
+        let mut table = vec![0u16; 1 << bits];
+        let mut code = 0usize;
+
+        for len in 1..=bits {
+            for (symbol, _) in lens
+                .iter()
+                .enumerate()
+                .filter(|&(_, &l)| u32::from(l) == len)
+            {
+                if code >= 1 << len {
+                    return None;
+                }
+
+                let shift = bits - len;
+                table[code << shift..(code + 1) << shift].fill((symbol as u16) << 5 | len as u16);
+                code += 1;
+            }
+            code <<= 1;
+        }
+
+        if lens.iter().any(|&l| u32::from(l) > bits) {
+            return None;
+        }
+
+        Some(Self { table, bits })
+    }
+
+    /// How many bits of input `lookup` wants.
+    pub(crate) fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// The symbol starting the given bits of input, and its codeword length.
+    pub(crate) fn lookup(&self, next: u32) -> Option<(usize, u32)> {
+        match self.table[next as usize] {
+            0 => None,
+            entry => Some((usize::from(entry >> 5), u32::from(entry & 31))),
+        }
+    }
+}
+
+#[test]
+fn it_limits_code_lengths() {
+    // Fibonacci frequencies make the deepest possible tree
+    let mut freqs = [0u32; 512];
+    let (mut a, mut b) = (1u32, 1u32);
+    for freq in freqs.iter_mut().take(40) {
+        *freq = a;
+        let next = a.saturating_add(b);
+        a = b;
+        b = next;
+    }
+
+    let lens = code_lengths(&freqs, 15);
+    assert_eq!(lens.iter().filter(|&&l| l > 0).count(), 40);
+    assert!(lens.iter().all(|&l| l <= 15));
+    assert!(lens[39] <= lens[0]);
+
+    let kraft: u32 = lens
+        .iter()
+        .filter(|&&l| l > 0)
+        .map(|&l| 1 << (15 - u32::from(l)))
+        .sum();
+    assert_eq!(kraft, 1 << 15);
+}
+
+#[test]
+fn it_decodes_canonical_codes() {
+    let mut freqs = [0u32; 8];
+    assert!(code_lengths(&freqs, 7).iter().all(|&l| l == 0));
+
+    freqs[5] = 10;
+    assert_eq!(code_lengths(&freqs, 7), vec![1, 0, 0, 0, 0, 1, 0, 0]);
+
+    freqs[2] = 5;
+    freqs[7] = 5;
+    let lens = code_lengths(&freqs, 7);
+    assert_eq!(lens, vec![0, 0, 2, 0, 0, 1, 0, 2]);
+
+    let codes = canonical_codes(&lens);
+    assert_eq!((codes[5], codes[2], codes[7]), (0b0, 0b10, 0b11));
+
+    let table = DecodeTable::new(&lens, 7).unwrap();
+    assert_eq!(table.lookup(0b011_0000), Some((5, 1)));
+    assert_eq!(table.lookup(0b100_0000), Some((2, 2)));
+    assert_eq!(table.lookup(0b111_1111), Some((7, 2)));
+
+    assert!(DecodeTable::new(&[1, 1, 1], 7).is_none());
+    assert!(DecodeTable::new(&[1, 8], 7).is_none());
+    assert!(DecodeTable::new(&[1, 0], 7)
+        .unwrap()
+        .lookup(0b100_0000)
+        .is_none());
+}
@@ -0,0 +1,113 @@
+//! Hash chain match finding, as used by both XPRESS and LZX.
+
+const MIN_MATCH: usize = 3;
+const HASH_BITS: u32 = 15;
+const WINDOW_MASK: usize = 65535;
+const MAX_CHAIN: usize = 32;
+const NICE_MATCH: usize = 258;
+const NONE: u32 = u32::MAX;
+
+/// Finds earlier occurrences of the data at each position.
+///
+/// Positions must be `insert`ed in order to be found by later searches.
+/// Offsets can be up to 64 KiB.
+pub(crate) struct MatchFinder<'a> {
+    data: &'a [u8],
+    max_len: usize,
+    max_offset: usize,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl<'a> MatchFinder<'a> {
+    pub(crate) fn new(data: &'a [u8], max_len: usize, max_offset: usize) -> Self {
+        assert!(max_offset <= WINDOW_MASK);
+
+        Self {
+            data,
+            max_len,
+            max_offset,
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_MASK + 1],
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let d = &self.data[pos..pos + MIN_MATCH];
+        let v = u32::from(d[0]) | u32::from(d[1]) << 8 | u32::from(d[2]) << 16;
+        (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    pub(crate) fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let h = self.hash(pos);
+            self.prev[pos & WINDOW_MASK] = self.head[h];
+            self.head[h] = pos as u32;
+        }
+    }
+
+    /// The longest match of at least 3 bytes for the data at `pos`, not
+    /// extending past `end`, as (length, offset).
+    pub(crate) fn find(&self, pos: usize, end: usize) -> Option<(usize, usize)> {
+        let max = (end - pos).min(self.max_len);
+        if max < MIN_MATCH {
+            return None;
+        }
+
+        let data = &self.data[pos..pos + max];
+        let mut best = (0, 0);
+        let mut cand = self.head[self.hash(pos)];
+
+        for _ in 0..MAX_CHAIN {
+            let c = cand as usize;
+            if cand == NONE || c >= pos || pos - c > self.max_offset {
+                break;
+            }
+
+            if self.data[c + best.0] == data[best.0] {
+                let len = common_len(&self.data[c..], data);
+
+                if len > best.0 {
+                    best = (len, pos - c);
+                    if len == max || len >= NICE_MATCH {
+                        break;
+                    }
+                }
+            }
+
+            cand = self.prev[c & WINDOW_MASK];
+        }
+
+        Some(best).filter(|&(len, _)| len >= MIN_MATCH)
+    }
+}
+
+/// The length of the common prefix of `a` and `b`.
+pub(crate) fn common_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[test]
+fn it_finds_matches() {
+    let data = b"abcdefabcdxyzabcdefabcdef";
+    let mut finder = MatchFinder::new(data, 258, 65535);
+
+    for pos in 0..13 {
+        let expected = match pos {
+            6 => Some((4, 6)),
+            7 => Some((3, 6)),
+            _ => None,
+        };
+        assert_eq!(finder.find(pos, data.len()), expected);
+        finder.insert(pos);
+    }
+
+    assert_eq!(finder.find(13, data.len()), Some((10, 13)));
+    assert_eq!(finder.find(13, 16), Some((3, 7)));
+
+    let mut short = MatchFinder::new(data, 258, 7);
+    for pos in 0..13 {
+        short.insert(pos);
+    }
+    assert_eq!(short.find(13, data.len()), Some((4, 7)));
+}
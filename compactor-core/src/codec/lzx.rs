@@ -0,0 +1,910 @@
+//! LZX, in the variant used by WOF and WIM files.
+//!
+//! Each chunk of up to 32 KiB is compressed independently, as one or more
+//! blocks.  Verbatim and aligned offset blocks hold Huffman coded literals and
+//! matches, with codeword lengths delta coded against the previous block's via
+//! a small "pretree" code.  Uncompressed blocks hold raw bytes.  The bitstream
+//! is made of little-endian 16-bit words, read most significant bit first.
+//!
+//! Before compression the targets of x86 CALL instructions - 0xE8 followed by
+//! a 32-bit offset - are made absolute, so repeated calls to the same function
+//! look the same.  Unlike LZX in cabinet files, this is always done, with no
+//! header, and as if the file were 12,000,000 bytes long.
+
+use std::io;
+
+use super::huffman::{canonical_codes, code_lengths, DecodeTable};
+use super::lz::{common_len, MatchFinder};
+
+/// The largest chunk LZX can compress, and the size WOF uses.
+pub const CHUNK_SIZE: usize = 32768;
+
+const BLOCK_VERBATIM: u32 = 1;
+const BLOCK_ALIGNED: u32 = 2;
+const BLOCK_UNCOMPRESSED: u32 = 3;
+
+const NUM_CHARS: usize = 256;
+const NUM_LEN_HEADERS: usize = 8;
+const NUM_PRIMARY_LENS: usize = 7;
+const NUM_OFFSET_SLOTS: usize = 30;
+const NUM_RECENT_OFFSETS: usize = 3;
+const MAIN_SYMBOLS: usize = NUM_CHARS + NUM_OFFSET_SLOTS * NUM_LEN_HEADERS;
+const LEN_SYMBOLS: usize = 249;
+const PRE_SYMBOLS: usize = 20;
+const ALIGNED_SYMBOLS: usize = 8;
+const ALIGNED_BITS: u32 = 3;
+
+const MAX_MAIN_LEN: u32 = 16;
+const MAX_LEN_LEN: u32 = 16;
+const MAX_PRE_LEN: u32 = 15;
+const MAX_ALIGNED_LEN: u32 = 7;
+
+const MIN_MATCH: usize = 2;
+const MAX_MATCH: usize = 257;
+// Explicit offsets are stored plus 2, and must fit the offset slots.
+const OFFSET_ADJUSTMENT: usize = 2;
+const MAX_OFFSET: usize = CHUNK_SIZE - 3;
+
+const E8_FILE_SIZE: i32 = 12_000_000;
+
+// Matches at least this long aren't worth checking for a better one.
+const LAZY_LIMIT: usize = 32;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("LZX: {}", msg))
+}
+
+/// Compress a chunk of at most `CHUNK_SIZE` bytes as LZX.
+///
+/// # Panics
+///
+/// If `input` is larger than `CHUNK_SIZE`.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    assert!(input.len() <= CHUNK_SIZE, "LZX chunks are at most 32 KiB");
+
+    if input.is_empty() {
+        return vec![];
+    }
+
+    let mut data = input.to_vec();
+    e8_encode(&mut data);
+
+    let tokens = parse(&data);
+    write_block(&tokens, data.len())
+}
+
+/// Decompress an LZX chunk, which should expand to `size` bytes.
+pub fn decompress(input: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    if size > CHUNK_SIZE {
+        return Err(invalid("chunk too large"));
+    }
+
+    let mut out = Vec::with_capacity(size);
+    let mut bits = BitReader::new(input, 0);
+    let mut main_lens = [0u8; MAIN_SYMBOLS];
+    let mut len_lens = [0u8; LEN_SYMBOLS];
+    let mut recent = [1usize; NUM_RECENT_OFFSETS];
+
+    while out.len() < size {
+        let block_type = bits.read(3);
+        let block_size = match bits.read(1) {
+            1 => CHUNK_SIZE,
+            _ => bits.read(16) as usize,
+        };
+        let block_end = out.len() + block_size.min(size - out.len());
+
+        let mut aligned = None;
+        match block_type {
+            BLOCK_ALIGNED | BLOCK_VERBATIM => {
+                if block_type == BLOCK_ALIGNED {
+                    let mut lens = [0u8; ALIGNED_SYMBOLS];
+                    for len in &mut lens {
+                        *len = bits.read(3) as u8;
+                    }
+                    aligned = Some(table(&lens, MAX_ALIGNED_LEN)?);
+                }
+
+                read_lens(&mut bits, &mut main_lens[..NUM_CHARS])?;
+                read_lens(&mut bits, &mut main_lens[NUM_CHARS..])?;
+                read_lens(&mut bits, &mut len_lens)?;
+            }
+            BLOCK_UNCOMPRESSED => {
+                let start = bits.align();
+                let header = input
+                    .get(start..start + 12)
+                    .ok_or_else(|| invalid("truncated uncompressed block"))?;
+                for (offset, bytes) in recent.iter_mut().zip(header.chunks(4)) {
+                    *offset = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+                }
+                if recent.contains(&0) {
+                    return Err(invalid("invalid recent offset"));
+                }
+
+                let len = block_end - out.len();
+                let data = input
+                    .get(start + 12..start + 12 + len)
+                    .ok_or_else(|| invalid("truncated uncompressed block"))?;
+                out.extend_from_slice(data);
+
+                // Padded to keep the bitstream 16-bit aligned
+                bits = BitReader::new(input, start + 12 + len + (len & 1));
+                continue;
+            }
+            _ => return Err(invalid("invalid block type")),
+        }
+
+        let main = table(&main_lens, MAX_MAIN_LEN)?;
+        let lengths = table(&len_lens, MAX_LEN_LEN)?;
+
+        while out.len() < block_end {
+            let symbol = bits.decode(&main)?;
+
+            if symbol < NUM_CHARS {
+                out.push(symbol as u8);
+                continue;
+            }
+
+            let symbol = symbol - NUM_CHARS;
+            let mut len = symbol % NUM_LEN_HEADERS;
+            let slot = symbol / NUM_LEN_HEADERS;
+
+            if len == NUM_PRIMARY_LENS {
+                len += bits.decode(&lengths)?;
+            }
+            len += MIN_MATCH;
+
+            let offset = if slot < NUM_RECENT_OFFSETS {
+                // Not a true LRU queue: R1 and R2 just swap with R0
+                let offset = recent[slot];
+                recent[slot] = recent[0];
+                offset
+            } else {
+                let extra = extra_bits(slot);
+                let mut offset = slot_base(slot);
+
+                match &aligned {
+                    Some(aligned) if extra >= ALIGNED_BITS => {
+                        offset += (bits.read(extra - ALIGNED_BITS) as usize) << ALIGNED_BITS;
+                        offset += bits.decode(aligned)?;
+                    }
+                    _ => offset += bits.read(extra) as usize,
+                }
+
+                recent[2] = recent[1];
+                recent[1] = recent[0];
+                offset - OFFSET_ADJUSTMENT
+            };
+            recent[0] = offset;
+
+            if offset > out.len() {
+                return Err(invalid("match offset before start of data"));
+            }
+            if len > block_end - out.len() {
+                return Err(invalid("match past end of block"));
+            }
+
+            // Matches may overlap themselves, so copy a byte at a time
+            let from = out.len() - offset;
+            for i in from..from + len {
+                out.push(out[i]);
+            }
+        }
+
+        if bits.overrun() {
+            return Err(invalid("truncated bitstream"));
+        }
+    }
+
+    e8_decode(&mut out);
+    Ok(out)
+}
+
+fn table(lens: &[u8], max_len: u32) -> io::Result<DecodeTable> {
+    DecodeTable::new(lens, max_len).ok_or_else(|| invalid("invalid Huffman code lengths"))
+}
+
+// The first adjusted offset in an offset slot.
+fn slot_base(slot: usize) -> usize {
+    if slot < 4 {
+        slot
+    } else {
+        (2 | (slot & 1)) << (slot / 2 - 1)
+    }
+}
+
+// The number of extra bits following an offset slot.
+fn extra_bits(slot: usize) -> u32 {
+    if slot < 4 {
+        0
+    } else {
+        (slot / 2 - 1) as u32
+    }
+}
+
+// The offset slot an adjusted offset falls in.
+fn offset_slot(adjusted: usize) -> usize {
+    if adjusted < 4 {
+        adjusted
+    } else {
+        let high = (usize::BITS - 1 - adjusted.leading_zeros()) as usize;
+        2 * high + ((adjusted >> (high - 1)) & 1)
+    }
+}
+
+// Read codeword lengths, delta coded against the previous lengths.
+fn read_lens(bits: &mut BitReader, lens: &mut [u8]) -> io::Result<()> {
+    let mut pre_lens = [0u8; PRE_SYMBOLS];
+    for len in &mut pre_lens {
+        *len = bits.read(4) as u8;
+    }
+    let pre = table(&pre_lens, MAX_PRE_LEN)?;
+
+    let delta = |old: u8, presym: usize| ((old as usize + 17 - presym) % 17) as u8;
+    let mut i = 0;
+
+    while i < lens.len() {
+        let presym = bits.decode(&pre)?;
+
+        let (run, len) = match presym {
+            0..=16 => (1, delta(lens[i], presym)),
+            17 => (4 + bits.read(4) as usize, 0),
+            18 => (20 + bits.read(5) as usize, 0),
+            _ => {
+                let run = 4 + bits.read(1) as usize;
+                match bits.decode(&pre)? {
+                    presym @ 0..=17 => (run, delta(lens[i], presym)),
+                    _ => return Err(invalid("invalid pretree run")),
+                }
+            }
+        };
+
+        // Runs may overshoot, which is harmless
+        let end = (i + run).min(lens.len());
+        lens[i..end].fill(len);
+        i = end;
+    }
+
+    Ok(())
+}
+
+fn e8_filter<F: FnMut(i32, i32) -> Option<i32>>(data: &mut [u8], mut translate: F) {
+    // A CALL can't start in the last 10 bytes
+    if data.len() <= 10 {
+        return;
+    }
+
+    let mut pos = 0;
+    while pos < data.len() - 10 {
+        if data[pos] != 0xE8 {
+            pos += 1;
+            continue;
+        }
+
+        let target = &mut data[pos + 1..pos + 5];
+        let value = i32::from_le_bytes([target[0], target[1], target[2], target[3]]);
+        if let Some(value) = translate(value, pos as i32) {
+            target.copy_from_slice(&value.to_le_bytes());
+        }
+        pos += 5;
+    }
+}
+
+// Make CALL targets absolute.
+fn e8_encode(data: &mut [u8]) {
+    e8_filter(data, |rel, pos| {
+        if rel >= -pos && rel < E8_FILE_SIZE {
+            if rel < E8_FILE_SIZE - pos {
+                Some(rel + pos)
+            } else {
+                Some(rel - E8_FILE_SIZE)
+            }
+        } else {
+            None
+        }
+    });
+}
+
+// Make CALL targets relative again.
+fn e8_decode(data: &mut [u8]) {
+    e8_filter(data, |abs, pos| {
+        if abs >= 0 {
+            if abs < E8_FILE_SIZE {
+                Some(abs - pos)
+            } else {
+                None
+            }
+        } else if abs >= -pos {
+            Some(abs + E8_FILE_SIZE)
+        } else {
+            None
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match {
+        len: usize,
+        offset: usize,
+        // Which recent offset this reuses, if any
+        recent: Option<usize>,
+    },
+}
+
+impl Token {
+    fn match_len(&self) -> usize {
+        match *self {
+            Token::Literal(_) => 0,
+            // Recent offsets are cheap, so count as a little longer
+            Token::Match { len, recent, .. } => len + recent.map_or(0, |_| 1),
+        }
+    }
+}
+
+// The best match at `pos`, if any, considering recent offsets.
+fn find(finder: &MatchFinder, data: &[u8], pos: usize, recent: &[usize]) -> Option<Token> {
+    let max = (data.len() - pos).min(MAX_MATCH);
+    let mut best = None;
+
+    for (i, &offset) in recent.iter().enumerate() {
+        if offset <= pos {
+            let len = common_len(&data[pos - offset..], &data[pos..pos + max]);
+            if len >= MIN_MATCH && len + 1 > best.map_or(0, |t: Token| t.match_len()) {
+                best = Some(Token::Match {
+                    len,
+                    offset,
+                    recent: Some(i),
+                });
+            }
+        }
+    }
+
+    if let Some((len, offset)) = finder.find(pos, data.len()) {
+        if len > best.map_or(0, |t| t.match_len()) {
+            best = Some(Token::Match {
+                len,
+                offset,
+                recent: recent.iter().position(|&r| r == offset),
+            });
+        }
+    }
+
+    best
+}
+
+// Greedy parsing into literals and matches, with one step of lazy evaluation.
+fn parse(data: &[u8]) -> Vec<Token> {
+    let mut finder = MatchFinder::new(data, MAX_MATCH, MAX_OFFSET);
+    let mut recent = [1usize; NUM_RECENT_OFFSETS];
+    let mut tokens = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let found = find(&finder, data, pos, &recent);
+        finder.insert(pos);
+
+        let token = match found {
+            Some(token) => token,
+            None => {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+                continue;
+            }
+        };
+
+        // Prefer a literal if the next position has a longer match
+        if token.match_len() < LAZY_LIMIT && pos + 1 < data.len() {
+            if let Some(next) = find(&finder, data, pos + 1, &recent) {
+                if next.match_len() > token.match_len() {
+                    tokens.push(Token::Literal(data[pos]));
+                    pos += 1;
+                    continue;
+                }
+            }
+        }
+
+        if let Token::Match {
+            len,
+            offset,
+            recent: reused,
+        } = token
+        {
+            match reused {
+                Some(i) => recent.swap(0, i),
+                None => recent = [offset, recent[0], recent[1]],
+            }
+
+            for p in pos + 1..pos + len {
+                finder.insert(p);
+            }
+            pos += len;
+        }
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+// A match's main symbol, length symbol, and offset extra bits.
+fn match_symbols(
+    len: usize,
+    offset: usize,
+    recent: Option<usize>,
+) -> (usize, Option<usize>, (u32, u32)) {
+    let (slot, extra) = match recent {
+        Some(i) => (i, (0, 0)),
+        None => {
+            let adjusted = offset + OFFSET_ADJUSTMENT;
+            let slot = offset_slot(adjusted);
+            (
+                slot,
+                ((adjusted - slot_base(slot)) as u32, extra_bits(slot)),
+            )
+        }
+    };
+
+    let header = (len - MIN_MATCH).min(NUM_PRIMARY_LENS);
+    let len_symbol = if header == NUM_PRIMARY_LENS {
+        Some(len - MIN_MATCH - NUM_PRIMARY_LENS)
+    } else {
+        None
+    };
+
+    (
+        NUM_CHARS + slot * NUM_LEN_HEADERS + header,
+        len_symbol,
+        extra,
+    )
+}
+
+// Write a single verbatim or aligned offset block, whichever is smaller.
+fn write_block(tokens: &[Token], size: usize) -> Vec<u8> {
+    let mut main_freqs = [0u32; MAIN_SYMBOLS];
+    let mut len_freqs = [0u32; LEN_SYMBOLS];
+    let mut aligned_freqs = [0u32; ALIGNED_SYMBOLS];
+    let mut aligned_uses = 0;
+
+    for token in tokens {
+        match *token {
+            Token::Literal(b) => main_freqs[b as usize] += 1,
+            Token::Match {
+                len,
+                offset,
+                recent,
+            } => {
+                let (main, len_symbol, (value, extra)) = match_symbols(len, offset, recent);
+                main_freqs[main] += 1;
+                if let Some(len_symbol) = len_symbol {
+                    len_freqs[len_symbol] += 1;
+                }
+                if extra >= ALIGNED_BITS {
+                    aligned_freqs[(value & 7) as usize] += 1;
+                    aligned_uses += 1;
+                }
+            }
+        }
+    }
+
+    let main_lens = code_lengths(&main_freqs, MAX_MAIN_LEN);
+    let len_lens = code_lengths(&len_freqs, MAX_LEN_LEN);
+    let aligned_lens = code_lengths(&aligned_freqs, MAX_ALIGNED_LEN);
+
+    // Aligned blocks code the low 3 bits of larger offsets, at the cost of
+    // a table of 8 3-bit lengths.
+    let aligned_cost: u32 = (0..ALIGNED_SYMBOLS)
+        .map(|s| aligned_freqs[s] * u32::from(aligned_lens[s]))
+        .sum::<u32>()
+        + ALIGNED_SYMBOLS as u32 * 3;
+    let aligned = aligned_uses > 0 && aligned_cost < aligned_uses * ALIGNED_BITS;
+
+    let mut bits = BitWriter::default();
+    bits.write(
+        if aligned {
+            BLOCK_ALIGNED
+        } else {
+            BLOCK_VERBATIM
+        },
+        3,
+    );
+    if size == CHUNK_SIZE {
+        bits.write(1, 1);
+    } else {
+        bits.write(0, 1);
+        bits.write(size as u32, 16);
+    }
+
+    if aligned {
+        for &len in &aligned_lens {
+            bits.write(len.into(), 3);
+        }
+    }
+
+    write_lens(&mut bits, &main_lens[..NUM_CHARS]);
+    write_lens(&mut bits, &main_lens[NUM_CHARS..]);
+    write_lens(&mut bits, &len_lens);
+
+    let main_codes = canonical_codes(&main_lens);
+    let len_codes = canonical_codes(&len_lens);
+    let aligned_codes = canonical_codes(&aligned_lens);
+
+    for token in tokens {
+        match *token {
+            Token::Literal(b) => {
+                bits.write(main_codes[b as usize], main_lens[b as usize].into());
+            }
+            Token::Match {
+                len,
+                offset,
+                recent,
+            } => {
+                let (main, len_symbol, (value, extra)) = match_symbols(len, offset, recent);
+                bits.write(main_codes[main], main_lens[main].into());
+                if let Some(s) = len_symbol {
+                    bits.write(len_codes[s], len_lens[s].into());
+                }
+
+                if aligned && extra >= ALIGNED_BITS {
+                    let low = (value & 7) as usize;
+                    bits.write(value >> ALIGNED_BITS, extra - ALIGNED_BITS);
+                    bits.write(aligned_codes[low], aligned_lens[low].into());
+                } else {
+                    bits.write(value, extra);
+                }
+            }
+        }
+    }
+
+    bits.finish()
+}
+
+// Write codeword lengths, delta coded against all zeros, preceded by the
+// pretree needed to read them.
+fn write_lens(bits: &mut BitWriter, lens: &[u8]) {
+    // (presym, extra bits value, extra bits, second presym)
+    let mut items = vec![];
+    let delta = |len: u8| (17 - len as usize) % 17;
+    let mut i = 0;
+
+    while i < lens.len() {
+        let run = lens[i..].iter().take_while(|&&l| l == lens[i]).count();
+
+        if lens[i] == 0 && run >= 20 {
+            let run = run.min(51);
+            items.push((18, run - 20, 5, None));
+            i += run;
+        } else if lens[i] == 0 && run >= 4 {
+            let run = run.min(19);
+            items.push((17, run - 4, 4, None));
+            i += run;
+        } else if run >= 4 {
+            let run = run.min(5);
+            items.push((19, run - 4, 1, Some(delta(lens[i]))));
+            i += run;
+        } else {
+            items.push((delta(lens[i]), 0, 0, None));
+            i += 1;
+        }
+    }
+
+    let mut freqs = [0u32; PRE_SYMBOLS];
+    for &(presym, _, _, second) in &items {
+        freqs[presym] += 1;
+        if let Some(second) = second {
+            freqs[second] += 1;
+        }
+    }
+
+    let pre_lens = code_lengths(&freqs, MAX_PRE_LEN);
+    let pre_codes = canonical_codes(&pre_lens);
+
+    for &len in &pre_lens {
+        bits.write(len.into(), 4);
+    }
+
+    for (presym, value, extra, second) in items {
+        bits.write(pre_codes[presym], pre_lens[presym].into());
+        bits.write(value as u32, extra);
+        if let Some(second) = second {
+            bits.write(pre_codes[second], pre_lens[second].into());
+        }
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits = (self.bits << n) | u64::from(value);
+        self.count += n;
+
+        while self.count >= 16 {
+            self.count -= 16;
+            let word = (self.bits >> self.count) as u16;
+            self.out.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let word = (self.bits << (16 - self.count)) as u16;
+            self.out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        self.out
+    }
+}
+
+// Reads the bitstream a word at a time as needed, with zeros past the end
+// of the input so a truncated stream can be detected afterwards.
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8], pos: usize) -> Self {
+        Self {
+            input,
+            pos,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        while self.count < n {
+            let word = match self.input.get(self.pos..self.pos + 2) {
+                Some(word) => u16::from_le_bytes([word[0], word[1]]),
+                None => 0,
+            };
+            self.pos += 2;
+            self.bits |= u64::from(word) << (48 - self.count);
+            self.count += 16;
+        }
+
+        (self.bits >> (64 - n)) as u32
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.bits <<= n;
+        self.count -= n;
+    }
+
+    fn read(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    fn decode(&mut self, table: &DecodeTable) -> io::Result<usize> {
+        let (symbol, len) = table
+            .lookup(self.peek(table.bits()))
+            .ok_or_else(|| invalid("invalid Huffman code"))?;
+        self.consume(len);
+
+        Ok(symbol)
+    }
+
+    // Where the next unread word starts.
+    fn word_pos(&self) -> usize {
+        self.pos - (self.count / 16) as usize * 2
+    }
+
+    fn overrun(&self) -> bool {
+        self.word_pos() > self.input.len()
+    }
+
+    // Skip to the next 16-bit boundary - or past a whole word if already on
+    // one, as the format requires - and return the byte position there.
+    fn align(&mut self) -> usize {
+        match self.count % 16 {
+            0 => {
+                self.read(16);
+            }
+            partial => self.consume(partial),
+        }
+
+        let pos = self.word_pos();
+        *self = Self::new(self.input, pos);
+        pos
+    }
+}
+
+#[cfg(test)]
+use super::noise;
+
+#[test]
+fn it_maps_offset_slots() {
+    for slot in 0..NUM_OFFSET_SLOTS {
+        let base = slot_base(slot);
+        let last = base + (1 << extra_bits(slot)) - 1;
+        assert_eq!(offset_slot(base), slot);
+        assert_eq!(offset_slot(last), slot);
+        assert_eq!(slot_base(slot + 1), last + 1);
+    }
+
+    assert_eq!(
+        offset_slot(MAX_OFFSET + OFFSET_ADJUSTMENT),
+        NUM_OFFSET_SLOTS - 1
+    );
+}
+
+#[test]
+fn it_decodes_uncompressed_blocks() {
+    // Block type 3, an explicit size of 12, padding to the next word, the
+    // recent offsets, and the data - with a CALL to translate back.
+    let mut input = vec![0x00, 0x60, 0x00, 0xC0];
+    input.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+    input.extend_from_slice(&[0x00, 0xE8, 0x20, 0, 0, 0, 1, 2, 3, 4, 5, 6]);
+
+    assert_eq!(
+        decompress(&input, 12).unwrap(),
+        vec![0x00, 0xE8, 0x1F, 0, 0, 0, 1, 2, 3, 4, 5, 6]
+    );
+
+    // A zero recent offset is invalid
+    input[4] = 0;
+    assert!(decompress(&input, 12).is_err());
+}
+
+#[test]
+fn it_decodes_reference_chunks() {
+    // Fixed chunks and what they decompress to.  Each was checked against
+    // libarchive's LZX decoder by wrapping it in a cabinet with an E8
+    // translation size of 12,000,000, the only other differences being the
+    // block headers.
+    let chunks: [(&str, &[u8], &[u8]); 4] = [
+        // Starts with a match at recent offset 0, which must begin as 1
+        (
+            "recent-offsets",
+            include_bytes!("testdata/lzx/recent-offsets.lzx"),
+            include_bytes!("testdata/lzx/recent-offsets.out"),
+        ),
+        // CALLs to translate, including one wrapped past the file size and
+        // others left alone
+        (
+            "e8-translation",
+            include_bytes!("testdata/lzx/e8-translation.lzx"),
+            include_bytes!("testdata/lzx/e8-translation.out"),
+        ),
+        (
+            "aligned-offsets",
+            include_bytes!("testdata/lzx/aligned-offsets.lzx"),
+            include_bytes!("testdata/lzx/aligned-offsets.out"),
+        ),
+        // A verbatim block, then an odd-sized uncompressed one whose header
+        // ends on a word boundary, so is followed by a whole word of padding,
+        // then a verbatim block with lengths delta coded against the first
+        // that reuses the uncompressed block's recent offsets
+        (
+            "blocks",
+            include_bytes!("testdata/lzx/blocks.lzx"),
+            include_bytes!("testdata/lzx/blocks.out"),
+        ),
+    ];
+
+    for (name, chunk, expected) in &chunks {
+        let decompressed = decompress(chunk, expected.len()).unwrap();
+        assert!(decompressed == *expected, "{}", name);
+    }
+
+    // Making sure the aligned offset chunk really is an aligned offset block
+    let aligned = chunks[2].1;
+    assert_eq!(
+        u16::from_le_bytes([aligned[0], aligned[1]]) >> 13,
+        BLOCK_ALIGNED as u16
+    );
+}
+
+#[test]
+fn it_translates_calls() {
+    let mut data = vec![0u8; 60];
+    for (pos, target) in [(0, 100i32), (10, -5), (20, -30), (30, 11_999_990), (50, 7)] {
+        data[pos] = 0xE8;
+        data[pos + 1..pos + 5].copy_from_slice(&target.to_le_bytes());
+    }
+    let original = data.clone();
+
+    e8_encode(&mut data);
+    assert_eq!(&data[1..5], &100i32.to_le_bytes());
+    assert_eq!(&data[11..15], &5i32.to_le_bytes());
+    // Before the start of the data, so left alone
+    assert_eq!(&data[21..25], &(-30i32).to_le_bytes());
+    // Past the magic file size, so wrapped around
+    assert_eq!(&data[31..35], &(-10i32).to_le_bytes());
+    // Too close to the end
+    assert_eq!(&data[51..55], &7i32.to_le_bytes());
+
+    e8_decode(&mut data);
+    assert_eq!(data, original);
+}
+
+#[test]
+fn it_round_trips() {
+    let text = b"It was the best of times, it was the worst of times, ".repeat(700);
+    let mut calls = noise(CHUNK_SIZE, 1);
+    for i in (0..calls.len()).step_by(7) {
+        calls[i] = 0xE8;
+    }
+
+    let cases: Vec<Vec<u8>> = vec![
+        vec![42],
+        b"ab".to_vec(),
+        b"abcabcabcabcabc".to_vec(),
+        vec![0xE8; 11],
+        vec![0; 4096],
+        vec![0; CHUNK_SIZE - 1],
+        vec![0; CHUNK_SIZE],
+        noise(4096, 2),
+        noise(CHUNK_SIZE, 3),
+        text[..CHUNK_SIZE].to_vec(),
+        text[..CHUNK_SIZE - 1001].to_vec(),
+        calls,
+    ];
+
+    for data in cases {
+        let compressed = compress(&data);
+        let decompressed = decompress(&compressed, data.len()).unwrap();
+        assert!(decompressed == data, "round trip of {} bytes", data.len());
+    }
+
+    assert!(compress(&[]).is_empty());
+    assert!(decompress(&[], 0).unwrap().is_empty());
+}
+
+#[test]
+fn it_uses_aligned_blocks() {
+    // 8-byte records copied from far back leave offsets whose low bits are
+    // always the same, which an aligned offset block codes almost for free.
+    let records = noise(8 * 2048, 4);
+    let mut data = records.clone();
+    let mut state = 1usize;
+    while data.len() < CHUNK_SIZE {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let record = (state >> 8) % 2048 * 8;
+        data.extend_from_slice(&records[record..record + 8]);
+    }
+
+    let compressed = compress(&data);
+    assert_eq!(
+        u16::from_le_bytes([compressed[0], compressed[1]]) >> 13,
+        BLOCK_ALIGNED as u16
+    );
+    assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+}
+
+#[test]
+fn it_compresses() {
+    let text = b"It was the best of times, it was the worst of times, ".repeat(700);
+    let text = &text[..CHUNK_SIZE];
+    assert!(compress(text).len() < text.len() / 8);
+    assert!(compress(&[0; CHUNK_SIZE]).len() < 128);
+}
+
+#[test]
+fn it_rejects_bad_input() {
+    let data = b"It was the best of times, it was the worst of times".repeat(100);
+    let compressed = compress(&data);
+
+    assert!(decompress(&compressed[..compressed.len() / 2], data.len()).is_err());
+    assert!(decompress(&compressed, CHUNK_SIZE + 1).is_err());
+
+    // Block types 0 and 4-7 don't exist
+    let mut bad = compressed.clone();
+    bad[1] &= 0x1F;
+    assert!(decompress(&bad, data.len()).is_err());
+    bad[1] |= 0xE0;
+    assert!(decompress(&bad, data.len()).is_err());
+}
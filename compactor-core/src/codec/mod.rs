@@ -0,0 +1,53 @@
+//! Pure-Rust implementations of the compression formats WOF uses.
+//!
+//! These let the effect of compression be predicted, and its results checked,
+//! on any platform - they never touch the filesystem.
+//!
+//! * `xpress` - XPRESS Huffman, used by XPRESS4K, XPRESS8K and XPRESS16K.
+//! * `lzx` - LZX with 32 KiB chunks, used by LZX.
+
+mod huffman;
+mod lz;
+pub mod lzx;
+pub mod xpress;
+
+use std::io;
+
+use crate::compact::Compression;
+
+/// Compress one chunk of a file as WOF would with `compression`.
+///
+/// # Panics
+///
+/// If `chunk` is larger than `compression`'s chunk size.
+pub fn compress_chunk(compression: Compression, chunk: &[u8]) -> Vec<u8> {
+    assert!(chunk.len() <= compression.chunk_size());
+
+    match compression {
+        Compression::Lzx => lzx::compress(chunk),
+        _ => xpress::compress(chunk),
+    }
+}
+
+/// Decompress one chunk of a file compressed by WOF with `compression`,
+/// which should expand to `size` bytes.
+pub fn decompress_chunk(compression: Compression, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::Lzx => lzx::decompress(data, size),
+        _ => xpress::decompress(data, size),
+    }
+}
+
+// Repeatable incompressible data.
+#[cfg(test)]
+pub(crate) fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
@@ -0,0 +1,473 @@
+//! XPRESS Huffman, or LZ77+Huffman, as specified in [MS-XCA].
+//!
+//! Data is compressed in blocks of 64 KiB of uncompressed input.  Each block
+//! starts with a 256 byte table of 4-bit code lengths for 512 symbols - 256
+//! literals and 256 match headers - followed by a bitstream of little-endian
+//! 16-bit words, read most significant bit first.  Extra match length bytes
+//! are interleaved with the words, at the point the decoder reads them.
+//!
+//! WOF compresses each chunk of a file separately, and chunks are never more
+//! than 64 KiB, so they're always a single block.
+//!
+//! [MS-XCA]: https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-xca/
+
+use std::convert::TryInto;
+use std::io;
+
+use super::huffman::{canonical_codes, code_lengths, DecodeTable};
+use super::lz::MatchFinder;
+
+const BLOCK_SIZE: usize = 65536;
+const SYMBOLS: usize = 512;
+const TABLE_SIZE: usize = SYMBOLS / 2;
+const MAX_CODE_LEN: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 65535 + MIN_MATCH;
+const MAX_OFFSET: usize = 65535;
+
+// A match of length 3 at offset 1, which doubles as an end of stream marker.
+const EOF_SYMBOL: usize = 256;
+
+// Matches at least this long aren't worth checking for a better one.
+const LAZY_LIMIT: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { len: usize, offset: usize },
+}
+
+impl Token {
+    fn symbol(self) -> usize {
+        match self {
+            Token::Literal(b) => b as usize,
+            Token::Match { len, offset } => {
+                256 + (offset_bits(offset) as usize) * 16 + (len - MIN_MATCH).min(15)
+            }
+        }
+    }
+}
+
+fn offset_bits(offset: usize) -> u32 {
+    usize::BITS - 1 - offset.leading_zeros()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("XPRESS Huffman: {}", msg),
+    )
+}
+
+/// Compress `input` as XPRESS Huffman.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + TABLE_SIZE + 4);
+    let mut finder = MatchFinder::new(input, MAX_MATCH, MAX_OFFSET);
+    let mut tokens = vec![];
+    let mut start = 0;
+
+    loop {
+        let end = (start + BLOCK_SIZE).min(input.len());
+        let last = end == input.len();
+
+        tokens.clear();
+        parse(&mut finder, input, start, end, &mut tokens);
+        write_block(&mut out, &tokens, last);
+
+        if last {
+            return out;
+        }
+        start = end;
+    }
+}
+
+/// Decompress XPRESS Huffman `input`, which should expand to `size` bytes.
+pub fn decompress(input: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+
+    while out.len() < size {
+        let table = input
+            .get(pos..pos + TABLE_SIZE)
+            .ok_or_else(|| invalid("truncated block"))?;
+        let lens = table
+            .iter()
+            .flat_map(|&pair| [pair & 15, pair >> 4])
+            .collect::<Vec<_>>();
+        let decoder = DecodeTable::new(&lens, MAX_CODE_LEN)
+            .ok_or_else(|| invalid("invalid Huffman code lengths"))?;
+        let mut bits = BitReader::new(input, pos + TABLE_SIZE)?;
+        let block_end = (out.len() + BLOCK_SIZE).min(size);
+
+        while out.len() < block_end {
+            let symbol = bits.decode(&decoder)?;
+
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            }
+
+            let mut len = (symbol - 256) & 15;
+            if len == 15 {
+                len = bits.byte()? as usize;
+                if len == 255 {
+                    len = bits.u16()? as usize;
+                    if len == 0 {
+                        len = bits.u32()? as usize;
+                    }
+                    if len < 15 {
+                        return Err(invalid("bad match length"));
+                    }
+                    len -= 15;
+                }
+                len += 15;
+            }
+            len += MIN_MATCH;
+
+            let extra = ((symbol - 256) >> 4) as u32;
+            let offset = (1 << extra) + bits.read(extra)? as usize;
+
+            if offset > out.len() {
+                return Err(invalid("match offset before start of data"));
+            }
+            if len > size - out.len() {
+                return Err(invalid("match past end of data"));
+            }
+
+            // Matches may overlap themselves, so copy a byte at a time
+            let from = out.len() - offset;
+            for i in from..from + len {
+                out.push(out[i]);
+            }
+        }
+
+        pos = bits.pos;
+    }
+
+    Ok(out)
+}
+
+fn write_block(out: &mut Vec<u8>, tokens: &[Token], last: bool) {
+    let mut freqs = [0u32; SYMBOLS];
+    for token in tokens {
+        freqs[token.symbol()] += 1;
+    }
+    if last {
+        freqs[EOF_SYMBOL] += 1;
+    }
+
+    let lens = code_lengths(&freqs, MAX_CODE_LEN);
+    let codes = canonical_codes(&lens);
+
+    out.extend(lens.chunks(2).map(|pair| pair[0] | pair[1] << 4));
+
+    let mut bits = BitWriter::new(out);
+    for &token in tokens {
+        let symbol = token.symbol();
+        bits.write(codes[symbol], lens[symbol].into());
+
+        if let Token::Match { len, offset } = token {
+            let len = len - MIN_MATCH;
+            if len >= 15 + 255 {
+                bits.bytes(&[255]);
+                bits.bytes(&(len as u16).to_le_bytes());
+            } else if len >= 15 {
+                bits.bytes(&[(len - 15) as u8]);
+            }
+
+            let extra = offset_bits(offset);
+            bits.write((offset - (1 << extra)) as u32, extra);
+        }
+    }
+
+    if last {
+        bits.write(codes[EOF_SYMBOL], lens[EOF_SYMBOL].into());
+    }
+
+    bits.finish();
+}
+
+// Writes bits into 16-bit words, reserving space for each word at the point
+// the decoder will read it, so bytes written in between land where it
+// expects them.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    slots: [usize; 2],
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        let start = out.len();
+        out.extend_from_slice(&[0; 4]);
+
+        Self {
+            out,
+            slots: [start, start + 2],
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits = (self.bits << n) | value;
+        self.count += n;
+
+        // The decoder reads another word once it's more than 16 bits in
+        if self.count > 16 {
+            self.count -= 16;
+            self.put((self.bits >> self.count) as u16);
+            self.slots = [self.slots[1], self.out.len()];
+            self.out.extend_from_slice(&[0; 2]);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn put(&mut self, word: u16) {
+        self.out[self.slots[0]..self.slots[0] + 2].copy_from_slice(&word.to_le_bytes());
+    }
+
+    fn finish(mut self) {
+        if self.count > 0 {
+            self.put((self.bits << (16 - self.count)) as u16);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u32,
+    extra: i32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8], pos: usize) -> io::Result<Self> {
+        let mut reader = Self {
+            input,
+            pos,
+            bits: 0,
+            extra: 16,
+        };
+        reader.bits = u32::from(reader.u16()?) << 16;
+        reader.bits |= u32::from(reader.u16()?);
+
+        Ok(reader)
+    }
+
+    fn decode(&mut self, table: &DecodeTable) -> io::Result<usize> {
+        let (symbol, len) = table
+            .lookup(self.bits >> (32 - table.bits()))
+            .ok_or_else(|| invalid("invalid Huffman code"))?;
+        self.consume(len)?;
+
+        Ok(symbol)
+    }
+
+    fn consume(&mut self, n: u32) -> io::Result<()> {
+        self.bits <<= n;
+        self.extra -= n as i32;
+
+        if self.extra < 0 {
+            self.bits |= u32::from(self.u16()?) << -self.extra;
+            self.extra += 16;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, n: u32) -> io::Result<u32> {
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let value = self.bits >> (32 - n);
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let bytes = self
+            .input
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| invalid("truncated bitstream"))?;
+        self.pos += N;
+
+        Ok(bytes.try_into().expect("N bytes"))
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+}
+
+// Greedy parsing into literals and matches, with one step of lazy evaluation.
+fn parse(finder: &mut MatchFinder, data: &[u8], start: usize, end: usize, tokens: &mut Vec<Token>) {
+    let mut pos = start;
+
+    while pos < end {
+        let found = finder.find(pos, end);
+        finder.insert(pos);
+
+        let (len, offset) = match found {
+            Some(m) => m,
+            None => {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+                continue;
+            }
+        };
+
+        // Prefer a literal if the next position has a longer match
+        if len < LAZY_LIMIT {
+            if let Some((next, _)) = finder.find(pos + 1, end) {
+                if next > len {
+                    tokens.push(Token::Literal(data[pos]));
+                    pos += 1;
+                    continue;
+                }
+            }
+        }
+
+        tokens.push(Token::Match { len, offset });
+        for p in pos + 1..pos + len {
+            finder.insert(p);
+        }
+        pos += len;
+    }
+}
+
+#[cfg(test)]
+use super::noise;
+
+#[test]
+fn it_decodes_literals() {
+    // 'a' and 'b' with 1-bit codes 0 and 1, spelling out "abba"
+    let mut input = vec![0; TABLE_SIZE];
+    input[0x61 / 2] = 0x10;
+    input[0x62 / 2] = 0x01;
+    input.extend_from_slice(&[0x00, 0x60, 0x00, 0x00]);
+
+    assert_eq!(decompress(&input, 4).unwrap(), b"abba");
+}
+
+#[test]
+fn it_decodes_matches() {
+    // 'a', then a match with a 1-bit code and length 15 + 3 + 2, the 2 being
+    // an extra byte following the two words read up front.
+    let mut input = vec![0; TABLE_SIZE];
+    input[0x61 / 2] = 0x10;
+    input[(256 + 15) / 2] = 0x10;
+    input.extend_from_slice(&[0x00, 0x40, 0x00, 0x00, 0x02]);
+
+    assert_eq!(decompress(&input, 21).unwrap(), vec![b'a'; 21]);
+}
+
+#[test]
+fn it_decodes_ms_xca_examples() {
+    // The LZ77+Huffman worked examples from MS-XCA section 3.2, as a sparse
+    // table of code lengths followed by the bitstream
+    let block = |lens: &[(usize, u8)], stream: &[u8]| {
+        let mut input = vec![0; TABLE_SIZE];
+        for &(i, pair) in lens {
+            input[i] = pair;
+        }
+        input.extend_from_slice(stream);
+        input
+    };
+
+    // Literals only: a-v get 5-bit codes, w-z and end of stream 4-bit ones
+    let mut lens = vec![(0x30, 0x50), (0x3b, 0x45), (0x3c, 0x44), (0x3d, 0x04)];
+    lens.extend((0x31..0x3b).map(|i| (i, 0x55)));
+    lens.push((0x80, 0x04));
+    let input = block(
+        &lens,
+        &[
+            0xd8, 0x52, 0x3e, 0xd7, 0x94, 0x11, 0x5b, 0xe9, 0x19, 0x5f, 0xf9, 0xd6, 0x7c, 0xdf,
+            0x8d, 0x04, 0x00, 0x00, 0x00, 0x00,
+        ],
+    );
+    assert_eq!(
+        decompress(&input, 26).unwrap(),
+        b"abcdefghijklmnopqrstuvwxyz"
+    );
+
+    // "abc" then a single match of 297 at offset 3, its length in an extra
+    // byte and word between the bitstream's first two words and the rest
+    let input = block(
+        &[(0x30, 0x30), (0x31, 0x23), (0x80, 0x02), (0x8f, 0x20)],
+        &[0xa8, 0xdc, 0x00, 0x00, 0xff, 0x26, 0x01],
+    );
+    assert_eq!(decompress(&input, 300).unwrap(), b"abc".repeat(100));
+}
+
+#[test]
+fn it_round_trips() {
+    let text = b"It was the best of times, it was the worst of times, ".repeat(2000);
+    let mut mixed = noise(40000, 1);
+    mixed.extend_from_slice(&text[..30000]);
+    mixed.extend(vec![0; 70000]);
+    mixed.extend(noise(1000, 2));
+
+    let cases: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![42],
+        b"abcabcabc".to_vec(),
+        vec![0; 4096],
+        vec![0; 65535],
+        vec![0; 65536],
+        vec![0; 65537],
+        vec![7; 200_000],
+        noise(4096, 3),
+        noise(BLOCK_SIZE * 2 + 3, 4),
+        text,
+        mixed,
+    ];
+
+    for data in cases {
+        let compressed = compress(&data);
+        let decompressed = decompress(&compressed, data.len()).unwrap();
+        assert!(decompressed == data, "round trip of {} bytes", data.len());
+    }
+}
+
+#[test]
+fn it_compresses() {
+    let text = b"It was the best of times, it was the worst of times, ".repeat(80);
+    assert!(compress(&text).len() < text.len() / 4);
+    assert!(compress(&[0; 65536]).len() < TABLE_SIZE + 32);
+
+    // Incompressible data grows by little more than the table
+    let random = noise(16384, 5);
+    assert!(compress(&random).len() < random.len() + TABLE_SIZE + 64);
+}
+
+#[test]
+fn it_rejects_bad_input() {
+    let data = b"It was the best of times, it was the worst of times".repeat(10);
+    let compressed = compress(&data);
+
+    assert!(decompress(&compressed[..100], data.len()).is_err());
+    assert!(decompress(&compressed[..compressed.len() / 2], data.len()).is_err());
+
+    // Every symbol with a 1-bit code
+    let mut bad = compressed.clone();
+    bad[..TABLE_SIZE].fill(0x11);
+    assert!(decompress(&bad, data.len()).is_err());
+
+    // No symbols at all
+    bad[..TABLE_SIZE].fill(0);
+    assert!(decompress(&bad, data.len()).is_err());
+}
@@ -0,0 +1,159 @@
+//! Compression algorithms and the backends that apply them.
+
+use std::convert::TryFrom;
+use std::fs::{File, Metadata};
+use std::io;
+use std::panic::RefUnwindSafe;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::platform::{self, NativeBackend};
+use crate::wof::Provider;
+
+// Algorithm identifiers as used by the WOF file provider.
+const FILE_PROVIDER_COMPRESSION_XPRESS4K: u32 = 0;
+const FILE_PROVIDER_COMPRESSION_LZX: u32 = 1;
+const FILE_PROVIDER_COMPRESSION_XPRESS8K: u32 = 2;
+const FILE_PROVIDER_COMPRESSION_XPRESS16K: u32 = 3;
+
+/// A WOF compression algorithm.
+///
+/// The XPRESS variants differ only in chunk size, trading speed for ratio.
+/// LZX is much slower, but compresses best.
+#[derive(
+    Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Compression {
+    Xpress4k,
+    #[default]
+    Xpress8k,
+    Xpress16k,
+    Lzx,
+}
+
+impl Compression {
+    /// Every algorithm, fastest first.
+    pub const ALL: [Compression; 4] = [
+        Compression::Xpress4k,
+        Compression::Xpress8k,
+        Compression::Xpress16k,
+        Compression::Lzx,
+    ];
+
+    /// The size of the chunks WOF compresses files in, each independently.
+    pub fn chunk_size(self) -> usize {
+        match self {
+            Compression::Xpress4k => 4096,
+            Compression::Xpress8k => 8192,
+            Compression::Xpress16k => 16384,
+            Compression::Lzx => 32768,
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Compression::Xpress4k => write!(f, "XPRESS4k"),
+            Compression::Xpress8k => write!(f, "XPRESS8K"),
+            Compression::Xpress16k => write!(f, "XPRESS16K"),
+            Compression::Lzx => write!(f, "LZX"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "XPRESS4K" => Ok(Compression::Xpress4k),
+            "XPRESS8K" => Ok(Compression::Xpress8k),
+            "XPRESS16K" => Ok(Compression::Xpress16k),
+            "LZX" => Ok(Compression::Lzx),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<u32> for Compression {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            FILE_PROVIDER_COMPRESSION_XPRESS4K => Ok(Compression::Xpress4k),
+            FILE_PROVIDER_COMPRESSION_XPRESS8K => Ok(Compression::Xpress8k),
+            FILE_PROVIDER_COMPRESSION_XPRESS16K => Ok(Compression::Xpress16k),
+            FILE_PROVIDER_COMPRESSION_LZX => Ok(Compression::Lzx),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Compression> for u32 {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::Xpress4k => FILE_PROVIDER_COMPRESSION_XPRESS4K,
+            Compression::Xpress8k => FILE_PROVIDER_COMPRESSION_XPRESS8K,
+            Compression::Xpress16k => FILE_PROVIDER_COMPRESSION_XPRESS16K,
+            Compression::Lzx => FILE_PROVIDER_COMPRESSION_LZX,
+        }
+    }
+}
+
+/// The filesystem operations needed to inspect and change file compression.
+///
+/// Paths are passed alongside open handles so implementations can use
+/// whichever is more convenient: `WofBackend` talks to the handle, while
+/// `MockBackend` keys its state on the path.
+pub trait CompressionBackend: std::fmt::Debug + Send + Sync + RefUnwindSafe {
+    /// Check if the volume containing `path` supports compression.
+    fn supports_compression(&self, path: &Path) -> io::Result<bool>;
+
+    /// Return what WOF is backing `path` with, if anything.
+    fn detect_provider(&self, path: &Path) -> io::Result<Option<Provider>>;
+
+    /// Return the algorithm `path` is currently compressed with, if any.
+    ///
+    /// WIM-backed files have none, as they weren't compressed by us.
+    fn detect_compression(&self, path: &Path) -> io::Result<Option<Compression>> {
+        match self.detect_provider(path)? {
+            Some(Provider::File(compression)) => Ok(Some(compression)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Compress an open file, returning `Ok(false)` if it wasn't beneficial.
+    fn compress(&self, path: &Path, file: &File, compression: Compression) -> io::Result<bool>;
+
+    /// Decompress an open file.
+    fn uncompress(&self, path: &Path, file: &File) -> io::Result<()>;
+
+    /// Compress an open, already compressed file with a different algorithm.
+    ///
+    /// If the new algorithm isn't beneficial the file is left uncompressed.
+    fn recompress(&self, path: &Path, file: &File, compression: Compression) -> io::Result<bool> {
+        self.uncompress(path, file)?;
+        self.compress(path, file, compression)
+    }
+
+    /// Remove legacy NTFS compression from an open file, so WOF can compress
+    /// it.
+    fn clear_ntfs_compression(&self, path: &Path, file: &File) -> io::Result<()>;
+
+    /// The physical size of `path`, after any compression.
+    fn size_on_disk(&self, path: &Path) -> io::Result<u64>;
+
+    /// Windows-style `FILE_ATTRIBUTE_*` flags for `path`.
+    fn file_attributes(&self, _path: &Path, metadata: &Metadata) -> u32 {
+        platform::file_attributes(metadata)
+    }
+}
+
+/// The compression backend for the platform we're running on.
+pub fn default_backend() -> Arc<dyn CompressionBackend> {
+    Arc::new(NativeBackend::default())
+}
@@ -0,0 +1,476 @@
+//! Compressing, decompressing, converting or recompressing a scanned folder.
+//!
+//! A `Compaction` hands files from the right group of a `FolderInfo` to a
+//! pool of `BackgroundCompactor`s, and files each result back into the group
+//! it now belongs in, recording any that didn't compress in the
+//! incompressible-files database.  When to start files, and what to show for
+//! each, is up to the caller - see `Backend` and the command line.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::background::BackgroundHandle;
+use crate::compact::{Compression, CompressionBackend};
+use crate::compression::{default_workers, spawn_compactors, Compacted};
+use crate::event::{Operation, Outcome};
+use crate::folder::{FileInfo, FileKind, FolderInfo, SkipReason};
+use crate::format::format_size;
+use crate::persistence::{incompressible_with, pathdb, record_path, resolve_folder};
+
+// How often the incompressible-files database is saved along the way.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happened to a file.
+#[derive(Debug)]
+pub struct FileResult {
+    /// Relative to the folder.
+    pub path: PathBuf,
+    pub full_path: PathBuf,
+    pub outcome: Outcome,
+    pub logical_size: u64,
+    pub old_compression: Option<Compression>,
+    pub old_physical_size: u64,
+    /// The file's algorithm afterwards, if it's compressed.
+    pub new_compression: Option<Compression>,
+    pub new_physical_size: u64,
+    pub error: Option<io::Error>,
+}
+
+/// The results of a finished `Compaction`.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionSummary {
+    pub operation: Operation,
+    /// Files worked on, including any that failed.
+    pub files: usize,
+    pub failed: usize,
+    /// The size on disk of the compressible group beforehand.
+    pub compressible_size: u64,
+    pub old_physical_size: u64,
+    pub new_physical_size: u64,
+    pub elapsed: Duration,
+}
+
+impl CompactionSummary {
+    /// Describe the results, like "Compacted 2 GiB in 300 files, saving 1 GiB
+    /// in 20.00s".
+    pub fn message(&self, decimal: bool) -> String {
+        let (old_size, new_size) = (self.old_physical_size, self.new_physical_size);
+        let done_verb = match self.operation {
+            Operation::Recompress => "Recompressed",
+            _ => "Converted",
+        };
+
+        match self.operation {
+            Operation::Compress => format!(
+                "Compacted {} in {} files, saving {} in {:.2?}",
+                format_size(self.compressible_size, decimal),
+                self.files,
+                format_size(old_size.saturating_sub(new_size), decimal),
+                self.elapsed
+            ),
+            Operation::Convert | Operation::Recompress if new_size > old_size => format!(
+                "{} {} files, using {} more in {:.2?}",
+                done_verb,
+                self.files,
+                format_size(new_size - old_size, decimal),
+                self.elapsed
+            ),
+            Operation::Convert | Operation::Recompress => format!(
+                "{} {} files, saving {} in {:.2?}",
+                done_verb,
+                self.files,
+                format_size(old_size - new_size, decimal),
+                self.elapsed
+            ),
+            Operation::Decompress => format!(
+                "Expanded {} files wasting {} in {:.2?}",
+                self.files,
+                format_size(new_size.saturating_sub(old_size), decimal),
+                self.elapsed
+            ),
+        }
+    }
+}
+
+/// An operation on a folder in progress.
+///
+/// Call `start_next` until it returns `None` to keep every worker busy, and
+/// `next_result` to collect what they've done, until nothing is in flight.
+/// Stopping early is just a matter of not starting any more files.  `finish`
+/// then returns the updated `FolderInfo`.
+pub struct Compaction {
+    folder: FolderInfo,
+    operation: Operation,
+    compression: Option<Compression>,
+    from: FileKind,
+    backend: Arc<dyn CompressionBackend>,
+    workers: usize,
+    send_file: Sender<(PathBuf, u64)>,
+    recv_result: Receiver<(PathBuf, io::Result<Compacted>)>,
+    tasks: Vec<BackgroundHandle<(), ()>>,
+    // Files currently with the workers, by full path
+    in_flight: HashMap<PathBuf, FileInfo>,
+    remaining: usize,
+    total: usize,
+    done: usize,
+    failed: usize,
+    compressible_size: u64,
+    old_size: u64,
+    resolved: PathBuf,
+    start: Instant,
+    last_save: Instant,
+}
+
+impl Compaction {
+    /// Start `operation` on `folder`, compressing with `compression` unless
+    /// decompressing.  Without a number of `workers`, `default_workers` picks
+    /// one.
+    pub fn new(
+        folder: FolderInfo,
+        operation: Operation,
+        compression: Compression,
+        workers: Option<usize>,
+        backend: Arc<dyn CompressionBackend>,
+    ) -> Self {
+        let mut folder = folder;
+
+        let compression = match operation {
+            Operation::Decompress => None,
+            _ => Some(compression),
+        };
+
+        let from = match operation {
+            Operation::Compress => FileKind::Compressible,
+            Operation::Decompress | Operation::Recompress => FileKind::Compressed,
+            Operation::Convert => FileKind::NtfsCompressed,
+        };
+
+        let workers = workers
+            .unwrap_or_else(|| default_workers(&folder.path))
+            .max(1);
+
+        let (send_file, send_file_rx) = bounded::<(PathBuf, u64)>(workers);
+        let (recv_result_tx, recv_result) = bounded::<(PathBuf, io::Result<Compacted>)>(workers);
+        let tasks = spawn_compactors(
+            workers,
+            backend.clone(),
+            compression,
+            send_file_rx,
+            recv_result_tx,
+        );
+
+        // Recompressed files rejoin the group they came from, so take each
+        // file once, and put back any that already use the new algorithm
+        let remaining = folder.len(from);
+        let total = match operation {
+            Operation::Recompress => folder
+                .compressed
+                .files
+                .iter()
+                .filter(|fi| fi.compression != compression)
+                .count(),
+            _ => remaining,
+        };
+
+        let _ = pathdb().write().unwrap().load();
+
+        Self {
+            compressible_size: folder.summary().compressible.physical_size,
+            old_size: folder.physical_size,
+            resolved: resolve_folder(&folder.path),
+            folder,
+            operation,
+            compression,
+            from,
+            backend,
+            workers,
+            send_file,
+            recv_result,
+            tasks,
+            in_flight: HashMap::new(),
+            remaining,
+            total,
+            done: 0,
+            failed: 0,
+            start: Instant::now(),
+            last_save: Instant::now(),
+        }
+    }
+
+    /// The algorithm files are being compressed with, or `None` if they're
+    /// being decompressed.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// What's being done, for status messages, like "Compacting".
+    pub fn verb(&self) -> &'static str {
+        match self.operation {
+            Operation::Compress => "Compacting",
+            Operation::Decompress => "Expanding",
+            Operation::Convert => "Converting",
+            Operation::Recompress => "Recompressing",
+        }
+    }
+
+    /// The folder as it stands.
+    pub fn folder(&self) -> &FolderInfo {
+        &self.folder
+    }
+
+    /// The number of files finished so far.
+    pub fn done(&self) -> usize {
+        self.done
+    }
+
+    /// The number of files to work on in all.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The fraction of files finished so far.
+    pub fn progress(&self) -> f32 {
+        self.done as f32 / self.total as f32
+    }
+
+    /// Files currently being worked on.
+    pub fn in_flight(&self) -> impl ExactSizeIterator<Item = &FileInfo> {
+        self.in_flight.values()
+    }
+
+    /// Hand the next file to a worker, if one is free and there are any left,
+    /// returning its full path and details.
+    pub fn start_next(&mut self) -> Option<(&Path, &FileInfo)> {
+        while self.in_flight.len() < self.workers && self.remaining > 0 {
+            self.remaining -= 1;
+
+            let fi = match self.folder.pop(self.from) {
+                Some(fi)
+                    if self.operation == Operation::Recompress
+                        && fi.compression == self.compression =>
+                {
+                    self.folder.push(self.from, fi);
+                    continue;
+                }
+                Some(fi) => fi,
+                None => break,
+            };
+
+            let path = record_path(&self.folder.path, &fi.path);
+            self.send_file
+                .send((path.clone(), fi.logical_size))
+                .expect("send_file");
+            self.in_flight.insert(path.clone(), fi);
+
+            return self
+                .in_flight
+                .get_key_value(&path)
+                .map(|(path, fi)| (path.as_path(), fi));
+        }
+
+        None
+    }
+
+    /// Wait up to `timeout` for a file to finish, and file it into the group
+    /// it now belongs in.
+    pub fn next_result(&mut self, timeout: Duration) -> Option<FileResult> {
+        let (path, result) = match self.recv_result.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => {
+                // The workers are gone, so put back what they had
+                for (_, fi) in self.in_flight.drain() {
+                    self.folder.push(self.from, fi);
+                }
+                self.remaining = 0;
+                return None;
+            }
+        };
+
+        let mut fi = self.in_flight.remove(&path).expect("in-flight file");
+        let old_compression = fi.compression;
+        let old_physical_size = fi.physical_size;
+        self.done += 1;
+
+        // Conversion may have cleared NTFS compression even if WOF didn't
+        // follow, and recompression may have decompressed
+        if matches!(self.operation, Operation::Convert | Operation::Recompress) {
+            fi.physical_size = self.backend.size_on_disk(&path).unwrap_or(fi.physical_size);
+        }
+
+        let mut error = None;
+        let (kind, outcome) = match (self.compression, result) {
+            (Some(compression), Ok(Compacted::Done)) => {
+                fi.compression = Some(compression);
+                fi.physical_size = self.backend.size_on_disk(&path).unwrap_or(fi.physical_size);
+
+                // Irritatingly Windows can return success when it fails.
+                if fi.physical_size == fi.logical_size {
+                    self.incompressible(&fi, compression, 1.0);
+                    fi.skip_reason = Some(SkipReason::Incompressible);
+                    (FileKind::Skipped, Outcome::Incompressible)
+                } else {
+                    (FileKind::Compressed, Outcome::Compressed)
+                }
+            }
+            // The old algorithm may have been better, so don't remember
+            // these as incompressible
+            (Some(_), Ok(Compacted::Incompressible { .. }))
+                if self.operation == Operation::Recompress =>
+            {
+                if fi.physical_size < fi.logical_size {
+                    (FileKind::Compressed, Outcome::Incompressible)
+                } else {
+                    (FileKind::Compressible, Outcome::Decompressed)
+                }
+            }
+            (Some(compression), Ok(Compacted::Incompressible { ratio })) => {
+                self.incompressible(&fi, compression, ratio);
+                fi.skip_reason = Some(SkipReason::Incompressible);
+                (FileKind::Skipped, Outcome::Incompressible)
+            }
+            (None, Ok(_)) => {
+                fi.physical_size = fi.logical_size;
+                (FileKind::Compressible, Outcome::Decompressed)
+            }
+            (_, Err(e)) => {
+                self.failed += 1;
+                error = Some(e);
+                fi.skip_reason = Some(SkipReason::Failed);
+                (FileKind::Skipped, Outcome::Failed)
+            }
+        };
+
+        if self.last_save.elapsed() > SAVE_INTERVAL {
+            let _ = pathdb().write().unwrap().save();
+            self.last_save = Instant::now();
+        }
+
+        let result = FileResult {
+            path: fi.path.clone(),
+            full_path: path,
+            outcome,
+            logical_size: fi.logical_size,
+            old_compression,
+            old_physical_size,
+            new_compression: match kind {
+                FileKind::Compressed => fi.compression,
+                _ => None,
+            },
+            new_physical_size: fi.physical_size,
+            error,
+        };
+        self.folder.push(kind, fi);
+
+        Some(result)
+    }
+
+    /// Wait for the workers to exit, and return the folder and how it went.
+    ///
+    /// Any files still in flight are waited for, but not filed back into the
+    /// folder, so collect them with `next_result` first.
+    pub fn finish(self) -> (FolderInfo, CompactionSummary) {
+        drop(self.send_file);
+        drop(self.recv_result);
+        for task in self.tasks {
+            task.wait();
+        }
+
+        let _ = pathdb().write().unwrap().save();
+
+        let summary = CompactionSummary {
+            operation: self.operation,
+            files: self.done,
+            failed: self.failed,
+            compressible_size: self.compressible_size,
+            old_physical_size: self.old_size,
+            new_physical_size: self.folder.physical_size,
+            elapsed: self.start.elapsed(),
+        };
+
+        (self.folder, summary)
+    }
+
+    fn incompressible(&self, fi: &FileInfo, compression: Compression, ratio: f32) {
+        let attempt = incompressible_with(compression, ratio);
+        let _ = pathdb()
+            .write()
+            .unwrap()
+            .insert_file(record_path(&self.resolved, &fi.path), Some(attempt));
+    }
+}
+
+#[test]
+fn it_compacts_folders() {
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::config::Config;
+    use crate::folder::FolderScan;
+    use crate::mock::MockBackend;
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("compactor-test").unwrap();
+    for i in 0..5 {
+        let mut file = File::create(dir.path().join(format!("{}.txt", i))).unwrap();
+        for _ in 0..2000 {
+            writeln!(file, "It was the best of times, it was the worst of times").unwrap();
+        }
+    }
+
+    let backend = Arc::new(MockBackend::default());
+    let incompressible = dir.path().join("0.txt");
+    backend.set_incompressible(&incompressible);
+
+    let excludes = Config::default().globset().unwrap();
+    let scan = FolderScan::new(dir.path(), excludes, backend.clone());
+    let folder = BackgroundHandle::spawn(scan).wait().unwrap();
+    assert_eq!(folder.summary().compressible.count, 5);
+
+    let mut compaction = Compaction::new(
+        folder,
+        Operation::Compress,
+        Compression::Lzx,
+        Some(2),
+        backend.clone(),
+    );
+    assert_eq!(compaction.total(), 5);
+
+    let mut results = vec![];
+    loop {
+        while let Some((path, _)) = compaction.start_next() {
+            assert!(path.starts_with(dir.path()));
+        }
+        assert!(compaction.in_flight().len() <= 2);
+
+        if compaction.in_flight().len() == 0 {
+            break;
+        }
+        results.extend(compaction.next_result(Duration::from_secs(10)));
+    }
+
+    assert_eq!(results.len(), 5);
+    let skipped = results
+        .iter()
+        .find(|result| result.full_path == incompressible)
+        .unwrap();
+    assert_eq!(skipped.outcome, Outcome::Incompressible);
+    assert_eq!(skipped.new_compression, None);
+    assert!(results
+        .iter()
+        .filter(|result| result.full_path != incompressible)
+        .all(|result| result.new_compression == Some(Compression::Lzx)));
+
+    let (folder, summary) = compaction.finish();
+    let groups = folder.summary();
+    assert_eq!(groups.compressed.count, 4);
+    assert_eq!(groups.skip_reasons[&SkipReason::Incompressible].count, 1);
+    assert_eq!((summary.files, summary.failed), (5, 0));
+    assert!(summary.new_physical_size < summary.old_physical_size);
+    assert!(summary.message(false).starts_with("Compacted"));
+}
@@ -0,0 +1,521 @@
+//! Comparing WOF's algorithms on a sample of a folder.
+//!
+//! A `Comparison` reads evenly spaced 32 KiB regions of a folder's
+//! compressible files and compresses each with every algorithm, timing how
+//! long each takes.  The sample is spread over every combination of top-level
+//! subfolder and extension in proportion to its size, with at least one region
+//! each, and the results extrapolated to whole files.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+use crate::background::{Background, ControlToken};
+use crate::codec::compress_chunk;
+use crate::compact::Compression;
+use crate::folder::{FileInfo, FolderInfo};
+use crate::platform::cluster_size;
+use crate::simulate::{extrapolate, DEFAULT_CLUSTER_SIZE};
+
+/// How much of a folder a `Comparison` reads, unless set with
+/// `with_sample_size`.
+pub const DEFAULT_SAMPLE_SIZE: u64 = 64 * 1024 * 1024;
+
+// The unit of sampling: one LZX chunk, or several XPRESS ones.
+const REGION_SIZE: u64 = 32768;
+
+// A faster algorithm is recommended over slower ones if it saves at least
+// this fraction of what they would.
+const GOOD_ENOUGH: f64 = 0.95;
+
+// Nothing is recommended unless it saves at least this fraction of the space.
+const WORTHWHILE: f64 = 0.01;
+
+/// How one algorithm would do on a group of files.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Projection {
+    pub compression: Compression,
+    /// The group's size on disk once compressed.
+    pub physical_size: u64,
+    /// Roughly how long compressing the group would take on one CPU, with
+    /// Compactor's own codecs standing in for Windows'.
+    pub cpu_secs: f64,
+}
+
+/// How each algorithm would do on a group of files.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupComparison {
+    /// The subfolder or extension, or empty for the whole folder.
+    pub name: String,
+    pub count: usize,
+    pub logical_size: u64,
+    pub physical_size: u64,
+    /// How much of the group was read and compressed.
+    pub sample_size: u64,
+    /// One per algorithm, in `Compression::ALL` order.
+    pub projections: Vec<Projection>,
+    /// The fastest algorithm saving nearly as much as the best, or `None` if
+    /// none would save a worthwhile amount.
+    pub recommended: Option<Compression>,
+}
+
+/// The results of a `Comparison`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FolderComparison {
+    pub total: GroupComparison,
+    /// By top-level subfolder, largest first.  Files directly in the folder
+    /// are grouped as ".".
+    pub folders: Vec<GroupComparison>,
+    /// By lower-case extension, largest first.  Files without one are grouped
+    /// as "".
+    pub extensions: Vec<GroupComparison>,
+}
+
+/// A `Background` job comparing every algorithm on the compressible files of
+/// a `FolderInfo`.
+///
+/// Status updates are the file being sampled and the fraction of the sample
+/// done.  If cancelled, there are no results.
+#[derive(Debug)]
+pub struct Comparison {
+    path: PathBuf,
+    files: Vec<FileInfo>,
+    sample_size: u64,
+}
+
+impl Comparison {
+    pub fn new(info: &FolderInfo) -> Self {
+        Self {
+            path: info.path.clone(),
+            files: info.compressible.files.iter().cloned().collect(),
+            sample_size: DEFAULT_SAMPLE_SIZE,
+        }
+    }
+
+    /// Set roughly how many bytes to sample.  If the files are no larger,
+    /// they're read in full.
+    pub fn with_sample_size(mut self, sample_size: u64) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+}
+
+// The results of compressing part of a group.
+#[derive(Debug, Default, Clone, Copy)]
+struct Sample {
+    size: u64,
+    // Per algorithm, in Compression::ALL order
+    stored: [u64; 4],
+    time: [Duration; 4],
+}
+
+impl Sample {
+    // Compress a region as each algorithm would, chunk by chunk.
+    fn add(&mut self, region: &[u8]) {
+        self.size += region.len() as u64;
+
+        for (i, &compression) in Compression::ALL.iter().enumerate() {
+            let start = Instant::now();
+            for chunk in region.chunks(compression.chunk_size()) {
+                let compressed = compress_chunk(compression, chunk).len();
+                self.stored[i] += compressed.min(chunk.len()) as u64;
+            }
+            self.time[i] += start.elapsed();
+        }
+    }
+}
+
+// Files sharing a top-level subfolder and extension, sampled together.
+#[derive(Debug, Default)]
+struct Cell {
+    files: Vec<usize>,
+    logical_size: u64,
+    // (index into files, offset) of each region to sample, in file order
+    regions: Vec<(usize, u64)>,
+    sample: Sample,
+}
+
+impl Cell {
+    // Spread `count` regions evenly over the cell's data, or take all of it.
+    fn plan(&mut self, files: &[FileInfo], count: Option<u64>) {
+        let count = match count {
+            Some(count) => count.max(1),
+            None => {
+                for &f in &self.files {
+                    let len = files[f].logical_size;
+                    self.regions
+                        .extend((0..len).step_by(REGION_SIZE as usize).map(|off| (f, off)));
+                }
+                return;
+            }
+        };
+
+        let mut files_iter = self.files.iter().copied();
+        let mut file = files_iter.next();
+        let mut start = 0;
+
+        for i in 0..count {
+            let pos = (u128::from(2 * i + 1) * u128::from(self.logical_size)
+                / u128::from(2 * count)) as u64;
+
+            while let Some(f) = file {
+                if pos < start + files[f].logical_size {
+                    let offset = (pos - start) / REGION_SIZE * REGION_SIZE;
+                    if self.regions.last() != Some(&(f, offset)) {
+                        self.regions.push((f, offset));
+                    }
+                    break;
+                }
+
+                start += files[f].logical_size;
+                file = files_iter.next();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    count: usize,
+    logical_size: u64,
+    physical_size: u64,
+    sample_size: u64,
+    projected: [u64; 4],
+    cpu_secs: [f64; 4],
+}
+
+impl Totals {
+    fn finish(self, name: String) -> GroupComparison {
+        let projections = Compression::ALL
+            .iter()
+            .enumerate()
+            .map(|(i, &compression)| Projection {
+                compression,
+                physical_size: self.projected[i],
+                cpu_secs: self.cpu_secs[i],
+            })
+            .collect::<Vec<_>>();
+
+        GroupComparison {
+            name,
+            count: self.count,
+            logical_size: self.logical_size,
+            physical_size: self.physical_size,
+            sample_size: self.sample_size,
+            recommended: recommend(self.physical_size, &projections),
+            projections,
+        }
+    }
+}
+
+// Pick the fastest algorithm that saves nearly as much as the best one.
+fn recommend(physical_size: u64, projections: &[Projection]) -> Option<Compression> {
+    let savings = |p: &Projection| physical_size.saturating_sub(p.physical_size);
+    let best = projections.iter().map(savings).max().unwrap_or(0);
+
+    if best == 0 || (best as f64) < physical_size as f64 * WORTHWHILE {
+        return None;
+    }
+
+    projections
+        .iter()
+        .find(|p| savings(p) as f64 >= best as f64 * GOOD_ENOUGH)
+        .map(|p| p.compression)
+}
+
+fn folder_name(path: &Path) -> String {
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => first.as_os_str().to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    }
+}
+
+fn extension_name(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn read_region(file: &mut File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut region = Vec::with_capacity(REGION_SIZE as usize);
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(REGION_SIZE).read_to_end(&mut region)?;
+    Ok(region)
+}
+
+fn sorted(groups: BTreeMap<String, Totals>) -> Vec<GroupComparison> {
+    let mut groups = groups
+        .into_iter()
+        .map(|(name, totals)| totals.finish(name))
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|g| Reverse(g.logical_size));
+    groups
+}
+
+impl Background for Comparison {
+    type Output = Option<FolderComparison>;
+    type Status = (PathBuf, f32);
+
+    fn run(self, control: &ControlToken<Self::Status>) -> Self::Output {
+        let Comparison {
+            path,
+            files,
+            sample_size,
+        } = self;
+        let cluster_size = cluster_size(&path).unwrap_or(DEFAULT_CLUSTER_SIZE);
+
+        let mut cells: BTreeMap<(String, String), Cell> = BTreeMap::new();
+        for (i, fi) in files.iter().enumerate() {
+            let cell = cells
+                .entry((folder_name(&fi.path), extension_name(&fi.path)))
+                .or_default();
+            cell.files.push(i);
+            cell.logical_size += fi.logical_size;
+        }
+
+        let total_size = files.iter().map(|fi| fi.logical_size).sum::<u64>();
+        let regions = (sample_size / REGION_SIZE).max(1);
+        for cell in cells.values_mut() {
+            let count = if total_size <= sample_size {
+                None
+            } else {
+                let share = u128::from(regions) * u128::from(cell.logical_size);
+                Some((share / u128::from(total_size)) as u64)
+            };
+            cell.plan(&files, count);
+        }
+
+        let planned = cells.values().map(|c| c.regions.len()).sum::<usize>();
+        let mut done = 0;
+        let mut last_status = Instant::now();
+
+        for cell in cells.values_mut() {
+            let mut open: Option<(usize, io::Result<File>)> = None;
+
+            for &(f, offset) in &cell.regions {
+                if control.is_cancelled_with_pause() {
+                    return None;
+                }
+
+                if last_status.elapsed() >= Duration::from_millis(50) {
+                    last_status = Instant::now();
+                    control.set_status((files[f].path.clone(), done as f32 / planned as f32));
+                }
+                done += 1;
+
+                if open.as_ref().map(|o| o.0) != Some(f) {
+                    open = Some((f, File::open(path.join(&files[f].path))));
+                }
+
+                // Unreadable files just go unsampled
+                if let Some((_, Ok(file))) = &mut open {
+                    if let Ok(region) = read_region(file, offset) {
+                        cell.sample.add(&region);
+                    }
+                }
+            }
+        }
+
+        let mut total = Totals::default();
+        let mut folders: BTreeMap<String, Totals> = BTreeMap::new();
+        let mut extensions: BTreeMap<String, Totals> = BTreeMap::new();
+
+        for ((folder, extension), cell) in cells {
+            let sample = cell.sample;
+
+            for group in [
+                &mut total,
+                folders.entry(folder).or_default(),
+                extensions.entry(extension).or_default(),
+            ] {
+                group.count += cell.files.len();
+                group.logical_size += cell.logical_size;
+                group.sample_size += sample.size;
+
+                for i in 0..Compression::ALL.len() {
+                    if sample.size > 0 {
+                        group.cpu_secs[i] += sample.time[i].as_secs_f64()
+                            * cell.logical_size as f64
+                            / sample.size as f64;
+                    }
+                }
+
+                for &f in &cell.files {
+                    let fi = &files[f];
+                    group.physical_size += fi.physical_size;
+
+                    for (i, &compression) in Compression::ALL.iter().enumerate() {
+                        group.projected[i] += extrapolate(
+                            fi.logical_size,
+                            sample.size,
+                            sample.stored[i],
+                            compression,
+                            cluster_size,
+                        )
+                        .min(fi.physical_size);
+                    }
+                }
+            }
+        }
+
+        Some(FolderComparison {
+            total: total.finish(String::new()),
+            folders: sorted(folders),
+            extensions: sorted(extensions),
+        })
+    }
+}
+
+#[test]
+fn it_recommends_algorithms() {
+    let projections = |sizes: [u64; 4]| {
+        Compression::ALL
+            .iter()
+            .zip(sizes.iter())
+            .map(|(&compression, &physical_size)| Projection {
+                compression,
+                physical_size,
+                cpu_secs: 0.0,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        recommend(1000, &projections([600, 500, 480, 300])),
+        Some(Compression::Lzx)
+    );
+    assert_eq!(
+        recommend(1000, &projections([600, 500, 490, 480])),
+        Some(Compression::Xpress8k)
+    );
+    assert_eq!(
+        recommend(1000, &projections([500, 500, 490, 480])),
+        Some(Compression::Xpress4k)
+    );
+    assert_eq!(recommend(1000, &projections([1000; 4])), None);
+    assert_eq!(recommend(1000, &projections([1000, 999, 995, 991])), None);
+    assert_eq!(recommend(0, &[]), None);
+}
+
+#[test]
+fn it_plans_samples() {
+    let files = [4096 * 10, 100, 32768 * 3 + 1]
+        .iter()
+        .map(|&len| FileInfo {
+            path: PathBuf::from(len.to_string()),
+            logical_size: len,
+            physical_size: len,
+            skip_reason: None,
+            projected_size: None,
+            compression: None,
+        })
+        .collect::<Vec<_>>();
+    let cell = |count| {
+        let mut cell = Cell {
+            files: vec![0, 1, 2],
+            logical_size: files.iter().map(|fi| fi.logical_size).sum(),
+            ..Cell::default()
+        };
+        cell.plan(&files, count);
+        cell.regions
+    };
+
+    assert_eq!(
+        cell(None),
+        vec![
+            (0, 0),
+            (0, 32768),
+            (1, 0),
+            (2, 0),
+            (2, 32768),
+            (2, 65536),
+            (2, 98304)
+        ]
+    );
+    assert_eq!(cell(Some(0)), vec![(2, 0)]);
+    assert_eq!(cell(Some(2)), vec![(0, 32768), (2, 32768)]);
+    // Too sparse to land in the small file
+    assert_eq!(
+        cell(Some(100)),
+        vec![(0, 0), (0, 32768), (2, 0), (2, 32768), (2, 65536)]
+    );
+}
+
+#[test]
+fn it_compares_algorithms() {
+    use std::fs;
+
+    use crate::background::BackgroundHandle;
+    use crate::codec::noise;
+    use crate::folder::FolderScan;
+    use crate::mock::MockBackend;
+    use crate::simulate::simulate;
+    use globset::GlobSet;
+    use std::sync::Arc;
+
+    let dir = tempdir::TempDir::new("compactor-compare").unwrap();
+    let text = b"It was the best of times, it was the worst of times. ".repeat(2000);
+    for sub in &["game", "other"] {
+        fs::create_dir(dir.path().join(sub)).unwrap();
+    }
+    fs::write(dir.path().join("game/story.txt"), &text).unwrap();
+    fs::write(dir.path().join("game/Assets.BIN"), noise(200_000, 1)).unwrap();
+    fs::write(dir.path().join("other/more.txt"), &text[..50_000]).unwrap();
+    fs::write(dir.path().join("readme"), &text[..20_000]).unwrap();
+
+    let scanner = FolderScan::new(
+        dir.path(),
+        GlobSet::empty(),
+        Arc::new(MockBackend::default()),
+    );
+    let info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+    assert_eq!(info.compressible.files.len(), 4);
+
+    // Everything fits in the sample, so the projections are as good as simulate's
+    let compare = BackgroundHandle::spawn(Comparison::new(&info));
+    let result = compare.wait().expect("comparison");
+
+    let total = &result.total;
+    assert_eq!(total.count, 4);
+    assert_eq!(total.sample_size, text.len() as u64 + 270_000);
+    assert_eq!(total.projections.len(), 4);
+
+    let sim = simulate(&text[..], Compression::Lzx, DEFAULT_CLUSTER_SIZE).unwrap();
+    let bin = &result.extensions[0];
+    assert_eq!(bin.name, "bin");
+    let txt = &result.extensions[1];
+    assert_eq!((txt.name.as_str(), txt.count), ("txt", 2));
+    let game = &result.folders[0];
+    assert_eq!((game.name.as_str(), game.count), ("game", 2));
+    assert_eq!(result.folders[2].name, ".");
+    assert_eq!(result.extensions[2].name, "");
+
+    assert!(bin
+        .projections
+        .iter()
+        .all(|p| p.physical_size == bin.physical_size));
+    assert_eq!(bin.recommended, None);
+
+    assert!(txt.projections[3].physical_size < txt.projections[0].physical_size);
+    assert!(txt.recommended.is_some());
+    // Logical sizes may be rounded up to what's allocated here
+    let story = game.projections[3].physical_size - bin.physical_size;
+    assert!(story >= sim.physical_size);
+    assert!(story <= sim.physical_size + DEFAULT_CLUSTER_SIZE);
+    assert!(total.projections.iter().all(|p| p.cpu_secs > 0.0));
+
+    // A small sample still covers every group
+    let compare = Comparison::new(&info).with_sample_size(65536);
+    let sampled = BackgroundHandle::spawn(compare).wait().expect("comparison");
+    assert!(sampled.total.sample_size < total.sample_size);
+    assert!(sampled.folders.iter().all(|g| g.sample_size > 0));
+    assert!(sampled.extensions.iter().all(|g| g.sample_size > 0));
+    assert_eq!(sampled.extensions[0].recommended, None);
+    assert!(sampled.extensions[1].recommended.is_some());
+}
@@ -0,0 +1,212 @@
+//! Compressing and decompressing files in the background.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crossbeam_channel::{Receiver, Sender};
+use filetime::FileTime;
+use fs2::FileExt;
+
+use crate::background::Background;
+use crate::background::BackgroundHandle;
+use crate::background::ControlToken;
+use crate::compact::{Compression, CompressionBackend};
+use crate::platform::{self, FILE_ATTRIBUTE_COMPRESSED};
+use crate::simulate::estimate;
+use crate::wof::Provider;
+
+/// A `Background` job that compresses each file it receives.
+///
+/// Files are read from `files_in` and each result sent to `files_out` as a
+/// `Compacted`, or an error.  With a `compression` of `None` files are
+/// decompressed instead.
+///
+/// Compression is skipped for files that look incompressible with the chosen
+/// algorithm, judging by a sample run through Compactor's own implementation
+/// of it, and files are locked while being worked on.  Files using legacy
+/// NTFS compression have it removed first, and files that are already
+/// compressed are switched to the new algorithm, but only if they look
+/// compressible.  WIM-backed files are refused with an error either way, as
+/// they belong to CompactOS or WIMBoot.
+///
+/// Several can share the same channels to work in parallel - see
+/// `spawn_compactors`.
+#[derive(Debug)]
+pub struct BackgroundCompactor {
+    backend: Arc<dyn CompressionBackend>,
+    compression: Option<Compression>,
+    files_in: Receiver<(PathBuf, u64)>,
+    files_out: Sender<(PathBuf, io::Result<Compacted>)>,
+}
+
+/// What a `BackgroundCompactor` did with a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compacted {
+    /// Compressed, recompressed or decompressed as asked.
+    Done,
+    /// Left alone as compression wasn't worthwhile, with `ratio` the estimated
+    /// compressed size as a fraction of the original.
+    Incompressible { ratio: f32 },
+}
+
+impl BackgroundCompactor {
+    pub fn new(
+        backend: Arc<dyn CompressionBackend>,
+        compression: Option<Compression>,
+        files_in: Receiver<(PathBuf, u64)>,
+        files_out: Sender<(PathBuf, io::Result<Compacted>)>,
+    ) -> Self {
+        Self {
+            backend,
+            compression,
+            files_in,
+            files_out,
+        }
+    }
+}
+
+/// Spawn `workers` `BackgroundCompactor`s sharing the same channels.
+///
+/// Results arrive in whatever order files finish.
+pub fn spawn_compactors(
+    workers: usize,
+    backend: Arc<dyn CompressionBackend>,
+    compression: Option<Compression>,
+    files_in: Receiver<(PathBuf, u64)>,
+    files_out: Sender<(PathBuf, io::Result<Compacted>)>,
+) -> Vec<BackgroundHandle<(), ()>> {
+    (0..workers.max(1))
+        .map(|_| {
+            BackgroundHandle::spawn(BackgroundCompactor::new(
+                backend.clone(),
+                compression,
+                files_in.clone(),
+                files_out.clone(),
+            ))
+        })
+        .collect()
+}
+
+/// A reasonable number of workers for compressing files under `path`.
+///
+/// Drives that are slow to seek get one, so files are read sequentially.
+/// Anything else gets one per CPU.
+pub fn default_workers(path: &Path) -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+    match platform::incurs_seek_penalty(path) {
+        Ok(true) => 1,
+        _ => cpus,
+    }
+}
+
+fn handle_file(
+    backend: &dyn CompressionBackend,
+    file: &Path,
+    compression: Option<Compression>,
+) -> io::Result<Compacted> {
+    let current = match backend.detect_provider(file) {
+        Ok(Some(Provider::Wim { .. })) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is backed by a WIM image",
+            ));
+        }
+        Ok(Some(Provider::File(current))) => Some(current),
+        _ => None,
+    };
+
+    let meta = std::fs::metadata(file)?;
+    let handle = platform::open_for_compression(file)?;
+
+    handle.try_lock_exclusive()?;
+
+    let ret = match compression {
+        Some(compression) => match estimate(&handle, meta.len(), compression) {
+            Ok(ratio) if ratio < 0.95 => {
+                let compressed = if current.is_some() {
+                    backend.recompress(file, &handle, compression)
+                } else {
+                    // WOF won't take files with legacy NTFS compression
+                    let legacy = backend.file_attributes(file, &meta) & FILE_ATTRIBUTE_COMPRESSED;
+                    let cleared = match legacy {
+                        0 => Ok(()),
+                        _ => backend.clear_ntfs_compression(file, &handle),
+                    };
+                    cleared.and_then(|_| backend.compress(file, &handle, compression))
+                };
+
+                compressed.map(|compressed| {
+                    if compressed {
+                        Compacted::Done
+                    } else {
+                        Compacted::Incompressible {
+                            ratio: ratio as f32,
+                        }
+                    }
+                })
+            }
+            Ok(ratio) => Ok(Compacted::Incompressible {
+                ratio: ratio as f32,
+            }),
+            Err(e) => Err(e),
+        },
+        None => backend.uncompress(file, &handle).map(|_| Compacted::Done),
+    };
+
+    let _ = filetime::set_file_handle_times(
+        &handle,
+        Some(FileTime::from_last_access_time(&meta)),
+        Some(FileTime::from_last_modification_time(&meta)),
+    );
+
+    FileExt::unlock(&handle)?;
+
+    ret
+}
+
+impl Background for BackgroundCompactor {
+    type Output = ();
+    type Status = ();
+
+    fn run(self, control: &ControlToken<Self::Status>) -> Self::Output {
+        for file in &self.files_in {
+            if control.is_cancelled_with_pause() {
+                break;
+            }
+
+            let file = file.0;
+            let ret = handle_file(&*self.backend, &file, self.compression);
+            if self.files_out.send((file, ret)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn it_retries_with_stronger_algorithms() {
+    use crate::codec::noise;
+    use crate::mock::MockBackend;
+    use tempdir::TempDir;
+
+    // Repeats only LZX's chunks are large enough to see
+    let dir = TempDir::new("compactor-test").unwrap();
+    let file = dir.path().join("repeats.bin");
+    std::fs::write(&file, noise(8192, 1).repeat(64)).unwrap();
+    let backend = MockBackend::default();
+
+    match handle_file(&backend, &file, Some(Compression::Xpress4k)).unwrap() {
+        Compacted::Incompressible { ratio } => assert!(ratio >= 0.95, "{}", ratio),
+        Compacted::Done => panic!("compressed with XPRESS 4K"),
+    }
+    assert_eq!(backend.get(&file), None);
+
+    assert_eq!(
+        handle_file(&backend, &file, Some(Compression::Lzx)).unwrap(),
+        Compacted::Done
+    );
+    assert_eq!(backend.get(&file).unwrap().compression, Compression::Lzx);
+}
@@ -1,22 +1,37 @@
+//! User settings, and their persistence.
+
 use std::io;
 use std::path::{Path, PathBuf};
 
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use serde_derive::{Deserialize, Serialize};
 
 use crate::compact::Compression;
 
+/// A `Config` with an optional file backing it.
 #[derive(Debug, Default)]
 pub struct ConfigFile {
     backing: Option<PathBuf>,
     config: Config,
 }
 
+/// User settings.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// Display sizes in decimal (MB) rather than binary (MiB) units.
     pub decimal: bool,
+    /// The algorithm to compress with.
     pub compression: Compression,
+    /// Globs for paths which should be skipped.
     pub excludes: Vec<String>,
+    /// How many files to compress at once, or `None` to pick based on the
+    /// drive.
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Read compressible files during analysis to project how much space
+    /// compressing them would save.
+    #[serde(default)]
+    pub project_savings: bool,
 }
 
 impl Default for Config {
@@ -75,11 +90,14 @@ impl Default for Config {
             .into_iter()
             .map(String::from)
             .collect(),
+            workers: None,
+            project_savings: false,
         }
     }
 }
 
 impl ConfigFile {
+    /// Load from `path`, falling back to defaults if it can't be read.
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
             backing: Some(path.as_ref().to_owned()),
@@ -92,6 +110,7 @@ impl ConfigFile {
         }
     }
 
+    /// Write the current config to the backing file, if there is one.
     pub fn save(&self) -> io::Result<()> {
         match &self.backing {
             Some(path) => {
@@ -116,15 +135,34 @@ impl ConfigFile {
 }
 
 impl Config {
+    /// Compile `excludes` for matching.
     pub fn globset(&self) -> Result<GlobSet, String> {
         let mut globs = GlobSetBuilder::new();
         for glob in &self.excludes {
-            globs.add(Glob::new(glob).map_err(|e| e.to_string())?);
+            // Excludes are written as Windows paths, so backslashes aren't
+            // escapes.  They only match backslashes, not `/` elsewhere.
+            let glob = GlobBuilder::new(glob)
+                .backslash_escape(false)
+                .build()
+                .map_err(|e| e.to_string())?;
+            globs.add(glob);
         }
         globs.build().map_err(|e| e.to_string())
     }
 }
 
+#[test]
+fn test_config_upgrade() {
+    let c: Config =
+        serde_json::from_str(r#"{"decimal":true,"compression":"Lzx","excludes":["*.foo"]}"#)
+            .unwrap();
+
+    assert!(c.decimal);
+    assert_eq!(c.compression, Compression::Lzx);
+    assert_eq!(c.workers, None);
+    assert!(!c.project_savings);
+}
+
 #[test]
 fn test_config() {
     let s = Config::default();
@@ -135,4 +173,12 @@ fn test_config() {
     assert!(gs.is_match("C:\\foo\\bar\\hmm.rar"));
     assert!(gs.is_match("C:\\Windows\\System32\\floop\\bla.txt"));
     assert!(gs.is_match("C:\\x.lz4"));
+
+    let c = Config {
+        excludes: vec![r"*\logs\*".to_string()],
+        ..Config::default()
+    };
+    let gs = c.globset().unwrap();
+    assert!(gs.is_match(r"game\logs\a.txt"));
+    assert!(!gs.is_match("game/logs/a.txt"));
 }
@@ -0,0 +1,189 @@
+//! Structured progress and results, for consumption by other programs.
+//!
+//! Events serialize to JSON objects tagged with a `type` field, and
+//! `NdjsonWriter` writes them one per line.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde_derive::Serialize;
+
+use crate::compact::Compression;
+use crate::compare::FolderComparison;
+use crate::folder::{FolderSummary, ScanOperation};
+
+/// What's being done to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Compress,
+    Decompress,
+    /// Replace legacy NTFS compression with WOF compression.
+    Convert,
+    /// Switch compressed files to a different algorithm.
+    Recompress,
+}
+
+/// How an operation on a file turned out.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Compressed,
+    Incompressible,
+    Decompressed,
+    Failed,
+}
+
+/// A serializable `io::Error`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorInfo {
+    /// The `io::ErrorKind`, like `"PermissionDenied"`.
+    pub kind: String,
+    /// The raw OS error code, if there is one.
+    pub code: Option<i32>,
+    pub message: String,
+}
+
+impl From<&io::Error> for ErrorInfo {
+    fn from(e: &io::Error) -> Self {
+        Self {
+            kind: format!("{:?}", e.kind()),
+            code: e.raw_os_error(),
+            message: e.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A scan of `path` has started.
+    ScanStarted { path: PathBuf },
+    /// A scan is in progress, and has reached `current`.
+    ScanProgress {
+        path: PathBuf,
+        current: PathBuf,
+        summary: FolderSummary,
+    },
+    /// A scan couldn't read `path`, so its results are incomplete.
+    ScanError {
+        path: PathBuf,
+        operation: ScanOperation,
+        error: ErrorInfo,
+    },
+    /// A scan has finished, or was interrupted if not `complete`.
+    ScanFinished {
+        path: PathBuf,
+        complete: bool,
+        summary: FolderSummary,
+    },
+    /// Work on a file has started.
+    FileStarted {
+        path: PathBuf,
+        operation: Operation,
+        compression: Option<Compression>,
+        logical_size: u64,
+        physical_size: u64,
+    },
+    /// Work on a file has finished.
+    FileResult {
+        path: PathBuf,
+        operation: Operation,
+        compression: Option<Compression>,
+        outcome: Outcome,
+        logical_size: u64,
+        old_physical_size: u64,
+        new_physical_size: u64,
+        error: Option<ErrorInfo>,
+    },
+    /// The current compression state of a file.
+    FileState {
+        path: PathBuf,
+        compression: Option<Compression>,
+        logical_size: u64,
+        physical_size: u64,
+        error: Option<ErrorInfo>,
+    },
+    /// How each algorithm would do on `path`, from a sample of it.
+    Comparison {
+        path: PathBuf,
+        comparison: FolderComparison,
+    },
+    /// Files under `path` recorded as incompressible have been forgotten.
+    Forgotten { path: PathBuf, files: usize },
+    /// A path couldn't be processed at all.
+    Error { path: PathBuf, error: ErrorInfo },
+    /// All done with `path`.
+    Summary {
+        path: PathBuf,
+        operation: Option<Operation>,
+        compression: Option<Compression>,
+        files: usize,
+        failed: usize,
+        old_physical_size: u64,
+        new_physical_size: u64,
+        elapsed_secs: f64,
+        interrupted: bool,
+        summary: FolderSummary,
+    },
+}
+
+/// Writes `Event`s as newline-delimited JSON.
+#[derive(Debug)]
+pub struct NdjsonWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Write an event, flushing so readers see it immediately.
+    pub fn emit(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[test]
+fn events_are_ndjson() {
+    let mut writer = NdjsonWriter::new(vec![]);
+
+    writer
+        .emit(&Event::ScanStarted {
+            path: PathBuf::from("foo"),
+        })
+        .unwrap();
+    writer
+        .emit(&Event::FileResult {
+            path: PathBuf::from("foo/bar"),
+            operation: Operation::Compress,
+            compression: Some(Compression::Lzx),
+            outcome: Outcome::Failed,
+            logical_size: 8192,
+            old_physical_size: 8192,
+            new_physical_size: 8192,
+            error: Some(ErrorInfo::from(&io::Error::from(
+                io::ErrorKind::PermissionDenied,
+            ))),
+        })
+        .unwrap();
+
+    let out = String::from_utf8(writer.into_inner()).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], r#"{"type":"scan_started","path":"foo"}"#);
+
+    let result: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(result["type"], "file_result");
+    assert_eq!(result["operation"], "compress");
+    assert_eq!(result["compression"], "Lzx");
+    assert_eq!(result["outcome"], "failed");
+    assert_eq!(result["error"]["kind"], "PermissionDenied");
+}
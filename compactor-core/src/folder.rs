@@ -0,0 +1,1209 @@
+//! Folder analysis.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use globset::GlobSet;
+use hashfilter::{Fingerprint, HashFilter};
+use serde_derive::Serialize;
+
+use crate::background::{Background, ControlToken};
+use crate::compact::{Compression, CompressionBackend};
+use crate::event::ErrorInfo;
+use crate::persistence::{pathdb, record_path, resolve_folder, skips_incompressible};
+use crate::platform::{
+    cluster_size, FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SPARSE_FILE,
+    FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_TEMPORARY,
+};
+use crate::simulate::{simulate, DEFAULT_CLUSTER_SIZE};
+use crate::wof::Provider;
+
+/// A file, with sizes before and after any compression.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    /// Relative to the containing `FolderInfo`'s path.
+    pub path: PathBuf,
+    pub logical_size: u64,
+    pub physical_size: u64,
+    /// Why the file is in the skipped group, if it is.
+    pub skip_reason: Option<SkipReason>,
+    /// The physical size once compressed, if the scan projected it.
+    pub projected_size: Option<u64>,
+    /// The algorithm a compressed file uses, if known.
+    pub compression: Option<Compression>,
+}
+
+/// A collection of files, with running size totals.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct GroupInfo {
+    pub files: VecDeque<FileInfo>,
+    pub logical_size: u64,
+    pub physical_size: u64,
+    /// Projected physical sizes, or current ones for files without.
+    pub projected_size: u64,
+}
+
+/// The results of a `FolderScan`, with files grouped by `FileKind`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FolderInfo {
+    pub path: PathBuf,
+    pub logical_size: u64,
+    pub physical_size: u64,
+    pub compressible: GroupInfo,
+    pub compressed: GroupInfo,
+    pub skipped: GroupInfo,
+    pub wim: GroupInfo,
+    pub sparse: GroupInfo,
+    pub ntfs_compressed: GroupInfo,
+    /// Totals for the skipped group, broken down by reason.
+    pub skip_reasons: BTreeMap<SkipReason, GroupSummary>,
+    /// Totals for compressed files whose algorithm is known, by algorithm.
+    pub algorithms: BTreeMap<Compression, GroupSummary>,
+    /// Paths that couldn't be read, in path order.  If there are any, the
+    /// totals are incomplete.
+    pub errors: Vec<ScanError>,
+    /// The algorithm compressible files' sizes were projected for, if any.
+    pub projection: Option<Compression>,
+}
+
+/// The totals from a `FolderInfo`, without the file lists.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct FolderSummary {
+    pub logical_size: u64,
+    pub physical_size: u64,
+    pub compressible: GroupSummary,
+    pub compressed: GroupSummary,
+    pub skipped: GroupSummary,
+    pub wim: GroupSummary,
+    pub sparse: GroupSummary,
+    pub ntfs_compressed: GroupSummary,
+    /// Totals for the skipped group, broken down by reason.
+    pub skip_reasons: BTreeMap<SkipReason, GroupSummary>,
+    /// Totals for compressed files whose algorithm is known, by algorithm.
+    pub algorithms: BTreeMap<Compression, GroupSummary>,
+    /// The number of paths that couldn't be read.
+    pub errors: usize,
+    /// The algorithm compressible files' sizes were projected for, if any.
+    pub projection: Option<Compression>,
+}
+
+/// The totals from a `GroupInfo`.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct GroupSummary {
+    pub count: usize,
+    pub logical_size: u64,
+    pub physical_size: u64,
+    /// Projected physical sizes, or current ones for files without.
+    pub projected_size: u64,
+}
+
+/// Why a file was put in the skipped group.
+///
+/// Where more than one applies, a scan picks the first in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// 4 KiB or smaller, so there's nothing to gain.
+    Small,
+    ReadOnly,
+    System,
+    Temporary,
+    /// Found not to compress well in an earlier run.
+    Incompressible,
+    /// Matched an exclude glob.
+    Excluded,
+    /// Compression or decompression failed.
+    Failed,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SkipReason::Small => write!(f, "Small"),
+            SkipReason::ReadOnly => write!(f, "Read-only"),
+            SkipReason::System => write!(f, "System"),
+            SkipReason::Temporary => write!(f, "Temporary"),
+            SkipReason::Incompressible => write!(f, "Incompressible"),
+            SkipReason::Excluded => write!(f, "Excluded"),
+            SkipReason::Failed => write!(f, "Failed"),
+        }
+    }
+}
+
+/// What a `FolderScan` was trying to do when it hit an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanOperation {
+    /// Listing a directory.
+    ReadDir,
+    /// Reading a file or directory's type and size.
+    Metadata,
+    /// Asking the `CompressionBackend` for a file's size on disk.
+    SizeOnDisk,
+    /// Asking the `CompressionBackend` what's backing a compressed file.
+    DetectProvider,
+    /// Reading a file's record from a disk image's MFT.
+    ReadRecord,
+    /// Decompressing a file from a disk image to check it.
+    Verify,
+}
+
+/// A path a `FolderScan` couldn't read.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanError {
+    /// Relative to the containing `FolderInfo`'s path.
+    pub path: PathBuf,
+    pub operation: ScanOperation,
+    pub error: ErrorInfo,
+}
+
+/// Which group a file belongs in.
+#[derive(Debug, Clone, Copy)]
+pub enum FileKind {
+    /// Already compressed.
+    Compressed,
+    /// A candidate for compression.
+    Compressible,
+    /// Excluded, too small, previously found incompressible, or otherwise not
+    /// worth touching.
+    Skipped,
+    /// Backed by a WIM image by CompactOS or WIMBoot, so never to be
+    /// decompressed or recompressed.
+    Wim,
+    /// Sparse, so smaller on disk without being compressed.
+    Sparse,
+    /// Using legacy NTFS (LZNT1) compression, and only a candidate for
+    /// converting to WOF.
+    NtfsCompressed,
+}
+
+impl FolderInfo {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            logical_size: 0,
+            physical_size: 0,
+            compressible: GroupInfo::default(),
+            compressed: GroupInfo::default(),
+            skipped: GroupInfo::default(),
+            wim: GroupInfo::default(),
+            sparse: GroupInfo::default(),
+            ntfs_compressed: GroupInfo::default(),
+            skip_reasons: BTreeMap::new(),
+            algorithms: BTreeMap::new(),
+            errors: vec![],
+            projection: None,
+        }
+    }
+
+    pub fn summary(&self) -> FolderSummary {
+        FolderSummary {
+            logical_size: self.logical_size,
+            physical_size: self.physical_size,
+            compressible: self.compressible.summary(),
+            compressed: self.compressed.summary(),
+            skipped: self.skipped.summary(),
+            wim: self.wim.summary(),
+            sparse: self.sparse.summary(),
+            ntfs_compressed: self.ntfs_compressed.summary(),
+            skip_reasons: self.skip_reasons.clone(),
+            algorithms: self.algorithms.clone(),
+            errors: self.errors.len(),
+            projection: self.projection,
+        }
+    }
+
+    pub fn len(&mut self, kind: FileKind) -> usize {
+        match kind {
+            FileKind::Compressible => self.compressible.files.len(),
+            FileKind::Compressed => self.compressed.files.len(),
+            FileKind::Skipped => self.skipped.files.len(),
+            FileKind::Wim => self.wim.files.len(),
+            FileKind::Sparse => self.sparse.files.len(),
+            FileKind::NtfsCompressed => self.ntfs_compressed.files.len(),
+        }
+    }
+
+    /// Every file, in each group.
+    pub fn files(&self) -> impl Iterator<Item = &FileInfo> {
+        self.compressible
+            .files
+            .iter()
+            .chain(&self.compressed.files)
+            .chain(&self.skipped.files)
+            .chain(&self.wim.files)
+            .chain(&self.sparse.files)
+            .chain(&self.ntfs_compressed.files)
+    }
+
+    /// Remove the next file from a group.
+    pub fn pop(&mut self, kind: FileKind) -> Option<FileInfo> {
+        let ret = match kind {
+            FileKind::Compressible => self.compressible.pop(),
+            FileKind::Compressed => self.compressed.pop(),
+            FileKind::Skipped => self.skipped.pop(),
+            FileKind::Wim => self.wim.pop(),
+            FileKind::Sparse => self.sparse.pop(),
+            FileKind::NtfsCompressed => self.ntfs_compressed.pop(),
+        };
+
+        if let Some(fi) = ret {
+            self.logical_size -= fi.logical_size;
+            self.physical_size -= fi.physical_size;
+
+            if let Some(reason) = fi.skip_reason {
+                let total = self.skip_reasons.get_mut(&reason).expect("skip reason");
+                total.count -= 1;
+                total.logical_size -= fi.logical_size;
+                total.physical_size -= fi.physical_size;
+                total.projected_size -= fi.projected_or_physical_size();
+
+                if total.count == 0 {
+                    self.skip_reasons.remove(&reason);
+                }
+            }
+
+            if let (FileKind::Compressed, Some(compression)) = (kind, fi.compression) {
+                let total = self.algorithms.get_mut(&compression).expect("algorithm");
+                total.count -= 1;
+                total.logical_size -= fi.logical_size;
+                total.physical_size -= fi.physical_size;
+                total.projected_size -= fi.projected_or_physical_size();
+
+                if total.count == 0 {
+                    self.algorithms.remove(&compression);
+                }
+            }
+
+            Some(fi)
+        } else {
+            None
+        }
+    }
+
+    /// Add a file to a group.
+    ///
+    /// Files added to the skipped group should have a `skip_reason`, which is
+    /// cleared for other groups.  Likewise `compression` is only kept for the
+    /// compressed group.
+    pub fn push(&mut self, kind: FileKind, mut fi: FileInfo) {
+        self.logical_size += fi.logical_size;
+        self.physical_size += fi.physical_size;
+
+        match (kind, fi.skip_reason) {
+            (FileKind::Skipped, Some(reason)) => {
+                let total = self.skip_reasons.entry(reason).or_default();
+                total.count += 1;
+                total.logical_size += fi.logical_size;
+                total.physical_size += fi.physical_size;
+                total.projected_size += fi.projected_or_physical_size();
+            }
+            (FileKind::Skipped, None) => (),
+            _ => fi.skip_reason = None,
+        }
+
+        match (kind, fi.compression) {
+            (FileKind::Compressed, Some(compression)) => {
+                let total = self.algorithms.entry(compression).or_default();
+                total.count += 1;
+                total.logical_size += fi.logical_size;
+                total.physical_size += fi.physical_size;
+                total.projected_size += fi.projected_or_physical_size();
+            }
+            (FileKind::Compressed, None) => (),
+            _ => fi.compression = None,
+        }
+
+        match kind {
+            FileKind::Compressible => self.compressible.push(fi),
+            FileKind::Compressed => self.compressed.push(fi),
+            FileKind::Skipped => self.skipped.push(fi),
+            FileKind::Wim => self.wim.push(fi),
+            FileKind::Sparse => self.sparse.push(fi),
+            FileKind::NtfsCompressed => self.ntfs_compressed.push(fi),
+        };
+    }
+
+    // Put each group in path order, as a sequential sorted walk would.
+    pub(crate) fn sort(&mut self) {
+        for group in [
+            &mut self.compressible,
+            &mut self.compressed,
+            &mut self.skipped,
+            &mut self.wim,
+            &mut self.sparse,
+            &mut self.ntfs_compressed,
+        ] {
+            group
+                .files
+                .make_contiguous()
+                .sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        self.errors.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+}
+
+impl FolderSummary {
+    /// The number of files in every group.
+    pub fn file_count(&self) -> usize {
+        self.compressible.count
+            + self.compressed.count
+            + self.skipped.count
+            + self.wim.count
+            + self.sparse.count
+            + self.ntfs_compressed.count
+    }
+}
+
+impl FileInfo {
+    fn projected_or_physical_size(&self) -> u64 {
+        self.projected_size.unwrap_or(self.physical_size)
+    }
+}
+
+impl GroupInfo {
+    pub fn summary(&self) -> GroupSummary {
+        GroupSummary {
+            count: self.files.len(),
+            logical_size: self.logical_size,
+            physical_size: self.physical_size,
+            projected_size: self.projected_size,
+        }
+    }
+
+    fn pop(&mut self) -> Option<FileInfo> {
+        let ret = self.files.pop_front();
+
+        if let Some(fi) = ret {
+            self.logical_size -= fi.logical_size;
+            self.physical_size -= fi.physical_size;
+            self.projected_size -= fi.projected_or_physical_size();
+
+            Some(fi)
+        } else {
+            None
+        }
+    }
+
+    fn push(&mut self, fi: FileInfo) {
+        self.logical_size += fi.logical_size;
+        self.physical_size += fi.physical_size;
+        self.projected_size += fi.projected_or_physical_size();
+        self.files.push_back(fi);
+    }
+}
+
+/// A `Background` job that walks a folder to build a `FolderInfo`.
+///
+/// Directories are listed in parallel, by one thread per CPU unless set with
+/// `with_threads`, but files in each group always end up in path order.
+///
+/// With `with_projection`, compressible files are also read to `simulate`
+/// their size once compressed, which is much slower.
+///
+/// Files found incompressible before are skipped, unless `with_compression`
+/// names a stronger algorithm than the one that failed.
+///
+/// Status updates are the path currently being scanned and a running summary.
+/// If cancelled, the partial results are returned as an `Err`.
+#[derive(Debug)]
+pub struct FolderScan {
+    path: PathBuf,
+    excludes: GlobSet,
+    backend: Arc<dyn CompressionBackend>,
+    threads: usize,
+    projection: Option<Compression>,
+    compression: Option<Compression>,
+}
+
+impl FolderScan {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        excludes: GlobSet,
+        backend: Arc<dyn CompressionBackend>,
+    ) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            excludes,
+            backend,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            projection: None,
+            compression: None,
+        }
+    }
+
+    /// Set the number of threads listing directories.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Project the size of compressible files once compressed with
+    /// `compression`.
+    pub fn with_projection(mut self, compression: Compression) -> Self {
+        self.projection = Some(compression);
+        self
+    }
+
+    /// Decide which incompressible files to skip for compressing with
+    /// `compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+}
+
+// The shared state of a parallel walk.
+struct Walker<'a> {
+    root: &'a Path,
+    // The root as the incompressible-files database knows it
+    resolved_root: &'a Path,
+    excludes: &'a GlobSet,
+    backend: &'a dyn CompressionBackend,
+    incompressible: &'a HashFilter,
+    // The algorithm and cluster size to project compressed sizes with
+    projection: Option<(Compression, u64)>,
+    // The algorithm files are to be compressed with, if known
+    compression: Option<Compression>,
+    // Directories queued or being listed; the walk is done when this hits 0
+    pending: AtomicUsize,
+    stop: AtomicBool,
+}
+
+type Found = Result<(FileKind, FileInfo), ScanError>;
+
+impl Walker<'_> {
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(self.root).unwrap_or(path).to_path_buf()
+    }
+
+    fn error(&self, path: &Path, operation: ScanOperation, e: &io::Error) -> ScanError {
+        ScanError {
+            path: self.relative(path),
+            operation,
+            error: ErrorInfo::from(e),
+        }
+    }
+
+    // Work out which group a file belongs in.
+    fn file(&self, path: &Path, metadata: &Metadata) -> Found {
+        // Ask the backend for the physical size - GetCompressedFileSizeW() for
+        // WOF.
+        let physical = self
+            .backend
+            .size_on_disk(path)
+            .map_err(|e| self.error(path, ScanOperation::SizeOnDisk, &e))?;
+
+        let mut fi = FileInfo {
+            path: self.relative(path),
+            logical_size: metadata.len().max(physical),
+            physical_size: physical,
+            skip_reason: None,
+            projected_size: None,
+            compression: None,
+        };
+
+        let attributes = self.backend.file_attributes(path, metadata);
+
+        // Only WOF's file provider is ours to decompress, but CompactOS and
+        // WIMBoot files, sparse files and NTFS compression all look smaller too
+        if fi.physical_size < fi.logical_size {
+            let provider = self
+                .backend
+                .detect_provider(path)
+                .map_err(|e| self.error(path, ScanOperation::DetectProvider, &e))?;
+
+            match provider {
+                Some(Provider::Wim { .. }) => return Ok((FileKind::Wim, fi)),
+                Some(Provider::File(compression)) => {
+                    fi.compression = Some(compression);
+                    return Ok((FileKind::Compressed, fi));
+                }
+                None if attributes & FILE_ATTRIBUTE_SPARSE_FILE != 0 => {
+                    return Ok((FileKind::Sparse, fi))
+                }
+                // Checked below, as it may still need skipping
+                None if attributes & FILE_ATTRIBUTE_COMPRESSED != 0 => (),
+                None => return Ok((FileKind::Compressed, fi)),
+            }
+        }
+
+        fi.skip_reason = if fi.logical_size <= 4096 {
+            Some(SkipReason::Small)
+        } else if attributes & FILE_ATTRIBUTE_READONLY != 0 {
+            Some(SkipReason::ReadOnly)
+        } else if attributes & FILE_ATTRIBUTE_SYSTEM != 0 {
+            Some(SkipReason::System)
+        } else if attributes & FILE_ATTRIBUTE_TEMPORARY != 0 {
+            Some(SkipReason::Temporary)
+        } else if self
+            .incompressible
+            .get_file(
+                record_path(self.resolved_root, &self.relative(path)),
+                &Fingerprint::from(metadata),
+            )
+            .is_some_and(|record| skips_incompressible(&record, self.compression))
+        {
+            Some(SkipReason::Incompressible)
+        } else if self.excludes.is_match(path) {
+            Some(SkipReason::Excluded)
+        } else {
+            None
+        };
+
+        if fi.skip_reason.is_some() {
+            return Ok((FileKind::Skipped, fi));
+        }
+
+        // Legacy NTFS compression must be cleared before WOF can be applied, so
+        // these are only touched when converting
+        if attributes & FILE_ATTRIBUTE_COMPRESSED != 0 {
+            return Ok((FileKind::NtfsCompressed, fi));
+        }
+
+        if let Some((compression, cluster_size)) = self.projection {
+            // A file we can't read is still compressible as far as we know,
+            // just without a projection.
+            fi.projected_size = File::open(path)
+                .and_then(|file| {
+                    let file = Stoppable(file, &self.stop);
+                    simulate(file, compression, cluster_size)
+                })
+                .map(|sim| sim.physical_size)
+                .ok();
+        }
+
+        Ok((FileKind::Compressible, fi))
+    }
+
+    fn queue(&self, dirs: &Sender<PathBuf>, dir: PathBuf) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let _ = dirs.send(dir);
+    }
+
+    // Send a result to the coordinator, stopping the walk if it's gone.
+    fn send(&self, files: &Sender<Found>, found: Found) -> bool {
+        if files.send(found).is_err() {
+            self.stop.store(true, Ordering::SeqCst);
+            return false;
+        }
+
+        true
+    }
+
+    // List directories from the queue until there are none left, queueing
+    // subdirectories and sending files and errors to `files`.
+    //
+    // Excluded directories are pruned, while excluded files are still recorded
+    // for accurate sizes.
+    fn work(&self, dirs_rx: Receiver<PathBuf>, dirs: Sender<PathBuf>, files: Sender<Found>) {
+        while !self.stop.load(Ordering::SeqCst) {
+            let dir = match dirs_rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(dir) => dir,
+                Err(RecvTimeoutError::Timeout) if self.pending.load(Ordering::SeqCst) > 0 => {
+                    continue
+                }
+                Err(_) => break,
+            };
+
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.send(&files, Err(self.error(&dir, ScanOperation::ReadDir, &e)));
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        if self.send(&files, Err(self.error(&dir, ScanOperation::ReadDir, &e))) {
+                            continue;
+                        }
+                        break;
+                    }
+                };
+
+                let path = entry.path();
+
+                match entry.file_type() {
+                    Ok(ft) if ft.is_dir() => {
+                        if !self.excludes.is_match(&path) {
+                            self.queue(&dirs, path);
+                        }
+                    }
+                    // Metadata should be infallible on Windows, it comes with
+                    // the DirEntry.
+                    Ok(ft) if ft.is_file() => {
+                        let found = entry
+                            .metadata()
+                            .map_err(|e| self.error(&path, ScanOperation::Metadata, &e))
+                            .and_then(|md| self.file(&path, &md));
+
+                        if !self.send(&files, found) {
+                            break;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        if !self.send(&files, Err(self.error(&path, ScanOperation::Metadata, &e))) {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Reads that fail once the walk is stopped, so large files don't hold it up.
+struct Stoppable<'a, R>(R, &'a AtomicBool);
+
+impl<R: Read> Read for Stoppable<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.1.load(Ordering::SeqCst) {
+            return Err(io::Error::other("scan stopped"));
+        }
+
+        self.0.read(buf)
+    }
+}
+
+impl Background for FolderScan {
+    type Output = Result<FolderInfo, FolderInfo>;
+    type Status = (PathBuf, FolderSummary);
+
+    fn run(self, control: &ControlToken<Self::Status>) -> Self::Output {
+        let FolderScan {
+            path,
+            excludes,
+            backend,
+            threads,
+            projection,
+            compression,
+        } = self;
+        let mut ds = FolderInfo::new(&path);
+        ds.projection = projection;
+        let _ = pathdb().write().unwrap().load();
+        let incompressible = pathdb().read().unwrap();
+        let resolved_root = resolve_folder(&path);
+
+        let walker = Walker {
+            root: &path,
+            resolved_root: &resolved_root,
+            excludes: &excludes,
+            backend: &*backend,
+            incompressible: &incompressible,
+            projection: projection.map(|compression| {
+                let cluster_size = cluster_size(&path).unwrap_or(DEFAULT_CLUSTER_SIZE);
+                (compression, cluster_size)
+            }),
+            compression,
+            pending: AtomicUsize::new(0),
+            stop: AtomicBool::new(false),
+        };
+
+        let (dirs, dirs_rx) = unbounded();
+        // Bounded, so pausing this thread holds up the workers too
+        let (files_tx, files) = bounded(1024);
+
+        match fs::metadata(&path) {
+            Ok(md) if md.is_dir() => {
+                if !excludes.is_match(&path) {
+                    walker.queue(&dirs, path.clone());
+                }
+            }
+            Ok(md) if md.is_file() => match walker.file(&path, &md) {
+                Ok((kind, fi)) => ds.push(kind, fi),
+                Err(e) => ds.errors.push(e),
+            },
+            Ok(_) => (),
+            Err(e) => ds
+                .errors
+                .push(walker.error(&path, ScanOperation::Metadata, &e)),
+        }
+
+        let cancelled = thread::scope(|scope| {
+            for _ in 0..threads {
+                let (dirs_rx, dirs, files_tx) = (dirs_rx.clone(), dirs.clone(), files_tx.clone());
+                let walker = &walker;
+                scope.spawn(move || walker.work(dirs_rx, dirs, files_tx));
+            }
+            drop(files_tx);
+
+            let mut last_status = Instant::now();
+
+            loop {
+                if control.is_cancelled_with_pause() {
+                    // Unblock any workers waiting to send
+                    walker.stop.store(true, Ordering::SeqCst);
+                    drop(files);
+                    return true;
+                }
+
+                match files.recv_timeout(Duration::from_millis(50)) {
+                    Ok(Ok((kind, fi))) => {
+                        if last_status.elapsed() >= Duration::from_millis(50) {
+                            last_status = Instant::now();
+                            control.set_status((fi.path.clone(), ds.summary()));
+                        }
+
+                        ds.push(kind, fi);
+                    }
+                    Ok(Err(e)) => ds.errors.push(e),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return control.is_cancelled(),
+                }
+            }
+        });
+
+        ds.sort();
+
+        if cancelled {
+            Err(ds)
+        } else {
+            Ok(ds)
+        }
+    }
+}
+
+#[test]
+fn it_walks() {
+    use crate::background::BackgroundHandle;
+    use crate::compact::default_backend;
+    use crate::config::Config;
+
+    let gs = Config::default().globset().unwrap();
+    let scanner = FolderScan::new("C:\\Games", gs, default_backend());
+
+    let task = BackgroundHandle::spawn(scanner);
+
+    let deadline = Instant::now() + Duration::from_millis(2000);
+
+    loop {
+        let ret = task.wait_timeout(Duration::from_millis(100));
+
+        if ret.is_some() {
+            println!("Scanned: {:?}", ret);
+            break;
+        } else {
+            println!("Status: {:?}", task.status());
+        }
+
+        if Instant::now() > deadline {
+            task.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+fn make_tree() -> tempdir::TempDir {
+    let dir = tempdir::TempDir::new("compactor-folder").unwrap();
+    for sub in &["a", "a/b", "a/b/c", "d", "skip", "skip/e"] {
+        fs::create_dir(dir.path().join(sub)).unwrap();
+    }
+    for file in &[
+        "z", "a/y", "a/b/x", "a/b/c/w", "a/v", "d/u", "skip/t", "skip/e/s",
+    ] {
+        fs::write(dir.path().join(file), "compress me ".repeat(1024)).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn it_walks_in_parallel_deterministically() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+    use globset::{Glob, GlobSetBuilder};
+
+    let dir = make_tree();
+    let mut excludes = GlobSetBuilder::new();
+    excludes.add(Glob::new("**/skip").unwrap());
+    let excludes = excludes.build().unwrap();
+
+    let scan = |threads| {
+        let scanner = FolderScan::new(
+            dir.path(),
+            excludes.clone(),
+            Arc::new(MockBackend::default()),
+        )
+        .with_threads(threads);
+        let info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+
+        let paths = info
+            .compressible
+            .files
+            .iter()
+            .map(|fi| fi.path.to_string_lossy().replace('\\', "/"))
+            .collect::<Vec<_>>();
+        (paths, info.logical_size)
+    };
+
+    let serial = scan(1);
+    assert_eq!(serial.0, vec!["a/b/c/w", "a/b/x", "a/v", "a/y", "d/u", "z"]);
+
+    for _ in 0..10 {
+        assert_eq!(serial, scan(8));
+    }
+}
+
+#[test]
+fn it_cancels_parallel_walks() {
+    use std::panic::AssertUnwindSafe;
+
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+
+    // Holds up every file until let through, failing once the gate is dropped.
+    #[derive(Debug)]
+    struct Gated(MockBackend, AssertUnwindSafe<Receiver<()>>);
+
+    impl CompressionBackend for Gated {
+        fn supports_compression(&self, path: &Path) -> io::Result<bool> {
+            self.0.supports_compression(path)
+        }
+
+        fn detect_provider(&self, path: &Path) -> io::Result<Option<Provider>> {
+            self.0.detect_provider(path)
+        }
+
+        fn compress(
+            &self,
+            path: &Path,
+            file: &fs::File,
+            compression: crate::compact::Compression,
+        ) -> io::Result<bool> {
+            self.0.compress(path, file, compression)
+        }
+
+        fn uncompress(&self, path: &Path, file: &fs::File) -> io::Result<()> {
+            self.0.uncompress(path, file)
+        }
+
+        fn clear_ntfs_compression(&self, path: &Path, file: &fs::File) -> io::Result<()> {
+            self.0.clear_ntfs_compression(path, file)
+        }
+
+        fn size_on_disk(&self, path: &Path) -> io::Result<u64> {
+            self.1
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::Interrupted))?;
+            self.0.size_on_disk(path)
+        }
+    }
+
+    let dir = make_tree();
+    let (gate, gate_rx) = bounded(0);
+    let backend = Gated(MockBackend::default(), AssertUnwindSafe(gate_rx));
+    let scanner = FolderScan::new(dir.path(), GlobSet::empty(), Arc::new(backend)).with_threads(8);
+
+    let task = BackgroundHandle::spawn(scanner);
+    gate.send(()).unwrap();
+    task.cancel();
+    drop(gate);
+
+    let info = task.wait().expect_err("cancelled");
+    assert!(info.summary().compressible.count <= 1);
+}
+
+#[test]
+fn it_records_scan_errors() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+
+    let dir = make_tree();
+    let backend = Arc::new(MockBackend::default());
+    backend.set_unreadable(dir.path().join("a/v"));
+
+    let scan = |path: &Path| {
+        let scanner = FolderScan::new(path, GlobSet::empty(), backend.clone());
+        BackgroundHandle::spawn(scanner).wait().expect("scan")
+    };
+
+    let info = scan(dir.path());
+    assert_eq!(info.summary().errors, 1);
+    assert_eq!(info.summary().compressible.count, 7);
+    assert_eq!(info.errors[0].path, Path::new("a").join("v"));
+    assert_eq!(info.errors[0].operation, ScanOperation::SizeOnDisk);
+    assert_eq!(info.errors[0].error.kind, "PermissionDenied");
+
+    let info = scan(&dir.path().join("missing"));
+    assert_eq!(info.summary().errors, 1);
+    assert_eq!(info.errors[0].path, Path::new(""));
+    assert_eq!(info.errors[0].operation, ScanOperation::Metadata);
+    assert_eq!(info.errors[0].error.kind, "NotFound");
+}
+
+#[test]
+fn it_retries_incompressible_files_that_change() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+
+    let dir = make_tree();
+    let updated = dir.path().join("a/y");
+    pathdb()
+        .write()
+        .unwrap()
+        .insert_file(&updated, None)
+        .unwrap();
+
+    let scan = || {
+        let scanner = FolderScan::new(
+            dir.path(),
+            GlobSet::empty(),
+            Arc::new(MockBackend::default()),
+        );
+        BackgroundHandle::spawn(scanner).wait().expect("scan")
+    };
+
+    let summary = scan().summary();
+    assert_eq!(summary.skip_reasons[&SkipReason::Incompressible].count, 1);
+    assert_eq!(summary.compressible.count, 7);
+
+    fs::write(&updated, "compress me again ".repeat(1024)).unwrap();
+    let summary = scan().summary();
+    assert!(summary.skip_reasons.is_empty());
+    assert_eq!(summary.compressible.count, 8);
+}
+
+#[test]
+fn it_retries_incompressible_files_with_stronger_algorithms() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+    use crate::persistence::incompressible_with;
+
+    let dir = make_tree();
+    {
+        let mut pathdb = pathdb().write().unwrap();
+        let attempt = incompressible_with(Compression::Xpress8k, 0.97);
+        pathdb
+            .insert_file(dir.path().join("a/y"), Some(attempt))
+            .unwrap();
+        pathdb.insert_file(dir.path().join("d/u"), None).unwrap();
+    }
+
+    let skipped = |compression: Option<Compression>| {
+        let mut scanner = FolderScan::new(
+            dir.path(),
+            GlobSet::empty(),
+            Arc::new(MockBackend::default()),
+        );
+        if let Some(compression) = compression {
+            scanner = scanner.with_compression(compression);
+        }
+        let info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+        info.summary()
+            .skip_reasons
+            .get(&SkipReason::Incompressible)
+            .map_or(0, |group| group.count)
+    };
+
+    assert_eq!(skipped(None), 2);
+    assert_eq!(skipped(Some(Compression::Xpress4k)), 2);
+    assert_eq!(skipped(Some(Compression::Xpress8k)), 2);
+    assert_eq!(skipped(Some(Compression::Lzx)), 1);
+}
+
+#[test]
+fn it_records_skip_reasons() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+    use globset::{Glob, GlobSetBuilder};
+
+    let dir = make_tree();
+    fs::write(dir.path().join("tiny"), "tiny").unwrap();
+    fs::write(dir.path().join("a/big.zip"), "zip ".repeat(4096)).unwrap();
+    let locked = dir.path().join("d/locked");
+    fs::write(&locked, "locked ".repeat(4096)).unwrap();
+    let mut perms = fs::metadata(&locked).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&locked, perms).unwrap();
+
+    let mut excludes = GlobSetBuilder::new();
+    excludes.add(Glob::new("**/skip").unwrap());
+    excludes.add(Glob::new("*.zip").unwrap());
+    let scanner = FolderScan::new(
+        dir.path(),
+        excludes.build().unwrap(),
+        Arc::new(MockBackend::default()),
+    );
+    let mut info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+
+    let reasons = info
+        .skipped
+        .files
+        .iter()
+        .map(|fi| (fi.path.to_string_lossy().replace('\\', "/"), fi.skip_reason))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reasons,
+        vec![
+            ("a/big.zip".to_string(), Some(SkipReason::Excluded)),
+            ("d/locked".to_string(), Some(SkipReason::ReadOnly)),
+            ("tiny".to_string(), Some(SkipReason::Small)),
+        ]
+    );
+
+    let summary = info.summary();
+    assert_eq!(summary.skip_reasons.len(), 3);
+    assert_eq!(summary.skip_reasons[&SkipReason::Small].count, 1);
+    assert_eq!(
+        summary.skip_reasons[&SkipReason::Excluded].logical_size,
+        4 * 4096
+    );
+
+    let fi = info.pop(FileKind::Skipped).unwrap();
+    assert!(!info.skip_reasons.contains_key(&SkipReason::Excluded));
+    info.push(FileKind::Compressible, fi);
+    assert_eq!(info.compressible.files.back().unwrap().skip_reason, None);
+    assert_eq!(info.summary().skip_reasons.len(), 2);
+}
+
+#[test]
+fn it_projects_compressed_sizes() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+
+    let dir = make_tree();
+    fs::write(dir.path().join("tiny"), "tiny").unwrap();
+
+    let scan = |projection: Option<Compression>| {
+        let mut scanner = FolderScan::new(
+            dir.path(),
+            GlobSet::empty(),
+            Arc::new(MockBackend::default()),
+        );
+        if let Some(compression) = projection {
+            scanner = scanner.with_projection(compression);
+        }
+        BackgroundHandle::spawn(scanner).wait().expect("scan")
+    };
+
+    let info = scan(None);
+    assert_eq!(info.projection, None);
+    assert!(info
+        .compressible
+        .files
+        .iter()
+        .all(|fi| fi.projected_size.is_none()));
+    assert_eq!(
+        info.compressible.projected_size,
+        info.compressible.physical_size
+    );
+
+    let mut info = scan(Some(Compression::Lzx));
+    assert_eq!(info.summary().projection, Some(Compression::Lzx));
+    assert_eq!(info.skipped.files[0].projected_size, None);
+    for fi in &info.compressible.files {
+        let sim = simulate(
+            &fs::read(dir.path().join(&fi.path)).unwrap()[..],
+            Compression::Lzx,
+            4096,
+        );
+        assert_eq!(fi.projected_size, Some(sim.unwrap().physical_size));
+    }
+
+    let summary = info.summary();
+    assert_eq!(summary.compressible.projected_size, 8 * 4096);
+    assert!(summary.compressible.projected_size < summary.compressible.physical_size);
+    assert_eq!(
+        summary.skipped.projected_size,
+        summary.skipped.physical_size
+    );
+
+    let fi = info.pop(FileKind::Compressible).unwrap();
+    assert_eq!(info.compressible.projected_size, 7 * 4096);
+    info.push(FileKind::Compressed, fi);
+    assert_eq!(info.compressed.projected_size, 4096);
+}
+
+#[test]
+fn it_totals_compressed_files_by_algorithm() {
+    let file = |name: &str, compression| FileInfo {
+        path: PathBuf::from(name),
+        logical_size: 8192,
+        physical_size: 4096,
+        skip_reason: None,
+        projected_size: None,
+        compression,
+    };
+
+    let mut info = FolderInfo::new("test");
+    info.push(FileKind::Compressed, file("a", Some(Compression::Lzx)));
+    info.push(FileKind::Compressed, file("b", Some(Compression::Lzx)));
+    info.push(FileKind::Compressed, file("c", None));
+    info.push(
+        FileKind::Compressible,
+        file("d", Some(Compression::Xpress4k)),
+    );
+
+    let summary = info.summary();
+    assert_eq!(summary.algorithms.len(), 1);
+    assert_eq!(summary.algorithms[&Compression::Lzx].count, 2);
+    assert_eq!(summary.algorithms[&Compression::Lzx].physical_size, 8192);
+    assert_eq!(info.compressible.files[0].compression, None);
+
+    let fi = info.pop(FileKind::Compressed).unwrap();
+    assert_eq!(fi.compression, Some(Compression::Lzx));
+    assert_eq!(info.algorithms[&Compression::Lzx].count, 1);
+    info.pop(FileKind::Compressed).unwrap();
+    assert!(info.algorithms.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn it_groups_sparse_files() {
+    use crate::background::BackgroundHandle;
+    use crate::compact::default_backend;
+
+    let dir = make_tree();
+    let sparse = File::create(dir.path().join("d/disk.img")).unwrap();
+    sparse.set_len(64 << 20).unwrap();
+
+    let scanner = FolderScan::new(dir.path(), GlobSet::empty(), default_backend());
+    let info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+
+    assert_eq!(info.sparse.files.len(), 1);
+    assert_eq!(info.sparse.files[0].path, Path::new("d").join("disk.img"));
+    assert_eq!(info.sparse.logical_size, 64 << 20);
+    assert!(info.compressed.files.is_empty());
+    assert_eq!(info.summary().file_count(), 9);
+}
+
+#[cfg(unix)]
+#[test]
+fn it_finds_incompressible_files_through_links() {
+    use crate::background::BackgroundHandle;
+    use crate::mock::MockBackend;
+
+    let dir = make_tree();
+    let link = tempdir::TempDir::new("compactor-link").unwrap();
+    let linked = link.path().join("tree");
+    std::os::unix::fs::symlink(dir.path(), &linked).unwrap();
+
+    // As compressing through the link would record it
+    let record = record_path(&resolve_folder(&linked), Path::new("a/y"));
+    pathdb().write().unwrap().insert_file(record, None).unwrap();
+
+    for root in &[dir.path(), &linked] {
+        let scanner = FolderScan::new(root, GlobSet::empty(), Arc::new(MockBackend::default()));
+        let info = BackgroundHandle::spawn(scanner).wait().expect("scan");
+        assert_eq!(info.path, *root);
+        assert_eq!(
+            info.summary().skip_reasons[&SkipReason::Incompressible].count,
+            1
+        );
+    }
+}
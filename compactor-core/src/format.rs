@@ -0,0 +1,13 @@
+//! Human-readable formatting.
+
+/// Format a size in bytes, in decimal (KB, MB) or binary (KiB, MiB) units.
+pub fn format_size(size: u64, decimal: bool) -> String {
+    use humansize::{file_size_opts as options, FileSize};
+
+    size.file_size(if decimal {
+        options::DECIMAL
+    } else {
+        options::BINARY
+    })
+    .expect("file size")
+}
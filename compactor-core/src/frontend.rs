@@ -0,0 +1,70 @@
+//! The interface between a `Backend` and whatever is driving it.
+//!
+//! A frontend sends `Command`s to a `Backend` over a channel, and the
+//! `Backend` reports back through the `Frontend` trait.
+
+use std::path::{Path, PathBuf};
+
+use crate::compare::FolderComparison;
+use crate::folder::FolderSummary;
+
+/// Requests for a `Backend` to do something.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Ask the frontend for a folder, and scan it.
+    ChooseFolder,
+    /// Scan the given folder.
+    Scan(PathBuf),
+    /// Rescan the current folder.
+    Analyse,
+    /// Compress the current folder.
+    Compress,
+    /// Decompress the current folder.
+    Decompress,
+    /// Convert legacy NTFS-compressed files in the current folder to WOF.
+    Convert,
+    /// Recompress files in the current folder that use a different algorithm.
+    Recompress,
+    /// Compare compression algorithms on the current folder.
+    Compare,
+    /// Forget files in the current folder recorded as incompressible, and
+    /// rescan it.
+    Forget,
+    Pause,
+    Resume,
+    Stop,
+}
+
+/// Changes in what a `Backend` is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Compression or decompression has started.
+    Compacting,
+    /// An algorithm comparison has started.
+    Comparing,
+    Paused,
+    Resumed,
+    /// A scan, compression, decompression or comparison has finished.
+    Scanned,
+    /// A scan was stopped before finishing.
+    Stopped,
+}
+
+pub trait Frontend {
+    /// Display a status message, with optional progress from 0.0 to 1.0.
+    fn status(&self, msg: &str, pct: Option<f32>);
+
+    /// Display the current state of the folder.
+    fn summary(&self, info: FolderSummary);
+
+    /// Display the results of an algorithm comparison.
+    fn comparison(&self, comparison: FolderComparison);
+
+    /// The folder being worked on has changed.
+    fn folder(&self, path: &Path);
+
+    fn state(&self, state: State);
+
+    /// Ask the user for a folder, blocking until they answer.
+    fn choose_folder(&self) -> Option<PathBuf>;
+}
@@ -0,0 +1,89 @@
+//! The guts of [Compactor]: folder analysis, compressibility estimation, and
+//! driving Windows 10 filesystem compression, free of any particular user
+//! interface.
+//!
+//! The general flow is:
+//!
+//! 1. Build a [`FolderScan`] and run it with [`BackgroundHandle::spawn`],
+//!    polling for status updates, to get a [`FolderInfo`] grouping files into
+//!    compressible, compressed and skipped.
+//! 2. Hand the [`FolderInfo`] to a [`Compaction`], which feeds files from the
+//!    appropriate group to [`BackgroundCompactor`]s to compress or decompress
+//!    them, filing each back where it now belongs.
+//!
+//! All filesystem compression goes through a [`CompressionBackend`].  On
+//! Windows the default is WOF, the Windows Overlay Filter; elsewhere
+//! compression is unsupported, but [`MockBackend`] can stand in for testing.
+//!
+//! [`Backend`] wraps all this up, taking [`Command`]s from a channel and
+//! reporting back to a [`Frontend`], which is how the GUI works.
+//!
+//! The [`codec`] module has pure-Rust versions of WOF's compression formats,
+//! for predicting and checking its results on any platform.  [`simulate`]
+//! uses them to work out how much space compressing a file would save, and a
+//! [`Comparison`] samples a folder to weigh up every algorithm against the
+//! others.  [`wof`] reads WOF's on-disk format, so compressed files can be
+//! checked without Windows, and an [`ImageScan`] reads an NTFS disk image
+//! directly, to analyse a volume without mounting it.
+//!
+//! Progress and results can be reported to other programs as [`Event`]s,
+//! serialized as newline-delimited JSON by [`NdjsonWriter`].
+//!
+//! ```
+//! use compactor_core::{default_backend, BackgroundHandle, Config, FolderScan};
+//!
+//! let excludes = Config::default().globset().expect("default globs");
+//! let scan = FolderScan::new(".", excludes, default_backend());
+//! let info = BackgroundHandle::spawn(scan).wait().expect("scan completed");
+//!
+//! println!("{} compressible files", info.summary().compressible.count);
+//! ```
+//!
+//! [Compactor]: https://github.com/Freaky/Compactor
+//! [`FolderScan`]: folder::FolderScan
+//! [`FolderInfo`]: folder::FolderInfo
+//! [`BackgroundHandle::spawn`]: background::BackgroundHandle::spawn
+//! [`Compaction`]: compaction::Compaction
+//! [`BackgroundCompactor`]: compression::BackgroundCompactor
+//! [`CompressionBackend`]: compact::CompressionBackend
+//! [`MockBackend`]: mock::MockBackend
+//! [`Backend`]: backend::Backend
+//! [`Command`]: frontend::Command
+//! [`Frontend`]: frontend::Frontend
+//! [`Event`]: event::Event
+//! [`NdjsonWriter`]: event::NdjsonWriter
+//! [`Comparison`]: compare::Comparison
+//! [`ImageScan`]: ntfs::ImageScan
+
+pub mod backend;
+pub mod background;
+pub mod codec;
+pub mod compact;
+pub mod compaction;
+pub mod compare;
+pub mod compression;
+pub mod config;
+pub mod event;
+pub mod folder;
+pub mod format;
+pub mod frontend;
+pub mod mock;
+pub mod ntfs;
+pub mod persistence;
+pub mod platform;
+pub mod simulate;
+pub mod wof;
+
+pub use crate::backend::Backend;
+pub use crate::background::{Background, BackgroundHandle, ControlToken};
+pub use crate::compact::{default_backend, Compression, CompressionBackend};
+pub use crate::compare::{Comparison, FolderComparison};
+pub use crate::compaction::{Compaction, CompactionSummary};
+pub use crate::compression::BackgroundCompactor;
+pub use crate::config::{Config, ConfigFile};
+pub use crate::event::{Event, NdjsonWriter};
+pub use crate::folder::{
+    FileInfo, FileKind, FolderInfo, FolderScan, FolderSummary, ScanError, ScanOperation, SkipReason,
+};
+pub use crate::frontend::{Command, Frontend, State};
+pub use crate::ntfs::ImageScan;
@@ -0,0 +1,298 @@
+//! An in-memory stand-in for WOF compression.
+//!
+//! `MockBackend` never modifies the files it's given - it just remembers which
+//! ones it has "compressed", with what, and how big they'd be on disk.  This
+//! lets the scan/compress/decompress flow run on systems without WofUtil.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::compact::{Compression, CompressionBackend};
+use crate::platform::{self, FILE_ATTRIBUTE_COMPRESSED};
+use crate::wof::Provider;
+
+/// The state of a file `MockBackend` has compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MockFile {
+    pub compression: Compression,
+    pub physical_size: u64,
+}
+
+/// A `CompressionBackend` that pretends to compress files.
+///
+/// Each algorithm shrinks files by a fixed ratio, rounded up to the cluster
+/// size, and files can be marked as incompressible, unreadable, WIM-backed or
+/// NTFS-compressed.
+#[derive(Debug)]
+pub struct MockBackend {
+    cluster_size: u64,
+    files: Mutex<HashMap<PathBuf, MockFile>>,
+    incompressible: Mutex<HashSet<PathBuf>>,
+    unreadable: Mutex<HashSet<PathBuf>>,
+    wim: Mutex<HashSet<PathBuf>>,
+    ntfs_compressed: Mutex<HashSet<PathBuf>>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::with_cluster_size(4096)
+    }
+}
+
+impl MockBackend {
+    pub fn with_cluster_size(cluster_size: u64) -> Self {
+        assert!(cluster_size > 0);
+
+        Self {
+            cluster_size,
+            files: Mutex::new(HashMap::new()),
+            incompressible: Mutex::new(HashSet::new()),
+            unreadable: Mutex::new(HashSet::new()),
+            wim: Mutex::new(HashSet::new()),
+            ntfs_compressed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Make future attempts to compress `path` report it wasn't beneficial.
+    pub fn set_incompressible<P: AsRef<Path>>(&self, path: P) {
+        self.incompressible
+            .lock()
+            .unwrap()
+            .insert(path.as_ref().to_owned());
+    }
+
+    /// Make future attempts to get the size of `path` fail with
+    /// `PermissionDenied`.
+    pub fn set_unreadable<P: AsRef<Path>>(&self, path: P) {
+        self.unreadable
+            .lock()
+            .unwrap()
+            .insert(path.as_ref().to_owned());
+    }
+
+    /// Make `path` look like it's backed by a WIM image, taking no space of its
+    /// own.
+    pub fn set_wim<P: AsRef<Path>>(&self, path: P) {
+        self.wim.lock().unwrap().insert(path.as_ref().to_owned());
+    }
+
+    fn is_wim(&self, path: &Path) -> bool {
+        self.wim.lock().unwrap().contains(path)
+    }
+
+    /// Make `path` look like it uses legacy NTFS compression, which must be
+    /// cleared before it can be compressed.
+    pub fn set_ntfs_compressed<P: AsRef<Path>>(&self, path: P) {
+        self.ntfs_compressed
+            .lock()
+            .unwrap()
+            .insert(path.as_ref().to_owned());
+    }
+
+    fn is_ntfs_compressed(&self, path: &Path) -> bool {
+        self.ntfs_compressed.lock().unwrap().contains(path)
+    }
+
+    /// The mock compression state of `path`, if it's compressed.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<MockFile> {
+        self.files.lock().unwrap().get(path.as_ref()).copied()
+    }
+
+    /// The number of files currently compressed.
+    pub fn compressed_count(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    fn round_up(&self, size: u64) -> u64 {
+        size.div_ceil(self.cluster_size) * self.cluster_size
+    }
+
+    // Deterministic stand-ins for each algorithm's compression ratio, in
+    // percent.  LZNT1 manages 80.
+    fn ratio(compression: Compression) -> u64 {
+        match compression {
+            Compression::Xpress4k => 70,
+            Compression::Xpress8k => 65,
+            Compression::Xpress16k => 60,
+            Compression::Lzx => 50,
+        }
+    }
+}
+
+impl CompressionBackend for MockBackend {
+    fn supports_compression(&self, _path: &Path) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn detect_provider(&self, path: &Path) -> io::Result<Option<Provider>> {
+        if self.is_wim(path) {
+            return Ok(Some(Provider::Wim {
+                data_source_id: 1,
+                resource_hash: [0; 20],
+            }));
+        }
+
+        Ok(self.get(path).map(|f| Provider::File(f.compression)))
+    }
+
+    fn compress(&self, path: &Path, file: &File, compression: Compression) -> io::Result<bool> {
+        let logical = file.metadata()?.len();
+
+        if self.is_ntfs_compressed(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file uses NTFS compression",
+            ));
+        }
+
+        if self.get(path).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file is already compressed",
+            ));
+        }
+
+        if self.incompressible.lock().unwrap().contains(path) {
+            return Ok(false);
+        }
+
+        let physical_size = self.round_up(logical * Self::ratio(compression) / 100);
+
+        if physical_size >= self.round_up(logical) {
+            return Ok(false);
+        }
+
+        self.files.lock().unwrap().insert(
+            path.to_owned(),
+            MockFile {
+                compression,
+                physical_size,
+            },
+        );
+
+        Ok(true)
+    }
+
+    fn uncompress(&self, path: &Path, _file: &File) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is not compressed",
+            )),
+        }
+    }
+
+    fn clear_ntfs_compression(&self, path: &Path, _file: &File) -> io::Result<()> {
+        self.ntfs_compressed.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn size_on_disk(&self, path: &Path) -> io::Result<u64> {
+        if self.unreadable.lock().unwrap().contains(path) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+
+        let logical = std::fs::metadata(path)?.len();
+
+        if self.is_wim(path) {
+            return Ok(0);
+        }
+
+        if self.is_ntfs_compressed(path) {
+            return Ok(self.round_up(logical * 80 / 100));
+        }
+
+        Ok(self
+            .get(path)
+            .map(|f| f.physical_size)
+            .unwrap_or_else(|| self.round_up(logical)))
+    }
+
+    fn file_attributes(&self, path: &Path, metadata: &Metadata) -> u32 {
+        let attributes = platform::file_attributes(metadata);
+
+        if self.is_ntfs_compressed(path) {
+            attributes | FILE_ATTRIBUTE_COMPRESSED
+        } else {
+            attributes
+        }
+    }
+}
+
+#[test]
+fn mock_scan_compress_decompress() {
+    use std::sync::Arc;
+
+    use crossbeam_channel::unbounded;
+    use globset::GlobSet;
+
+    use crate::background::BackgroundHandle;
+    use crate::compression::{BackgroundCompactor, Compacted};
+    use crate::folder::{FolderInfo, FolderScan};
+
+    let dir = tempdir::TempDir::new("compactor-mock").unwrap();
+    let big = dir.path().join("big.txt");
+    std::fs::write(&big, "compress me please ".repeat(8192)).unwrap();
+    std::fs::write(dir.path().join("small.txt"), "tiny").unwrap();
+
+    let backend = Arc::new(MockBackend::default());
+
+    let scan = |backend: &Arc<MockBackend>| -> FolderInfo {
+        let scanner = FolderScan::new(dir.path(), GlobSet::empty(), backend.clone());
+        BackgroundHandle::spawn(scanner).wait().expect("scan")
+    };
+
+    let run = |compression: Option<Compression>, files: Vec<PathBuf>| {
+        let (send_file, files_in) = unbounded();
+        let (files_out, results) = unbounded();
+        let compactor = BackgroundCompactor::new(backend.clone(), compression, files_in, files_out);
+        let task = BackgroundHandle::spawn(compactor);
+
+        for file in files {
+            send_file.send((file, 0)).unwrap();
+        }
+        drop(send_file);
+        task.wait();
+
+        results.iter().collect::<Vec<_>>()
+    };
+
+    let info = scan(&backend).summary();
+    assert_eq!(info.compressible.count, 1);
+    assert_eq!(info.skipped.count, 1);
+    assert_eq!(info.compressed.count, 0);
+
+    let results = run(Some(Compression::Lzx), vec![big.clone()]);
+    assert_eq!(results.len(), 1);
+    assert!(matches!(results[0].1, Ok(Compacted::Done)));
+    assert_eq!(backend.get(&big).unwrap().compression, Compression::Lzx);
+
+    let info = scan(&backend).summary();
+    assert_eq!(info.compressible.count, 0);
+    assert_eq!(info.compressed.count, 1);
+    assert!(info.compressed.physical_size < info.compressed.logical_size);
+
+    let results = run(None, vec![big.clone()]);
+    assert!(results[0].1.is_ok());
+    assert_eq!(backend.compressed_count(), 0);
+
+    let info = scan(&backend).summary();
+    assert_eq!(info.compressible.count, 1);
+    assert_eq!(info.compressed.count, 0);
+
+    // WIM-backed files are left to Windows
+    backend.set_wim(&big);
+    let info = scan(&backend).summary();
+    assert_eq!(info.wim.count, 1);
+    assert_eq!(info.compressed.count, 0);
+
+    for compression in [Some(Compression::Lzx), None] {
+        let results = run(compression, vec![big.clone()]);
+        assert!(results[0].1.is_err());
+    }
+    assert_eq!(backend.compressed_count(), 0);
+}
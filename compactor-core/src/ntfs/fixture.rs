@@ -0,0 +1,438 @@
+//! Small NTFS images for tests, built from scratch.
+//!
+//! They hold just enough for `Volume` to read: a boot sector, an MFT with the
+//! usual system records, and whatever files and directories are added.  Each
+//! record gets an update sequence, and non-resident data is split into two
+//! fragments, the second before the first, to exercise fixups and data runs.
+
+use std::io;
+
+use super::{Run, ATTR_DATA, ATTR_FILE_NAME, ATTR_REPARSE_POINT, ATTR_STANDARD_INFORMATION};
+use super::{FIRST_USER_RECORD, ROOT_RECORD};
+use crate::compact::Compression;
+use crate::platform::FILE_ATTRIBUTE_SPARSE_FILE;
+use crate::wof::{self, Provider, WOF_STREAM};
+
+pub(crate) const CLUSTER_SIZE: usize = 4096;
+pub(crate) const RECORD_SIZE: usize = 1024;
+
+// Where the MFT starts
+const MFT_LCN: u64 = 1;
+
+// Values larger than this are stored non-resident
+const MAX_RESIDENT: usize = 512;
+
+const SYSTEM_FILES: [&str; 12] = [
+    "$MFT", "$MFTMirr", "$LogFile", "$Volume", "$AttrDef", ".", "$Bitmap", "$Boot", "$BadClus",
+    "$Secure", "$UpCase", "$Extend",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) enum Data {
+    /// Resident if small enough, otherwise in clusters of its own.
+    Bytes(Vec<u8>),
+    /// Entirely sparse.
+    Sparse(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    parent: u64,
+    name: String,
+    directory: bool,
+    attributes: u32,
+    data: Option<Data>,
+    reparse: Option<Vec<u8>>,
+    wof: Option<Vec<u8>>,
+    // Put the WofCompressedData stream in an extension record
+    extension: bool,
+}
+
+/// Builds an NTFS image, with files in records from `FIRST_USER_RECORD` on.
+#[derive(Debug, Default)]
+pub(crate) struct ImageBuilder {
+    nodes: Vec<Node>,
+}
+
+impl ImageBuilder {
+    fn add(&mut self, parent: u64, name: &str, directory: bool, data: Option<Data>) -> u64 {
+        self.nodes.push(Node {
+            parent,
+            name: name.to_string(),
+            directory,
+            attributes: 0,
+            data,
+            reparse: None,
+            wof: None,
+            extension: false,
+        });
+
+        FIRST_USER_RECORD + self.nodes.len() as u64 - 1
+    }
+
+    fn node(&mut self, record: u64) -> &mut Node {
+        &mut self.nodes[(record - FIRST_USER_RECORD) as usize]
+    }
+
+    pub(crate) fn dir(&mut self, parent: u64, name: &str) -> u64 {
+        self.add(parent, name, true, None)
+    }
+
+    pub(crate) fn file(&mut self, parent: u64, name: &str, data: &[u8]) -> u64 {
+        self.add(parent, name, false, Some(Data::Bytes(data.to_vec())))
+    }
+
+    pub(crate) fn sparse_file(&mut self, parent: u64, name: &str, size: u64) -> u64 {
+        self.add(parent, name, false, Some(Data::Sparse(size)))
+    }
+
+    /// Add a file compressed by WOF's file provider.
+    pub(crate) fn wof_file(
+        &mut self,
+        parent: u64,
+        name: &str,
+        data: &[u8],
+        compression: Compression,
+    ) -> u64 {
+        let record = self.sparse_file(parent, name, data.len() as u64);
+        let node = self.node(record);
+        node.reparse = Some(Provider::File(compression).to_reparse_point());
+        node.wof = Some(wof::compress(data, compression));
+        record
+    }
+
+    /// Replace a file's reparse point.
+    pub(crate) fn reparse(&mut self, record: u64, reparse: Vec<u8>) {
+        self.node(record).reparse = Some(reparse);
+    }
+
+    /// Replace a file's `WofCompressedData` stream.
+    pub(crate) fn wof_stream(&mut self, record: u64, stream: Vec<u8>) {
+        self.node(record).wof = Some(stream);
+    }
+
+    /// Move a file's `WofCompressedData` stream to an extension record.
+    pub(crate) fn extension(&mut self, record: u64) {
+        self.node(record).extension = true;
+    }
+
+    /// Set a file's `FILE_ATTRIBUTE_*` flags.
+    pub(crate) fn attributes(&mut self, record: u64, attributes: u32) {
+        self.node(record).attributes = attributes;
+    }
+
+    pub(crate) fn build(&self) -> Vec<u8> {
+        let extensions = self.nodes.iter().filter(|n| n.extension).count();
+        let records = FIRST_USER_RECORD as usize + self.nodes.len() + extensions;
+        let mft_clusters = (records * RECORD_SIZE).div_ceil(CLUSTER_SIZE);
+
+        let mut image = Image {
+            data: vec![0; (MFT_LCN as usize + mft_clusters) * CLUSTER_SIZE],
+        };
+        image.data[..512].copy_from_slice(&boot_sector());
+
+        let mut mft = vec![];
+        for (number, name) in SYSTEM_FILES.iter().enumerate() {
+            let mut attrs = vec![file_name(ROOT_RECORD, name, 3, number == 5)];
+            if number == 0 {
+                let runs = [Run {
+                    lcn: Some(MFT_LCN),
+                    length: mft_clusters as u64,
+                }];
+                attrs.push(non_resident(
+                    ATTR_DATA,
+                    "",
+                    0,
+                    &runs,
+                    (records * RECORD_SIZE) as u64,
+                    None,
+                ));
+            }
+            mft.push(record(&attrs, number == 5 || number == 11, 0));
+        }
+        mft.resize(FIRST_USER_RECORD as usize, vec![0; RECORD_SIZE]);
+
+        let mut extension_records = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let number = FIRST_USER_RECORD + i as u64;
+            let mut attributes = node.attributes;
+            if let Some(Data::Sparse(_)) = node.data {
+                attributes |= FILE_ATTRIBUTE_SPARSE_FILE;
+            }
+            let mut attrs = vec![standard_information(attributes)];
+
+            // Long names get a DOS name too, which should be ignored
+            if node.name.len() > 12 {
+                attrs.push(file_name(node.parent, "LONGNA~1.TXT", 2, node.directory));
+                attrs.push(file_name(node.parent, &node.name, 1, node.directory));
+            } else {
+                attrs.push(file_name(node.parent, &node.name, 3, node.directory));
+            }
+
+            match &node.data {
+                Some(Data::Bytes(data)) => attrs.push(image.value(ATTR_DATA, "", data)),
+                Some(Data::Sparse(size)) => {
+                    let runs = [Run {
+                        lcn: None,
+                        length: (*size as usize).div_ceil(CLUSTER_SIZE) as u64,
+                    }];
+                    attrs.push(non_resident(ATTR_DATA, "", 0x8000, &runs, *size, Some(0)));
+                }
+                None => (),
+            }
+
+            if let Some(reparse) = &node.reparse {
+                attrs.push(resident(ATTR_REPARSE_POINT, "", reparse));
+            }
+
+            if let Some(stream) = &node.wof {
+                let attr = image.value(ATTR_DATA, WOF_STREAM, stream);
+                if node.extension {
+                    extension_records.push(record(&[attr], false, number));
+                } else {
+                    attrs.push(attr);
+                }
+            }
+
+            mft.push(record(&attrs, node.directory, 0));
+        }
+        mft.extend(extension_records);
+
+        let mft = mft.concat();
+        let start = MFT_LCN as usize * CLUSTER_SIZE;
+        image.data[start..start + mft.len()].copy_from_slice(&mft);
+        image.data
+    }
+}
+
+struct Image {
+    data: Vec<u8>,
+}
+
+impl Image {
+    // Store an attribute value, resident if it's small.
+    fn value(&mut self, kind: u32, name: &str, value: &[u8]) -> Vec<u8> {
+        if value.len() <= MAX_RESIDENT {
+            return resident(kind, name, value);
+        }
+
+        // Second half first, with a gap, so the second run's offset is
+        // negative
+        let clusters = value.len().div_ceil(CLUSTER_SIZE);
+        let split = clusters.div_ceil(2);
+        let (head, tail) = value.split_at((split * CLUSTER_SIZE).min(value.len()));
+        let second = self.allocate(tail);
+        self.data.extend(vec![0; CLUSTER_SIZE]);
+        let first = self.allocate(head);
+
+        let mut runs = vec![Run {
+            lcn: Some(first),
+            length: split as u64,
+        }];
+        if clusters > split {
+            runs.push(Run {
+                lcn: Some(second),
+                length: (clusters - split) as u64,
+            });
+        }
+
+        non_resident(kind, name, 0, &runs, value.len() as u64, None)
+    }
+
+    // Append data in whole clusters, returning the first one's number.
+    fn allocate(&mut self, data: &[u8]) -> u64 {
+        let lcn = (self.data.len() / CLUSTER_SIZE) as u64;
+        self.data.extend(data);
+        self.data
+            .resize(self.data.len().div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE, 0);
+        lcn
+    }
+}
+
+fn boot_sector() -> Vec<u8> {
+    let mut boot = vec![0; 512];
+    boot[3..11].copy_from_slice(b"NTFS    ");
+    boot[0x0b..0x0d].copy_from_slice(&512u16.to_le_bytes());
+    boot[0x0d] = (CLUSTER_SIZE / 512) as u8;
+    boot[0x30..0x38].copy_from_slice(&MFT_LCN.to_le_bytes());
+    // Negative: 2^10 bytes per record
+    boot[0x40] = -10i8 as u8;
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+    boot
+}
+
+/// Encode mapping pairs, as used by non-resident attributes.
+pub(crate) fn encode_runs(runs: &[Run]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut last = 0i64;
+
+    for run in runs {
+        let length = minimal(run.length as i64, false);
+        let offset = match run.lcn {
+            Some(lcn) => {
+                let delta = lcn as i64 - last;
+                last = lcn as i64;
+                minimal(delta, true)
+            }
+            None => vec![],
+        };
+
+        out.push(length.len() as u8 | (offset.len() as u8) << 4);
+        out.extend(length);
+        out.extend(offset);
+    }
+
+    out.push(0);
+    out
+}
+
+// The fewest little-endian bytes holding `n`, keeping its sign if `signed`.
+fn minimal(n: i64, signed: bool) -> Vec<u8> {
+    let bytes = n.to_le_bytes();
+    for len in 1..8 {
+        let shift = 64 - 8 * len as u32;
+        let fits = if signed {
+            (n << shift) >> shift == n
+        } else {
+            ((n as u64) << shift) >> shift == n as u64
+        };
+
+        if fits {
+            return bytes[..len].to_vec();
+        }
+    }
+    bytes.to_vec()
+}
+
+fn attribute_header(kind: u32, name: &str, non_resident: bool, flags: u16) -> (Vec<u8>, usize) {
+    let name = name.encode_utf16().collect::<Vec<_>>();
+    let header_len = if non_resident {
+        if flags != 0 {
+            0x48
+        } else {
+            0x40
+        }
+    } else {
+        0x18
+    };
+
+    let mut attr = vec![0; header_len];
+    attr[0..4].copy_from_slice(&kind.to_le_bytes());
+    attr[8] = non_resident as u8;
+    attr[9] = name.len() as u8;
+    attr[0x0a..0x0c].copy_from_slice(&(header_len as u16).to_le_bytes());
+    attr[0x0c..0x0e].copy_from_slice(&flags.to_le_bytes());
+    for c in name {
+        attr.extend(&c.to_le_bytes());
+    }
+    attr.resize(attr.len().div_ceil(8) * 8, 0);
+
+    let content = attr.len();
+    (attr, content)
+}
+
+fn finish_attribute(mut attr: Vec<u8>) -> Vec<u8> {
+    attr.resize(attr.len().div_ceil(8) * 8, 0);
+    let len = attr.len() as u32;
+    attr[4..8].copy_from_slice(&len.to_le_bytes());
+    attr
+}
+
+fn resident(kind: u32, name: &str, value: &[u8]) -> Vec<u8> {
+    let (mut attr, offset) = attribute_header(kind, name, false, 0);
+    attr[0x10..0x14].copy_from_slice(&(value.len() as u32).to_le_bytes());
+    attr[0x14..0x16].copy_from_slice(&(offset as u16).to_le_bytes());
+    attr.extend(value);
+    finish_attribute(attr)
+}
+
+fn non_resident(
+    kind: u32,
+    name: &str,
+    flags: u16,
+    runs: &[Run],
+    real_size: u64,
+    compressed_size: Option<u64>,
+) -> Vec<u8> {
+    let clusters = runs.iter().map(|r| r.length).sum::<u64>();
+    let (mut attr, offset) = attribute_header(kind, name, true, flags);
+    attr[0x18..0x20].copy_from_slice(&clusters.saturating_sub(1).to_le_bytes());
+    attr[0x20..0x22].copy_from_slice(&(offset as u16).to_le_bytes());
+    let allocated = clusters * CLUSTER_SIZE as u64;
+    attr[0x28..0x30].copy_from_slice(&allocated.to_le_bytes());
+    attr[0x30..0x38].copy_from_slice(&real_size.to_le_bytes());
+    attr[0x38..0x40].copy_from_slice(&real_size.to_le_bytes());
+    if let Some(compressed) = compressed_size {
+        attr[0x40..0x48].copy_from_slice(&compressed.to_le_bytes());
+    }
+    attr.extend(encode_runs(runs));
+    finish_attribute(attr)
+}
+
+fn standard_information(attributes: u32) -> Vec<u8> {
+    let mut value = vec![0; 0x48];
+    value[0x20..0x24].copy_from_slice(&attributes.to_le_bytes());
+    resident(ATTR_STANDARD_INFORMATION, "", &value)
+}
+
+fn file_name(parent: u64, name: &str, namespace: u8, directory: bool) -> Vec<u8> {
+    let name = name.encode_utf16().collect::<Vec<_>>();
+    let mut value = vec![0; 0x42];
+    // With a sequence number in the top 16 bits, as real references have
+    value[0..8].copy_from_slice(&(parent | 1 << 48).to_le_bytes());
+    if directory {
+        value[0x38..0x3c].copy_from_slice(&0x1000_0000u32.to_le_bytes());
+    }
+    value[0x40] = name.len() as u8;
+    value[0x41] = namespace;
+    for c in name {
+        value.extend(&c.to_le_bytes());
+    }
+    resident(ATTR_FILE_NAME, "", &value)
+}
+
+fn record(attrs: &[Vec<u8>], directory: bool, base: u64) -> Vec<u8> {
+    const USA_OFFSET: usize = 0x30;
+    const USA_COUNT: usize = RECORD_SIZE / 512 + 1;
+    const USN: u16 = 0x0ace;
+
+    let mut data = vec![0; RECORD_SIZE];
+    data[0..4].copy_from_slice(b"FILE");
+    data[4..6].copy_from_slice(&(USA_OFFSET as u16).to_le_bytes());
+    data[6..8].copy_from_slice(&(USA_COUNT as u16).to_le_bytes());
+    data[0x10..0x12].copy_from_slice(&1u16.to_le_bytes());
+    data[0x12..0x14].copy_from_slice(&1u16.to_le_bytes());
+    let first = (USA_OFFSET + 2 * USA_COUNT).div_ceil(8) * 8;
+    data[0x14..0x16].copy_from_slice(&(first as u16).to_le_bytes());
+    let flags = 1 | if directory { 2 } else { 0 };
+    data[0x16..0x18].copy_from_slice(&(flags as u16).to_le_bytes());
+    data[0x1c..0x20].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+    data[0x20..0x28].copy_from_slice(&base.to_le_bytes());
+
+    let mut pos = first;
+    for attr in attrs {
+        data[pos..pos + attr.len()].copy_from_slice(attr);
+        pos += attr.len();
+    }
+    data[pos..pos + 4].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+    data[0x18..0x1c].copy_from_slice(&(pos as u32 + 8).to_le_bytes());
+
+    data[USA_OFFSET..USA_OFFSET + 2].copy_from_slice(&USN.to_le_bytes());
+    for i in 1..USA_COUNT {
+        let end = i * 512 - 2;
+        let entry = USA_OFFSET + 2 * i;
+        let original = [data[end], data[end + 1]];
+        data[entry..entry + 2].copy_from_slice(&original);
+        data[end..end + 2].copy_from_slice(&USN.to_le_bytes());
+    }
+
+    data
+}
+
+/// Write an image to a temporary file, for `ImageScan`.
+pub(crate) fn write_image(image: &[u8]) -> io::Result<(tempdir::TempDir, std::path::PathBuf)> {
+    let dir = tempdir::TempDir::new("compactor-ntfs")?;
+    let path = dir.path().join("volume.img");
+    std::fs::write(&path, image)?;
+    Ok((dir, path))
+}
@@ -0,0 +1,607 @@
+//! Reading NTFS volumes from raw disk images, without Windows.
+//!
+//! Only what's needed to find files and how they're compressed is understood:
+//! the boot sector, MFT records and their attributes, and the data runs of
+//! non-resident attributes.  Directory indexes are never read, as each file's
+//! `$FILE_NAME` names its parent, which is enough to rebuild paths.
+//!
+//! `ImageScan` uses this to build a `FolderInfo` for a whole volume.
+
+#[cfg(test)]
+pub(crate) mod fixture;
+mod scan;
+
+pub use self::scan::ImageScan;
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The attribute types Compactor cares about.
+pub const ATTR_STANDARD_INFORMATION: u32 = 0x10;
+pub const ATTR_FILE_NAME: u32 = 0x30;
+pub const ATTR_DATA: u32 = 0x80;
+pub const ATTR_REPARSE_POINT: u32 = 0xc0;
+const ATTR_END: u32 = 0xffff_ffff;
+
+/// The MFT record of the root directory.
+pub const ROOT_RECORD: u64 = 5;
+
+/// Records before this are reserved for the filesystem's own use.
+pub const FIRST_USER_RECORD: u64 = 24;
+
+const ATTRIBUTE_FLAG_COMPRESSED: u16 = 0x0001;
+const ATTRIBUTE_FLAG_SPARSE: u16 = 0x8000;
+
+const RECORD_IN_USE: u16 = 0x0001;
+const RECORD_IS_DIRECTORY: u16 = 0x0002;
+
+// Update sequence arrays protect the end of every 512 bytes, whatever the
+// sector size.
+const FIXUP_STRIDE: usize = 512;
+
+fn invalid<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+// Read a little-endian integer of `len` bytes, checking it's in bounds.
+fn field(data: &[u8], pos: usize, len: usize) -> io::Result<u64> {
+    let bytes = pos
+        .checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or_else(|| invalid("truncated MFT record"))?;
+
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &byte| (acc << 8) | u64::from(byte)))
+}
+
+/// A run of clusters, or of sparse clusters reading as zeros if `lcn` is
+/// `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub lcn: Option<u64>,
+    pub length: u64,
+}
+
+/// Decode the mapping pairs of a non-resident attribute.
+pub fn parse_runs(data: &[u8]) -> io::Result<Vec<Run>> {
+    let mut runs = vec![];
+    let mut lcn: i64 = 0;
+    let mut pos = 0;
+
+    loop {
+        let header = *data
+            .get(pos)
+            .ok_or_else(|| invalid("unterminated data runs"))?;
+        if header == 0 {
+            return Ok(runs);
+        }
+
+        let length_size = usize::from(header & 0x0f);
+        let offset_size = usize::from(header >> 4);
+        if length_size == 0 || length_size > 8 || offset_size > 8 {
+            return Err(invalid("bad data run header"));
+        }
+        pos += 1;
+
+        let length = field(data, pos, length_size)?;
+        pos += length_size;
+
+        let run_lcn = match offset_size {
+            0 => None,
+            _ => {
+                let shift = 64 - 8 * offset_size as u32;
+                let delta = ((field(data, pos, offset_size)? << shift) as i64) >> shift;
+                lcn = lcn
+                    .checked_add(delta)
+                    .filter(|&lcn| lcn >= 0)
+                    .ok_or_else(|| invalid("data run before start of volume"))?;
+                Some(lcn as u64)
+            }
+        };
+        pos += offset_size;
+
+        runs.push(Run {
+            lcn: run_lcn,
+            length,
+        });
+    }
+}
+
+/// The contents of an attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Resident(Vec<u8>),
+    NonResident {
+        /// The first virtual cluster this part of the attribute maps, for
+        /// attributes split over several records.
+        start_vcn: u64,
+        runs: Vec<Run>,
+        allocated_size: u64,
+        real_size: u64,
+        /// The space actually allocated, for compressed and sparse
+        /// attributes.
+        compressed_size: Option<u64>,
+    },
+}
+
+/// An attribute of an MFT record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub kind: u32,
+    pub name: String,
+    pub flags: u16,
+    pub value: Value,
+}
+
+impl Attribute {
+    fn parse(data: &[u8]) -> io::Result<Self> {
+        let kind = field(data, 0x00, 4)? as u32;
+        let non_resident = field(data, 0x08, 1)? != 0;
+        let name_len = field(data, 0x09, 1)? as usize;
+        let name_offset = field(data, 0x0a, 2)? as usize;
+        let flags = field(data, 0x0c, 2)? as u16;
+
+        let name = (0..name_len)
+            .map(|i| field(data, name_offset + 2 * i, 2).map(|c| c as u16))
+            .collect::<io::Result<Vec<_>>>()?;
+        let name = String::from_utf16_lossy(&name);
+
+        let value = if non_resident {
+            let runs_offset = field(data, 0x20, 2)? as usize;
+            let compressed_size =
+                if flags & (ATTRIBUTE_FLAG_COMPRESSED | ATTRIBUTE_FLAG_SPARSE) != 0 {
+                    Some(field(data, 0x40, 8)?)
+                } else {
+                    None
+                };
+
+            Value::NonResident {
+                start_vcn: field(data, 0x10, 8)?,
+                runs: parse_runs(data.get(runs_offset..).unwrap_or_default())?,
+                allocated_size: field(data, 0x28, 8)?,
+                real_size: field(data, 0x30, 8)?,
+                compressed_size,
+            }
+        } else {
+            let len = field(data, 0x10, 4)? as usize;
+            let offset = field(data, 0x14, 2)? as usize;
+            let value = data
+                .get(offset..offset + len)
+                .ok_or_else(|| invalid("truncated resident attribute"))?;
+            Value::Resident(value.to_vec())
+        };
+
+        Ok(Self {
+            kind,
+            name,
+            flags,
+            value,
+        })
+    }
+
+    /// The size of the attribute's contents.
+    pub fn size(&self) -> u64 {
+        match &self.value {
+            Value::Resident(data) => data.len() as u64,
+            Value::NonResident { real_size, .. } => *real_size,
+        }
+    }
+
+    /// The space the attribute's clusters take up, not counting sparse ones.
+    pub fn allocated_size(&self) -> u64 {
+        match &self.value {
+            Value::Resident(_) => 0,
+            Value::NonResident {
+                allocated_size,
+                compressed_size,
+                ..
+            } => compressed_size.unwrap_or(*allocated_size),
+        }
+    }
+
+    /// Whether the attribute is sparse or uses legacy NTFS compression.
+    pub fn is_sparse_or_compressed(&self) -> bool {
+        self.flags & (ATTRIBUTE_FLAG_COMPRESSED | ATTRIBUTE_FLAG_SPARSE) != 0
+    }
+
+    /// Add the runs of a later part of a non-resident attribute that was
+    /// split over several records.
+    pub fn append(&mut self, later: Attribute) {
+        if let (
+            Value::NonResident { runs, .. },
+            Value::NonResident {
+                runs: later_runs, ..
+            },
+        ) = (&mut self.value, later.value)
+        {
+            runs.extend(later_runs);
+        }
+    }
+}
+
+/// A file record from the MFT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub number: u64,
+    pub directory: bool,
+    /// The record this extends with more attributes, for extension records.
+    pub base: Option<u64>,
+    pub attributes: Vec<Attribute>,
+}
+
+impl Record {
+    /// Parse a record, applying its fixups.  Unused records are `None`.
+    pub fn parse(number: u64, data: &mut [u8]) -> io::Result<Option<Self>> {
+        match &data[..4.min(data.len())] {
+            b"FILE" => (),
+            [0, 0, 0, 0] => return Ok(None),
+            _ => return Err(invalid("bad MFT record signature")),
+        }
+
+        let usa_offset = field(data, 0x04, 2)? as usize;
+        let usa_count = field(data, 0x06, 2)? as usize;
+        if usa_count == 0 || (usa_count - 1) * FIXUP_STRIDE > data.len() {
+            return Err(invalid("bad MFT record update sequence"));
+        }
+
+        let usn = field(data, usa_offset, 2)?;
+        for i in 1..usa_count {
+            let end = i * FIXUP_STRIDE - 2;
+            if field(data, end, 2)? != usn {
+                return Err(invalid("torn MFT record"));
+            }
+            let original = field(data, usa_offset + 2 * i, 2)? as u16;
+            data[end..end + 2].copy_from_slice(&original.to_le_bytes());
+        }
+
+        let flags = field(data, 0x16, 2)? as u16;
+        if flags & RECORD_IN_USE == 0 {
+            return Ok(None);
+        }
+
+        let used = (field(data, 0x18, 4)? as usize).min(data.len());
+        let base = field(data, 0x20, 8)? & 0xffff_ffff_ffff;
+        let mut pos = field(data, 0x14, 2)? as usize;
+        let mut attributes = vec![];
+
+        while pos + 4 <= used && field(data, pos, 4)? as u32 != ATTR_END {
+            let len = field(data, pos + 4, 4)? as usize;
+            let attr = data
+                .get(pos..pos + len)
+                .filter(|_| len >= 0x18)
+                .ok_or_else(|| invalid("bad attribute length"))?;
+            attributes.push(Attribute::parse(attr)?);
+            pos += len;
+        }
+
+        Ok(Some(Self {
+            number,
+            directory: flags & RECORD_IS_DIRECTORY != 0,
+            base: if base == 0 { None } else { Some(base) },
+            attributes,
+        }))
+    }
+
+    /// Find an attribute by type and name, if it's present in this record.
+    pub fn attribute(&self, kind: u32, name: &str) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.kind == kind && attr.name == name)
+    }
+}
+
+/// An NTFS volume in a disk image.
+#[derive(Debug)]
+pub struct Volume<R> {
+    image: R,
+    cluster_size: u64,
+    record_size: u64,
+    // Where the MFT lives
+    mft: Vec<Run>,
+    records: u64,
+}
+
+impl<R: Read + Seek> Volume<R> {
+    /// Read the boot sector and find the MFT.
+    pub fn open(mut image: R) -> io::Result<Self> {
+        let mut boot = [0; 512];
+        image.seek(SeekFrom::Start(0))?;
+        image.read_exact(&mut boot).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("not an NTFS volume"),
+            _ => e,
+        })?;
+
+        if &boot[3..11] != b"NTFS    " || boot[510..512] != [0x55, 0xaa] {
+            return Err(invalid("not an NTFS volume"));
+        }
+
+        let sector_size = field(&boot, 0x0b, 2)?;
+        let cluster_sectors = match boot[0x0d] {
+            n @ 1..=0x80 => u64::from(n),
+            n @ 0xe0..=0xff => 1 << (256 - u32::from(n)),
+            _ => 0,
+        };
+        let cluster_size = sector_size * cluster_sectors;
+        let record_size = match boot[0x40] as i8 {
+            n @ 1..=127 => cluster_size * n as u64,
+            n @ -31..=-1 => 1 << -n,
+            _ => 0,
+        };
+
+        if !sector_size.is_power_of_two()
+            || !(256..=4096).contains(&sector_size)
+            || !cluster_size.is_power_of_two()
+            || !(512..=65536).contains(&record_size)
+            || record_size % FIXUP_STRIDE as u64 != 0
+        {
+            return Err(invalid("bad NTFS geometry"));
+        }
+
+        let mut volume = Self {
+            image,
+            cluster_size,
+            record_size,
+            mft: vec![Run {
+                lcn: Some(field(&boot, 0x30, 8)?),
+                length: record_size.div_ceil(cluster_size),
+            }],
+            records: 1,
+        };
+
+        let mft = volume
+            .record(0)?
+            .ok_or_else(|| invalid("$MFT record not in use"))?;
+        match mft.attribute(ATTR_DATA, "") {
+            Some(Attribute {
+                value:
+                    Value::NonResident {
+                        runs, real_size, ..
+                    },
+                ..
+            }) => {
+                volume.mft = runs.clone();
+                volume.records = real_size / record_size;
+            }
+            _ => return Err(invalid("$MFT has no data")),
+        }
+
+        Ok(volume)
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// The number of records in the MFT, used or not.
+    pub fn record_count(&self) -> u64 {
+        self.records
+    }
+
+    /// Read a record from the MFT, or `None` if it's not in use.
+    pub fn record(&mut self, number: u64) -> io::Result<Option<Record>> {
+        if number >= self.records {
+            return Err(invalid("record past end of MFT"));
+        }
+
+        let mut data = vec![0; self.record_size as usize];
+        let mft = std::mem::take(&mut self.mft);
+        let read = self.read_runs(&mft, number * self.record_size, &mut data);
+        self.mft = mft;
+        read?;
+
+        Record::parse(number, &mut data)
+    }
+
+    /// Read the contents of an attribute.
+    pub fn open_value<'a>(&'a mut self, value: &'a Value) -> ValueReader<'a, R> {
+        let remaining = match value {
+            Value::Resident(data) => data.len() as u64,
+            Value::NonResident { real_size, .. } => *real_size,
+        };
+
+        ValueReader {
+            volume: self,
+            value,
+            pos: 0,
+            remaining,
+        }
+    }
+
+    // Fill `buf` from `offset` bytes into the clusters mapped by `runs`.
+    fn read_runs(&mut self, runs: &[Run], mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        // Lengths and LCNs come straight from the image, so mind overflow
+        let past_end = || invalid("data run past end of volume");
+
+        for run in runs {
+            let run_size = run
+                .length
+                .checked_mul(self.cluster_size)
+                .ok_or_else(past_end)?;
+            if offset >= run_size {
+                offset -= run_size;
+                continue;
+            }
+
+            let len = ((run_size - offset) as usize).min(buf.len());
+            match run.lcn {
+                Some(lcn) => {
+                    let start = lcn
+                        .checked_mul(self.cluster_size)
+                        .and_then(|start| start.checked_add(offset))
+                        .ok_or_else(past_end)?;
+                    self.image.seek(SeekFrom::Start(start))?;
+                    self.image.read_exact(&mut buf[..len])?;
+                }
+                None => buf[..len].iter_mut().for_each(|b| *b = 0),
+            }
+
+            buf = &mut buf[len..];
+            offset = 0;
+            if buf.is_empty() {
+                return Ok(());
+            }
+        }
+
+        Err(invalid("read past end of data runs"))
+    }
+}
+
+/// Reads an attribute's contents from a `Volume`.
+#[derive(Debug)]
+pub struct ValueReader<'a, R> {
+    volume: &'a mut Volume<R>,
+    value: &'a Value,
+    pos: u64,
+    remaining: u64,
+}
+
+impl<R: Read + Seek> Read for ValueReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Stick to a cluster at a time, rather than working out where runs end
+        let len = (buf.len() as u64)
+            .min(self.remaining)
+            .min(self.volume.cluster_size) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        match self.value {
+            Value::Resident(data) => {
+                let start = self.pos as usize;
+                buf[..len].copy_from_slice(&data[start..start + len]);
+            }
+            Value::NonResident {
+                start_vcn, runs, ..
+            } => {
+                let start = start_vcn
+                    .checked_mul(self.volume.cluster_size)
+                    .ok_or_else(|| invalid("data run past end of volume"))?;
+                let offset = self
+                    .pos
+                    .checked_sub(start)
+                    .ok_or_else(|| invalid("attribute's first runs are missing"))?;
+                self.volume.read_runs(runs, offset, &mut buf[..len])?;
+            }
+        }
+
+        self.pos += len as u64;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+#[test]
+fn it_parses_runs() {
+    use self::fixture::{encode_runs, ImageBuilder};
+    use std::io::Cursor;
+
+    let runs = vec![
+        Run {
+            lcn: Some(100),
+            length: 8,
+        },
+        Run {
+            lcn: None,
+            length: 300,
+        },
+        Run {
+            lcn: Some(20),
+            length: 1,
+        },
+        Run {
+            lcn: Some(0x1_0000_0000),
+            length: 0x12345,
+        },
+    ];
+    let encoded = encode_runs(&runs);
+    assert_eq!(&encoded[..3], &[0x11, 8, 100]);
+    assert_eq!(&encoded[3..6], &[0x02, 0x2c, 0x01]);
+    assert_eq!(&encoded[6..9], &[0x11, 1, 0xb0]);
+    assert_eq!(parse_runs(&encoded).unwrap(), runs);
+
+    assert!(parse_runs(&[]).is_err());
+    assert!(parse_runs(&[0x11, 8]).is_err());
+    assert!(parse_runs(&[0x10, 8, 0]).is_err());
+    assert!(parse_runs(&[0x11, 8, 0xff, 0]).is_err());
+
+    // Runs far beyond any real volume must fail rather than overflow
+    let mut volume = Volume::open(Cursor::new(ImageBuilder::default().build())).unwrap();
+    let oversized = [
+        Run {
+            lcn: Some(1),
+            length: 0x40 << 56,
+        },
+        Run {
+            lcn: Some(1 << 62),
+            length: 1,
+        },
+    ];
+    for run in &oversized {
+        let runs = parse_runs(&encode_runs(&[*run])).unwrap();
+        assert_eq!(runs, [*run]);
+        let mut buf = [0; 512];
+        assert!(volume.read_runs(&runs, 0, &mut buf).is_err());
+        assert!(volume.read_runs(&runs, 4096, &mut buf).is_err());
+    }
+
+    let value = Value::NonResident {
+        start_vcn: 1 << 62,
+        runs: vec![],
+        allocated_size: 4096,
+        real_size: 4096,
+        compressed_size: None,
+    };
+    let mut buf = [0; 512];
+    assert!(volume.open_value(&value).read(&mut buf).is_err());
+}
+
+#[test]
+fn it_reads_volumes() {
+    use self::fixture::{ImageBuilder, RECORD_SIZE};
+    use std::io::Cursor;
+
+    let data = b"It was the best of times, it was the worst of times. ".repeat(200);
+    let mut builder = ImageBuilder::default();
+    let file = builder.file(ROOT_RECORD, "story.txt", &data);
+    let image = builder.build();
+
+    let mut volume = Volume::open(Cursor::new(&image)).unwrap();
+    assert_eq!(volume.cluster_size(), 4096);
+    assert_eq!(volume.record_count(), file + 1);
+    assert!(volume.record(FIRST_USER_RECORD - 1).unwrap().is_none());
+    assert!(volume.record(file + 1).is_err());
+
+    let root = volume.record(ROOT_RECORD).unwrap().unwrap();
+    assert!(root.directory);
+
+    let record = volume.record(file).unwrap().unwrap();
+    assert!(!record.directory);
+    assert_eq!(record.base, None);
+    let attr = record.attribute(ATTR_DATA, "").unwrap().clone();
+    assert_eq!(attr.size(), data.len() as u64);
+    assert_eq!(attr.allocated_size(), 12288);
+
+    let mut contents = vec![];
+    volume
+        .open_value(&attr.value)
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert!(contents == data);
+
+    // A torn write leaves the end of a sector out of step with the rest
+    let mut torn = image.clone();
+    let pos = 4096 + file as usize * RECORD_SIZE + 510;
+    torn[pos] ^= 0xff;
+    let mut volume = Volume::open(Cursor::new(&torn)).unwrap();
+    assert!(volume.record(file).is_err());
+
+    let mut bad = image.clone();
+    bad[3] = b'X';
+    assert!(Volume::open(Cursor::new(&bad)).is_err());
+
+    // Records are at most 64 KiB, however big the boot sector says
+    let mut bad = image;
+    bad[0x40] = -20i8 as u8;
+    assert!(Volume::open(Cursor::new(&bad)).is_err());
+}
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use globset::GlobSet;
+
+use super::{
+    Attribute, Volume, ATTR_DATA, ATTR_FILE_NAME, ATTR_REPARSE_POINT, ATTR_STANDARD_INFORMATION,
+    FIRST_USER_RECORD, ROOT_RECORD,
+};
+use crate::background::{Background, ControlToken};
+use crate::event::ErrorInfo;
+use crate::folder::SkipReason;
+use crate::folder::{FileInfo, FileKind, FolderInfo, FolderSummary, ScanError, ScanOperation};
+use crate::platform::{
+    FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SPARSE_FILE,
+    FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_TEMPORARY,
+};
+use crate::wof::{self, Provider, WOF_STREAM};
+
+// $FILE_NAME namespaces
+const NAMESPACE_DOS: u8 = 2;
+
+// Guards against loops in corrupt directory structures
+const MAX_DEPTH: usize = 1024;
+
+/// A `Background` job that reads an NTFS disk image to build a `FolderInfo`,
+/// without mounting it.
+///
+/// Files are grouped as a `FolderScan` would group them on a live volume, with
+/// WOF-compressed files' algorithms from their reparse points and paths
+/// relative to the root of the volume.  The incompressible-files database
+/// isn't consulted, as it refers to files on this machine.  System metadata
+/// files are left out.
+///
+/// With `with_verification`, each WOF-compressed file is also decompressed, and
+/// any that fail are reported as errors.
+///
+/// Status updates are the name of the last file found and a running summary.
+/// If cancelled, the partial results are returned as an `Err`.
+#[derive(Debug)]
+pub struct ImageScan {
+    path: PathBuf,
+    excludes: GlobSet,
+    verify: bool,
+}
+
+impl ImageScan {
+    pub fn new<P: AsRef<Path>>(path: P, excludes: GlobSet) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            excludes,
+            verify: false,
+        }
+    }
+
+    /// Decompress every WOF-compressed file to check it's intact.
+    pub fn with_verification(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+}
+
+// A file or directory, gathered from its base record and any extensions.
+#[derive(Debug, Default)]
+struct Entry {
+    // Whether the base record has been seen
+    present: bool,
+    directory: bool,
+    // The parent record and name
+    name: Option<(u64, String, u8)>,
+    attributes: u32,
+    data: Vec<Attribute>,
+    wof: Vec<Attribute>,
+    reparse: Option<Attribute>,
+}
+
+impl Entry {
+    fn add(&mut self, attr: Attribute) {
+        match (attr.kind, attr.name.as_str()) {
+            (ATTR_STANDARD_INFORMATION, _) => {
+                if let super::Value::Resident(value) = &attr.value {
+                    self.attributes = super::field(value, 0x20, 4).unwrap_or(0) as u32;
+                }
+            }
+            (ATTR_FILE_NAME, _) => {
+                if let super::Value::Resident(value) = &attr.value {
+                    if let Some(name) = parse_file_name(value) {
+                        // Prefer any name over a DOS short name
+                        if self.name.as_ref().is_none_or(|n| n.2 == NAMESPACE_DOS) {
+                            self.name = Some(name);
+                        }
+                    }
+                }
+            }
+            (ATTR_DATA, "") => self.data.push(attr),
+            (ATTR_DATA, WOF_STREAM) => self.wof.push(attr),
+            (ATTR_REPARSE_POINT, _) => self.reparse = Some(attr),
+            _ => (),
+        }
+    }
+}
+
+fn parse_file_name(value: &[u8]) -> Option<(u64, String, u8)> {
+    let parent = super::field(value, 0, 8).ok()? & 0xffff_ffff_ffff;
+    let len = super::field(value, 0x40, 1).ok()? as usize;
+    let namespace = super::field(value, 0x41, 1).ok()? as u8;
+    let name = (0..len)
+        .map(|i| super::field(value, 0x42 + 2 * i, 2).map(|c| c as u16))
+        .collect::<io::Result<Vec<_>>>()
+        .ok()?;
+
+    Some((parent, String::from_utf16_lossy(&name), namespace))
+}
+
+// Put the parts of an attribute split over several records back together.
+fn join(mut parts: Vec<Attribute>) -> Option<Attribute> {
+    parts.sort_by_key(|attr| match attr.value {
+        super::Value::NonResident { start_vcn, .. } => start_vcn,
+        super::Value::Resident(_) => 0,
+    });
+
+    let mut parts = parts.into_iter();
+    let mut attr = parts.next()?;
+    for part in parts {
+        attr.append(part);
+    }
+    Some(attr)
+}
+
+struct Scanner<'a> {
+    volume: Volume<BufReader<File>>,
+    excludes: &'a GlobSet,
+    verify: bool,
+    entries: HashMap<u64, Entry>,
+    // Directory paths, or None for ones that are excluded or unreachable
+    dirs: HashMap<u64, Option<PathBuf>>,
+}
+
+impl Scanner<'_> {
+    fn dir_path(&mut self, record: u64) -> Option<PathBuf> {
+        let mut chain = vec![];
+        let mut current = record;
+
+        let mut path = loop {
+            if let Some(path) = self.dirs.get(&current) {
+                break path.clone();
+            }
+
+            let entry = self.entries.get(&current);
+            let parent = match entry {
+                _ if current == ROOT_RECORD => break Some(PathBuf::new()),
+                _ if current < FIRST_USER_RECORD || chain.len() > MAX_DEPTH => break None,
+                Some(Entry {
+                    present: true,
+                    directory: true,
+                    name: Some((parent, _, _)),
+                    ..
+                }) => *parent,
+                _ => break None,
+            };
+
+            chain.push(current);
+            current = parent;
+        };
+
+        self.dirs.insert(current, path.clone());
+        for &dir in chain.iter().rev() {
+            path = path.and_then(|parent| {
+                let name = &self.entries[&dir].name.as_ref()?.1;
+                Some(parent.join(name)).filter(|path| !self.excludes.is_match(path))
+            });
+            self.dirs.insert(dir, path.clone());
+        }
+
+        path
+    }
+
+    fn error(&self, path: PathBuf, operation: ScanOperation, e: &io::Error) -> ScanError {
+        ScanError {
+            path,
+            operation,
+            error: ErrorInfo::from(e),
+        }
+    }
+
+    // Work out which group a file belongs in, like `Walker::file`.
+    fn file(&mut self, path: PathBuf, entry: Entry) -> Result<(FileKind, FileInfo), ScanError> {
+        let data = join(entry.data);
+        let size = data.as_ref().map_or(0, |attr| attr.size());
+        let mut physical = match &data {
+            Some(attr) if attr.is_sparse_or_compressed() => attr.allocated_size(),
+            _ => size,
+        };
+
+        let provider = match &entry.reparse {
+            Some(attr) => {
+                let mut reparse = vec![];
+                self.volume
+                    .open_value(&attr.value)
+                    .read_to_end(&mut reparse)
+                    .map_err(|e| self.error(path.clone(), ScanOperation::Metadata, &e))?;
+                // Other kinds of reparse point are fine, but not our concern
+                Provider::from_reparse_point(&reparse).ok()
+            }
+            None => None,
+        };
+
+        let mut compression = None;
+        if let Some(Provider::File(c)) = provider {
+            let stream = join(entry.wof);
+            physical = stream.as_ref().map_or(0, |attr| match attr.value {
+                super::Value::Resident(_) => attr.size(),
+                super::Value::NonResident { .. } => attr.allocated_size(),
+            });
+            compression = Some(c);
+
+            if self.verify {
+                let stream = stream.ok_or_else(|| {
+                    let e = io::Error::new(io::ErrorKind::NotFound, "no WofCompressedData stream");
+                    self.error(path.clone(), ScanOperation::Verify, &e)
+                })?;
+                let reader = self.volume.open_value(&stream.value);
+                wof::decompress(reader, c, size, io::sink())
+                    .map_err(|e| self.error(path.clone(), ScanOperation::Verify, &e))?;
+            }
+        }
+
+        let mut fi = FileInfo {
+            path,
+            logical_size: size.max(physical),
+            physical_size: physical,
+            skip_reason: None,
+            projected_size: None,
+            compression,
+        };
+
+        let attributes = entry.attributes;
+
+        match provider {
+            Some(Provider::Wim { .. }) => return Ok((FileKind::Wim, fi)),
+            Some(Provider::File(_)) => return Ok((FileKind::Compressed, fi)),
+            None if fi.physical_size >= fi.logical_size => (),
+            None if attributes & FILE_ATTRIBUTE_SPARSE_FILE != 0 => {
+                return Ok((FileKind::Sparse, fi))
+            }
+            None if attributes & FILE_ATTRIBUTE_COMPRESSED != 0 => (),
+            None => return Ok((FileKind::Compressed, fi)),
+        }
+
+        fi.skip_reason = if fi.logical_size <= 4096 {
+            Some(SkipReason::Small)
+        } else if attributes & FILE_ATTRIBUTE_READONLY != 0 {
+            Some(SkipReason::ReadOnly)
+        } else if attributes & FILE_ATTRIBUTE_SYSTEM != 0 {
+            Some(SkipReason::System)
+        } else if attributes & FILE_ATTRIBUTE_TEMPORARY != 0 {
+            Some(SkipReason::Temporary)
+        } else if self.excludes.is_match(&fi.path) {
+            Some(SkipReason::Excluded)
+        } else {
+            None
+        };
+
+        if fi.skip_reason.is_some() {
+            return Ok((FileKind::Skipped, fi));
+        }
+
+        if attributes & FILE_ATTRIBUTE_COMPRESSED != 0 {
+            return Ok((FileKind::NtfsCompressed, fi));
+        }
+
+        Ok((FileKind::Compressible, fi))
+    }
+}
+
+impl Background for ImageScan {
+    type Output = Result<FolderInfo, FolderInfo>;
+    type Status = (PathBuf, FolderSummary);
+
+    fn run(self, control: &ControlToken<Self::Status>) -> Self::Output {
+        let ImageScan {
+            path,
+            excludes,
+            verify,
+        } = self;
+        let mut ds = FolderInfo::new(&path);
+
+        let volume = match File::open(&path).and_then(|file| Volume::open(BufReader::new(file))) {
+            Ok(volume) => volume,
+            Err(e) => {
+                ds.errors.push(ScanError {
+                    path: PathBuf::new(),
+                    operation: ScanOperation::Metadata,
+                    error: ErrorInfo::from(&e),
+                });
+                return Ok(ds);
+            }
+        };
+
+        let mut scanner = Scanner {
+            volume,
+            excludes: &excludes,
+            verify,
+            entries: HashMap::new(),
+            dirs: HashMap::new(),
+        };
+        let mut last_status = Instant::now();
+
+        for number in 0..scanner.volume.record_count() {
+            if number % 256 == 0 && control.is_cancelled_with_pause() {
+                return Err(ds);
+            }
+
+            let record = match scanner.volume.record(number) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(e) => {
+                    let e = io::Error::new(e.kind(), format!("record {}: {}", number, e));
+                    ds.errors.push(scanner.error(
+                        PathBuf::from("$MFT"),
+                        ScanOperation::ReadRecord,
+                        &e,
+                    ));
+                    continue;
+                }
+            };
+
+            let entry = scanner
+                .entries
+                .entry(record.base.unwrap_or(number))
+                .or_default();
+            if record.base.is_none() {
+                entry.present = true;
+                entry.directory = record.directory;
+            }
+            for attr in record.attributes {
+                entry.add(attr);
+            }
+
+            if last_status.elapsed() >= Duration::from_millis(50) {
+                last_status = Instant::now();
+                if let Some((_, name, _)) = &entry.name {
+                    control.set_status((PathBuf::from(name), ds.summary()));
+                }
+            }
+        }
+
+        let mut files = scanner
+            .entries
+            .iter()
+            .filter(|(&number, entry)| {
+                number >= FIRST_USER_RECORD && entry.present && !entry.directory
+            })
+            .filter_map(|(&number, entry)| entry.name.as_ref().map(|n| (number, n.0, n.1.clone())))
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+
+        for (i, (number, parent, name)) in files.into_iter().enumerate() {
+            if i % 256 == 0 && control.is_cancelled_with_pause() {
+                ds.sort();
+                return Err(ds);
+            }
+
+            let path = match scanner.dir_path(parent) {
+                Some(dir) => dir.join(name),
+                None => continue,
+            };
+            let entry = scanner.entries.remove(&number).expect("entry");
+
+            match scanner.file(path, entry) {
+                Ok((kind, fi)) => {
+                    if last_status.elapsed() >= Duration::from_millis(50) {
+                        last_status = Instant::now();
+                        control.set_status((fi.path.clone(), ds.summary()));
+                    }
+                    ds.push(kind, fi);
+                }
+                Err(e) => ds.errors.push(e),
+            }
+        }
+
+        ds.sort();
+        Ok(ds)
+    }
+}
+
+#[cfg(test)]
+use super::fixture::{write_image, ImageBuilder};
+
+#[cfg(test)]
+fn scan_image(builder: &ImageBuilder, scan: impl FnOnce(&Path) -> ImageScan) -> FolderInfo {
+    use crate::background::BackgroundHandle;
+
+    let (_dir, path) = write_image(&builder.build()).unwrap();
+    BackgroundHandle::spawn(scan(&path)).wait().expect("scan")
+}
+
+#[cfg(test)]
+fn paths(group: &crate::folder::GroupInfo) -> Vec<String> {
+    group
+        .files
+        .iter()
+        .map(|fi| fi.path.to_string_lossy().replace('\\', "/"))
+        .collect()
+}
+
+#[test]
+fn it_scans_images() {
+    use crate::compact::Compression;
+    use crate::platform::FILE_ATTRIBUTE_SYSTEM;
+    use globset::{Glob, GlobSetBuilder};
+
+    let text = b"It was the best of times, it was the worst of times. ".repeat(1000);
+    let mut b = ImageBuilder::default();
+    let games = b.dir(ROOT_RECORD, "Games");
+    let game = b.dir(games, "A Rather Long Game Name");
+    let logs = b.dir(game, "logs");
+    b.file(game, "game.exe", &text);
+    b.file(game, "readme.txt", b"Tiny");
+    b.file(logs, "today.log", &text);
+    for (i, &compression) in Compression::ALL.iter().enumerate() {
+        b.wof_file(game, &format!("data{}.pak", i), &text, compression);
+    }
+    let ext = b.wof_file(ROOT_RECORD, "extended.dat", &text, Compression::Lzx);
+    b.extension(ext);
+    b.sparse_file(ROOT_RECORD, "disk.vhdx", 1 << 30);
+    let wim = b.sparse_file(ROOT_RECORD, "notepad.exe", 200_000);
+    let provider = Provider::Wim {
+        data_source_id: 1,
+        resource_hash: [7; 20],
+    };
+    b.reparse(wim, provider.to_reparse_point());
+    let system = b.file(ROOT_RECORD, "pagefile.sys", &text);
+    b.attributes(system, FILE_ATTRIBUTE_SYSTEM);
+    b.file(11, "$ObjId", &text);
+
+    let mut excludes = GlobSetBuilder::new();
+    excludes.add(Glob::new("**/logs").unwrap());
+    let excludes = excludes.build().unwrap();
+
+    let info = scan_image(&b, |path| ImageScan::new(path, excludes));
+    assert!(info.errors.is_empty(), "{:?}", info.errors);
+
+    assert_eq!(
+        paths(&info.compressible),
+        vec!["Games/A Rather Long Game Name/game.exe"]
+    );
+    assert_eq!(
+        paths(&info.compressed),
+        vec![
+            "Games/A Rather Long Game Name/data0.pak",
+            "Games/A Rather Long Game Name/data1.pak",
+            "Games/A Rather Long Game Name/data2.pak",
+            "Games/A Rather Long Game Name/data3.pak",
+            "extended.dat",
+        ]
+    );
+    assert_eq!(
+        paths(&info.skipped),
+        vec!["Games/A Rather Long Game Name/readme.txt", "pagefile.sys"]
+    );
+    assert_eq!(info.skip_reasons[&SkipReason::Small].count, 1);
+    assert_eq!(info.skip_reasons[&SkipReason::System].count, 1);
+    assert_eq!(paths(&info.wim), vec!["notepad.exe"]);
+    assert_eq!(info.wim.physical_size, 0);
+
+    let pak = &info.compressed.files[3];
+    assert_eq!(pak.compression, Some(Compression::Lzx));
+    assert_eq!(pak.logical_size, text.len() as u64);
+    // Small enough to be resident in the file's record
+    let stream = wof::compress(&text, Compression::Lzx).len() as u64;
+    assert_eq!(pak.physical_size, stream);
+
+    assert_eq!(paths(&info.sparse), vec!["disk.vhdx"]);
+    let sparse = &info.sparse.files[0];
+    assert_eq!((sparse.logical_size, sparse.physical_size), (1 << 30, 0));
+    assert_eq!(sparse.compression, None);
+
+    let summary = info.summary();
+    assert_eq!(summary.algorithms.len(), 4);
+    assert_eq!(summary.algorithms[&Compression::Lzx].count, 2);
+    assert_eq!(summary.algorithms[&Compression::Xpress4k].count, 1);
+    assert_eq!(
+        summary.compressed.count,
+        summary.algorithms.values().map(|g| g.count).sum::<usize>()
+    );
+}
+
+#[test]
+fn it_verifies_wof_streams() {
+    use crate::compact::Compression;
+
+    let text = b"It was the best of times, it was the worst of times. ".repeat(1000);
+    let mut b = ImageBuilder::default();
+    b.wof_file(ROOT_RECORD, "good.dat", &text, Compression::Xpress8k);
+    let bad = b.wof_file(ROOT_RECORD, "bad.dat", &text, Compression::Xpress8k);
+    let mut stream = wof::compress(&text, Compression::Xpress8k);
+    let len = stream.len();
+    stream.truncate(len - 100);
+    b.wof_stream(bad, stream);
+    let odd = b.file(ROOT_RECORD, "odd.dat", &text);
+    b.reparse(odd, vec![0x0c, 0, 0, 0xa0, 0, 0, 0, 0]);
+
+    // Unverified, the damage goes unnoticed
+    let info = scan_image(&b, |path| ImageScan::new(path, GlobSet::empty()));
+    assert!(info.errors.is_empty());
+    assert_eq!(info.compressed.files.len(), 2);
+    assert_eq!(paths(&info.compressible), vec!["odd.dat"]);
+
+    let info = scan_image(&b, |path| {
+        ImageScan::new(path, GlobSet::empty()).with_verification()
+    });
+    assert_eq!(paths(&info.compressed), vec!["good.dat"]);
+    assert_eq!(info.errors.len(), 1);
+    assert_eq!(info.errors[0].path, PathBuf::from("bad.dat"));
+    assert_eq!(info.errors[0].operation, ScanOperation::Verify);
+}
+
+#[test]
+fn it_reports_unreadable_images() {
+    use crate::background::BackgroundHandle;
+
+    let (_dir, path) = write_image(&[0; 4096]).unwrap();
+    let scan = ImageScan::new(&path, GlobSet::empty());
+    let info = BackgroundHandle::spawn(scan).wait().expect("scan");
+    assert_eq!(info.errors.len(), 1);
+    assert_eq!(info.errors[0].operation, ScanOperation::Metadata);
+    assert_eq!(info.logical_size, 0);
+
+    // A damaged record is skipped, but the rest is still read
+    let mut b = ImageBuilder::default();
+    b.file(ROOT_RECORD, "first.txt", &[1; 8192]);
+    let second = b.file(ROOT_RECORD, "second.txt", &[2; 8192]);
+    let mut image = b.build();
+    image[4096 + second as usize * super::fixture::RECORD_SIZE] = b'X';
+    let (_dir, path) = write_image(&image).unwrap();
+
+    let scan = ImageScan::new(&path, GlobSet::empty());
+    let info = BackgroundHandle::spawn(scan).wait().expect("scan");
+    assert_eq!(paths(&info.compressible), vec!["first.txt"]);
+    assert_eq!(info.errors.len(), 1);
+    assert_eq!(info.errors[0].operation, ScanOperation::ReadRecord);
+}
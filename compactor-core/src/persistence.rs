@@ -0,0 +1,115 @@
+//! Process-wide settings and the incompressible-files database.
+
+use directories::ProjectDirs;
+use hashfilter::{Attempt, HashFilter, Record};
+use lazy_static::lazy_static;
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::compact::Compression;
+use crate::config::ConfigFile;
+use crate::folder::FolderInfo;
+
+// Dead records tolerated before `init` compacts the database.
+const COMPACT_THRESHOLD: u64 = 1024;
+
+lazy_static! {
+    static ref PATHDB: RwLock<HashFilter> = RwLock::new(HashFilter::default());
+    static ref CONFIG: RwLock<ConfigFile> = RwLock::new(ConfigFile::default());
+}
+
+/// Point the config and database at their usual locations.
+///
+/// Until this is called both are memory-only.
+pub fn init() {
+    if let Some(dirs) = ProjectDirs::from("", "Freaky", "Compactor") {
+        let mut pathdb = pathdb().write().unwrap();
+        pathdb.set_backing(dirs.cache_dir().join("incompressible.db"));
+        // Entries from before records were fingerprinted
+        let _ = pathdb.migrate(dirs.cache_dir().join("incompressible.dat"));
+        // Drop removed and replaced records once they outnumber live ones
+        let _ = pathdb.load();
+        if pathdb.dead_records() > COMPACT_THRESHOLD && pathdb.dead_records() > pathdb.len() as u64
+        {
+            let _ = pathdb.compact();
+        }
+        *config().write().unwrap() = ConfigFile::new(dirs.config_dir().join("config.json"));
+    }
+}
+
+/// Forget any files in `info` recorded as incompressible, so the next scan
+/// considers them again.
+///
+/// Returns how many were forgotten.
+pub fn forget_incompressible(info: &FolderInfo) -> io::Result<usize> {
+    let folder = resolve_folder(&info.path);
+    let mut pathdb = pathdb().write().unwrap();
+    let _ = pathdb.load();
+
+    let forgotten = info
+        .files()
+        .filter(|fi| pathdb.remove_file(record_path(&folder, &fi.path)))
+        .count();
+
+    if forgotten > 0 {
+        pathdb.save()?;
+        pathdb.compact()?;
+    }
+
+    Ok(forgotten)
+}
+
+/// `folder` with any junctions or symlinks resolved, so files reached through
+/// them share records in the incompressible-files database.
+///
+/// Resolving once per folder avoids doing it for every file.
+pub fn resolve_folder(folder: &Path) -> PathBuf {
+    std::fs::canonicalize(folder).unwrap_or_else(|_| folder.to_path_buf())
+}
+
+/// The path to record a file under, from its folder as given by
+/// `resolve_folder` and its path relative to that.
+pub fn record_path(folder: &Path, relative: &Path) -> PathBuf {
+    // Scanning a single file leaves it with an empty relative path
+    if relative.as_os_str().is_empty() {
+        folder.to_path_buf()
+    } else {
+        folder.join(relative)
+    }
+}
+
+/// What to record for a file that isn't worth compressing with `compression`,
+/// with `ratio` its estimated compressed size as a fraction of the original.
+pub fn incompressible_with(compression: Compression, ratio: f32) -> Attempt {
+    Attempt {
+        method: u32::from(compression),
+        ratio,
+    }
+}
+
+/// Whether a file with `record` should still be skipped when compressing with
+/// `compression`.
+///
+/// Only algorithms stronger than the one that failed get another try.  Records
+/// that don't say which failed are always skipped, as is everything when
+/// `compression` isn't known.
+pub fn skips_incompressible(record: &Record, compression: Option<Compression>) -> bool {
+    let tried = record
+        .attempt
+        .and_then(|attempt| Compression::try_from(attempt.method).ok());
+
+    match (tried, compression) {
+        (Some(tried), Some(compression)) => compression <= tried,
+        _ => true,
+    }
+}
+
+pub fn config() -> &'static RwLock<ConfigFile> {
+    &CONFIG
+}
+
+pub fn pathdb() -> &'static RwLock<HashFilter> {
+    &PATHDB
+}
@@ -0,0 +1,31 @@
+//! Platform-specific glue.
+//!
+//! Windows gets the real thing - WOF compression and native file attributes -
+//! while everything else gets portable stand-ins, so the rest of the crate can
+//! be built and tested anywhere.
+//!
+//! Each platform provides:
+//!
+//! * `NativeBackend` - the default `CompressionBackend`.
+//! * `file_attributes()` - Windows-style `FILE_ATTRIBUTE_*` flags for a file.
+//! * `open_for_compression()` - open a file with the access compaction needs.
+//! * `incurs_seek_penalty()` - whether a drive is slow to seek.
+//! * `cluster_size()` - the allocation unit of a volume.
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[cfg(not(windows))]
+mod portable;
+#[cfg(not(windows))]
+pub use self::portable::*;
+
+// These match the values in winnt.h, and are defined here so non-Windows
+// platforms can speak the same language.
+pub const FILE_ATTRIBUTE_READONLY: u32 = 0x0000_0001;
+pub const FILE_ATTRIBUTE_SYSTEM: u32 = 0x0000_0004;
+pub const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x0000_0100;
+pub const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x0000_0200;
+pub const FILE_ATTRIBUTE_COMPRESSED: u32 = 0x0000_0800;
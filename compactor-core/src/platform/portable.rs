@@ -0,0 +1,88 @@
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use filesize::PathExt;
+
+use crate::compact::{Compression, CompressionBackend};
+use crate::platform::{FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SPARSE_FILE};
+use crate::wof::Provider;
+
+pub type NativeBackend = UnsupportedBackend;
+
+/// Approximate Windows file attributes from portable metadata.
+///
+/// On Unix, files with fewer blocks allocated than their length are taken to
+/// be sparse.
+pub fn file_attributes(metadata: &Metadata) -> u32 {
+    let mut attributes = 0;
+
+    if metadata.permissions().readonly() {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if metadata.blocks() * 512 < metadata.len() {
+            attributes |= FILE_ATTRIBUTE_SPARSE_FILE;
+        }
+    }
+
+    attributes
+}
+
+pub fn open_for_compression(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).open(path)
+}
+
+/// Whether the drive holding `path` is slow to seek, which is never known here.
+pub fn incurs_seek_penalty(_path: &Path) -> io::Result<bool> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "drive type detection is not supported on this platform",
+    ))
+}
+
+/// The cluster size of the volume holding `path`, which is never known here.
+pub fn cluster_size(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cluster size detection is not supported on this platform",
+    ))
+}
+
+/// A backend for systems without WOF, which can measure but never compress.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnsupportedBackend;
+
+fn unsupported() -> io::Error {
+    io::Error::other("compression is not supported on this platform")
+}
+
+impl CompressionBackend for UnsupportedBackend {
+    fn supports_compression(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn detect_provider(&self, _path: &Path) -> io::Result<Option<Provider>> {
+        Ok(None)
+    }
+
+    fn compress(&self, _path: &Path, _file: &File, _compression: Compression) -> io::Result<bool> {
+        Err(unsupported())
+    }
+
+    fn uncompress(&self, _path: &Path, _file: &File) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn clear_ntfs_compression(&self, _path: &Path, _file: &File) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn size_on_disk(&self, path: &Path) -> io::Result<u64> {
+        path.size_on_disk()
+    }
+}
@@ -0,0 +1,575 @@
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use std::convert::TryFrom;
+use std::ffi::{CString, OsStr};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::fs::{MetadataExt, OpenOptionsExt};
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+
+use filesize::PathExt;
+use winapi::shared::minwindef::{BOOL, BYTE, DWORD, MAX_PATH, PBOOL, PULONG, ULONG, USHORT};
+use winapi::shared::ntdef::{LONGLONG, PVOID};
+use winapi::shared::winerror::{HRESULT_CODE, SUCCEEDED};
+use winapi::um::fileapi::{
+    GetDiskFreeSpaceW, GetVolumeNameForVolumeMountPointW, GetVolumePathNameW,
+};
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::winioctl::{
+    FSCTL_DELETE_EXTERNAL_BACKING, FSCTL_SET_COMPRESSION, FSCTL_SET_EXTERNAL_BACKING,
+};
+use winapi::um::winnt::{
+    FILE_READ_DATA, FILE_WRITE_ATTRIBUTES, FILE_WRITE_DATA, HANDLE, HRESULT, LPCWSTR,
+};
+use winapi::um::winver::{GetFileVersionInfoA, GetFileVersionInfoSizeA, VerQueryValueA};
+use winapi::STRUCT;
+
+use crate::compact::{Compression, CompressionBackend};
+use crate::wof::Provider;
+
+pub type NativeBackend = WofBackend;
+
+pub fn file_attributes(metadata: &Metadata) -> u32 {
+    metadata.file_attributes()
+}
+
+pub fn open_for_compression(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES | FILE_READ_DATA)
+        .open(path)
+}
+
+STRUCT! {
+    struct _WOF_FILE_COMPRESSION_INFO_V1 {
+        Algorithm: ULONG,
+        Flags: ULONG,
+    }
+}
+
+STRUCT! {
+    struct _WOF_EXTERNAL_INFO {
+        Version: ULONG,
+        Provider: ULONG,
+    }
+}
+
+// Not WIM_PROVIDER_EXTERNAL_INFO, which is what's passed to set a backing,
+// and starts with a version.  DataSourceId is a LARGE_INTEGER.
+STRUCT! {
+    struct _WIM_EXTERNAL_FILE_INFO {
+        DataSourceId: LONGLONG,
+        ResourceHash: [BYTE; 20],
+        Flags: ULONG,
+    }
+}
+
+// What WofIsExternalFile() fills in, depending on the provider.
+#[repr(C)]
+#[derive(Clone, Copy)]
+union _EXTERNAL_FILE_INFO {
+    file: _WOF_FILE_COMPRESSION_INFO_V1,
+    wim: _WIM_EXTERNAL_FILE_INFO,
+}
+
+STRUCT! {
+    struct _FILE_PROVIDER_EXTERNAL_INFO_V1 {
+        Version: ULONG,
+        Algorithm: ULONG,
+        Flags: ULONG,
+    }
+}
+
+STRUCT! {
+    struct STORAGE_PROPERTY_QUERY {
+        PropertyId: DWORD,
+        QueryType: DWORD,
+        AdditionalParameters: [BYTE; 1],
+    }
+}
+
+STRUCT! {
+    struct DEVICE_SEEK_PENALTY_DESCRIPTOR {
+        Version: DWORD,
+        Size: DWORD,
+        IncursSeekPenalty: BYTE,
+    }
+}
+
+STRUCT! {
+    struct VS_FIXEDFILEINFO {
+        dwSignature: DWORD,
+        dwStrucVersion: DWORD,
+        dwFileVersionMS: DWORD,
+        dwFileVersionLS: DWORD,
+        dwProductVersionMS: DWORD,
+        dwProductVersionLS: DWORD,
+        dwFileFlagsMask: DWORD,
+        dwFileFlags: DWORD,
+        dwFileOS: DWORD,
+        dwFileType: DWORD,
+        dwFileSubtype: DWORD,
+        dwFileDateMS: DWORD,
+        dwFileDateLS: DWORD,
+    }
+}
+
+const VS_FIXEDFILEINFO_SIGNATURE: DWORD = 0xFEEF_04BD;
+
+const IOCTL_STORAGE_QUERY_PROPERTY: DWORD = 0x002D_1400;
+const STORAGE_DEVICE_SEEK_PENALTY_PROPERTY: DWORD = 7;
+const PROPERTY_STANDARD_QUERY: DWORD = 0;
+
+const ERROR_SUCCESS: HRESULT = 0;
+const ERROR_COMPRESSION_NOT_BENEFICIAL: HRESULT = 344;
+
+const COMPRESSION_FORMAT_NONE: USHORT = 0;
+
+const FILE_PROVIDER_CURRENT_VERSION: ULONG = 1;
+const WOF_CURRENT_VERSION: ULONG = 1;
+const WOF_PROVIDER_WIM: ULONG = 1;
+const WOF_PROVIDER_FILE: ULONG = 2;
+
+impl Default for _FILE_PROVIDER_EXTERNAL_INFO_V1 {
+    fn default() -> Self {
+        Self {
+            Version: FILE_PROVIDER_CURRENT_VERSION,
+            Algorithm: Compression::default().into(),
+            Flags: 0,
+        }
+    }
+}
+
+impl Default for _WOF_EXTERNAL_INFO {
+    fn default() -> Self {
+        Self {
+            Version: WOF_CURRENT_VERSION,
+            Provider: WOF_PROVIDER_FILE,
+        }
+    }
+}
+
+impl From<Compression> for _FILE_PROVIDER_EXTERNAL_INFO_V1 {
+    fn from(compression: Compression) -> Self {
+        Self {
+            Version: FILE_PROVIDER_CURRENT_VERSION,
+            Algorithm: compression.into(),
+            Flags: 0,
+        }
+    }
+}
+
+pub fn system_supports_compression() -> std::io::Result<bool> {
+    let dll = CString::new("WofUtil.dll").unwrap();
+    let path = CString::new("\\").unwrap();
+    let mut handle = 0;
+
+    let len = unsafe { GetFileVersionInfoSizeA(dll.as_ptr(), &mut handle) };
+
+    if len == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; len as usize];
+
+    let ret = unsafe {
+        GetFileVersionInfoA(
+            dll.as_ptr(),
+            handle,
+            len,
+            buf.as_mut_ptr() as *mut _ as PVOID,
+        )
+    };
+
+    if ret == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut pinfo: PVOID = std::ptr::null_mut();
+    let mut pinfo_size = 0;
+
+    let ret = unsafe {
+        VerQueryValueA(
+            buf.as_mut_ptr() as *mut _ as PVOID,
+            path.as_ptr(),
+            &mut pinfo,
+            &mut pinfo_size,
+        )
+    };
+
+    if ret == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    assert!(pinfo_size as usize >= std::mem::size_of::<VS_FIXEDFILEINFO>());
+    assert!(!pinfo.is_null());
+
+    let pinfo: &VS_FIXEDFILEINFO = unsafe { &*(pinfo as *const VS_FIXEDFILEINFO) };
+    assert!(pinfo.dwSignature == VS_FIXEDFILEINFO_SIGNATURE);
+
+    Ok((pinfo.dwFileVersionMS >> 16) & 0xffff >= 10)
+}
+
+pub fn file_supports_compression<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let mut version: ULONG = 0;
+
+    let ret = unsafe {
+        WofGetDriverVersion(
+            file.as_raw_handle() as HANDLE,
+            WOF_PROVIDER_FILE,
+            &mut version,
+        )
+    };
+
+    if SUCCEEDED(ret) && version > 0 {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub fn detect_provider<P: AsRef<OsStr>>(path: P) -> std::io::Result<Option<Provider>> {
+    let mut p: Vec<u16> = path.as_ref().encode_wide().collect();
+    p.push(0);
+
+    let mut is_external: BOOL = 0;
+    let mut provider: ULONG = 0;
+    let mut info: _EXTERNAL_FILE_INFO = unsafe { std::mem::zeroed() };
+    let mut len: ULONG = std::mem::size_of::<_EXTERNAL_FILE_INFO>() as ULONG;
+
+    let ret = unsafe {
+        WofIsExternalFile(
+            p.as_ptr(),
+            &mut is_external,
+            &mut provider,
+            &mut info as *mut _ as PVOID,
+            &mut len,
+        )
+    };
+
+    if !SUCCEEDED(ret) {
+        return Err(std::io::Error::from_raw_os_error(HRESULT_CODE(ret)));
+    }
+
+    if is_external == 0 {
+        return Ok(None);
+    }
+
+    match provider {
+        WOF_PROVIDER_FILE => {
+            let algorithm = unsafe { info.file.Algorithm };
+            Ok(Compression::try_from(algorithm).ok().map(Provider::File))
+        }
+        WOF_PROVIDER_WIM => {
+            let wim = unsafe { info.wim };
+            Ok(Some(Provider::Wim {
+                data_source_id: wim.DataSourceId,
+                resource_hash: wim.ResourceHash,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn detect_compression<P: AsRef<OsStr>>(path: P) -> std::io::Result<Option<Compression>> {
+    match detect_provider(path)? {
+        Some(Provider::File(compression)) => Ok(Some(compression)),
+        _ => Ok(None),
+    }
+}
+
+unsafe fn as_byte_slice<T: Sized + Copy>(p: &T) -> &[u8] {
+    std::slice::from_raw_parts((p as *const T) as *const u8, std::mem::size_of::<T>())
+}
+
+pub fn compress_file<P: AsRef<Path>>(path: P, compression: Compression) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    compress_file_handle(&file, compression)
+}
+
+pub fn compress_file_handle(file: &File, compression: Compression) -> std::io::Result<bool> {
+    const LEN: usize = std::mem::size_of::<_WOF_EXTERNAL_INFO>()
+        + std::mem::size_of::<_FILE_PROVIDER_EXTERNAL_INFO_V1>();
+
+    let mut data = [0u8; LEN];
+    let (wof, inf) = data.split_at_mut(std::mem::size_of::<_WOF_EXTERNAL_INFO>());
+    unsafe {
+        wof.copy_from_slice(as_byte_slice(&_WOF_EXTERNAL_INFO::default()));
+        inf.copy_from_slice(as_byte_slice(&_FILE_PROVIDER_EXTERNAL_INFO_V1::from(
+            compression,
+        )));
+    }
+
+    let mut bytes_returned: DWORD = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as HANDLE,
+            FSCTL_SET_EXTERNAL_BACKING,
+            &mut data as *mut _ as PVOID,
+            data.len() as DWORD,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    // BOOL my arse
+    if SUCCEEDED(ret) {
+        Ok(true)
+    } else {
+        let e = HRESULT_CODE(ret);
+
+        if e == ERROR_COMPRESSION_NOT_BENEFICIAL {
+            Ok(false)
+        } else {
+            Err(std::io::Error::from_raw_os_error(e))
+        }
+    }
+}
+
+pub fn uncompress_file<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let file = File::open(path)?;
+    uncompress_file_handle(&file)
+}
+
+pub fn uncompress_file_handle(file: &File) -> std::io::Result<()> {
+    let mut bytes_returned: DWORD = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as HANDLE,
+            FSCTL_DELETE_EXTERNAL_BACKING,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if SUCCEEDED(ret) {
+        Ok(())
+    } else {
+        Err(std::io::Error::from_raw_os_error(HRESULT_CODE(ret)))
+    }
+}
+
+/// Remove legacy NTFS compression from `path`.
+///
+/// This needs write access, so `path` is opened again rather than using the
+/// handle from `open_for_compression()`.
+pub fn clear_ntfs_compression<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let file = OpenOptions::new()
+        .access_mode(FILE_READ_DATA | FILE_WRITE_DATA | FILE_WRITE_ATTRIBUTES)
+        .open(path)?;
+    let mut format = COMPRESSION_FORMAT_NONE;
+    let mut bytes_returned: DWORD = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as HANDLE,
+            FSCTL_SET_COMPRESSION,
+            &mut format as *mut _ as PVOID,
+            std::mem::size_of::<USHORT>() as DWORD,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn to_wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(std::iter::once(0)).collect()
+}
+
+fn from_wide(buf: &[u16]) -> PathBuf {
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    PathBuf::from(std::ffi::OsString::from_wide(&buf[..len]))
+}
+
+// The mount point of the volume containing path, as a wide string.
+fn volume_mount_point(path: &Path) -> io::Result<[u16; MAX_PATH + 1]> {
+    let path = to_wide(path.as_os_str());
+    let mut mount_point = [0u16; MAX_PATH + 1];
+
+    let ret = unsafe {
+        GetVolumePathNameW(
+            path.as_ptr(),
+            mount_point.as_mut_ptr(),
+            mount_point.len() as DWORD,
+        )
+    };
+
+    if ret == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(mount_point)
+    }
+}
+
+// The \\?\Volume{GUID} device path of the volume containing path.
+fn volume_device(path: &Path) -> io::Result<PathBuf> {
+    let mount_point = volume_mount_point(path)?;
+    let mut volume = [0u16; MAX_PATH + 1];
+
+    let ret = unsafe {
+        GetVolumeNameForVolumeMountPointW(
+            mount_point.as_ptr(),
+            volume.as_mut_ptr(),
+            volume.len() as DWORD,
+        )
+    };
+
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Opening the device rather than its root directory needs the trailing
+    // backslash removed.
+    let volume = from_wide(&volume);
+    let volume = volume.to_string_lossy();
+    Ok(PathBuf::from(volume.trim_end_matches('\\')))
+}
+
+/// Whether the drive holding `path` is slow to seek, like a spinning disk.
+pub fn incurs_seek_penalty(path: &Path) -> io::Result<bool> {
+    let device = OpenOptions::new()
+        .access_mode(0)
+        .open(volume_device(path)?)?;
+
+    let query = STORAGE_PROPERTY_QUERY {
+        PropertyId: STORAGE_DEVICE_SEEK_PENALTY_PROPERTY,
+        QueryType: PROPERTY_STANDARD_QUERY,
+        AdditionalParameters: [0],
+    };
+    let mut descriptor: DEVICE_SEEK_PENALTY_DESCRIPTOR = unsafe { std::mem::zeroed() };
+    let mut bytes_returned: DWORD = 0;
+
+    let ret = unsafe {
+        DeviceIoControl(
+            device.as_raw_handle() as HANDLE,
+            IOCTL_STORAGE_QUERY_PROPERTY,
+            &query as *const _ as PVOID,
+            std::mem::size_of::<STORAGE_PROPERTY_QUERY>() as DWORD,
+            &mut descriptor as *mut _ as PVOID,
+            std::mem::size_of::<DEVICE_SEEK_PENALTY_DESCRIPTOR>() as DWORD,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if ret == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(descriptor.IncursSeekPenalty != 0)
+    }
+}
+
+/// The cluster size of the volume holding `path`, in bytes.
+pub fn cluster_size(path: &Path) -> io::Result<u64> {
+    let mount_point = volume_mount_point(path)?;
+    let mut sectors_per_cluster: DWORD = 0;
+    let mut bytes_per_sector: DWORD = 0;
+    let mut free_clusters: DWORD = 0;
+    let mut total_clusters: DWORD = 0;
+
+    let ret = unsafe {
+        GetDiskFreeSpaceW(
+            mount_point.as_ptr(),
+            &mut sectors_per_cluster,
+            &mut bytes_per_sector,
+            &mut free_clusters,
+            &mut total_clusters,
+        )
+    };
+
+    if ret == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(u64::from(sectors_per_cluster) * u64::from(bytes_per_sector))
+    }
+}
+
+/// The real thing: Windows Overlay Filter compression via WofUtil.dll.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WofBackend;
+
+impl CompressionBackend for WofBackend {
+    fn supports_compression(&self, path: &Path) -> io::Result<bool> {
+        file_supports_compression(path)
+    }
+
+    fn detect_provider(&self, path: &Path) -> io::Result<Option<Provider>> {
+        detect_provider(path)
+    }
+
+    fn compress(&self, _path: &Path, file: &File, compression: Compression) -> io::Result<bool> {
+        compress_file_handle(file, compression)
+    }
+
+    fn uncompress(&self, _path: &Path, file: &File) -> io::Result<()> {
+        uncompress_file_handle(file)
+    }
+
+    fn clear_ntfs_compression(&self, path: &Path, _file: &File) -> io::Result<()> {
+        clear_ntfs_compression(path)
+    }
+
+    fn size_on_disk(&self, path: &Path) -> io::Result<u64> {
+        path.size_on_disk()
+    }
+}
+
+#[link(name = "WofUtil")]
+extern "system" {
+    pub fn WofGetDriverVersion(
+        file_or_volume_handle: HANDLE,
+        provider: ULONG,
+        version: PULONG,
+    ) -> HRESULT;
+
+    pub fn WofIsExternalFile(
+        file_path: LPCWSTR,
+        is_external_file: PBOOL,
+        provider: PULONG,
+        external_file_info: PVOID,
+        length: PULONG,
+    ) -> HRESULT;
+
+    // This is a slightly simpler way of setting file backing.
+    pub fn WofSetFileDataLocation(
+        file_handle: HANDLE,
+        provider: ULONG,
+        external_file_info: PVOID,
+        length: ULONG,
+    ) -> HRESULT;
+}
+
+#[test]
+fn compact_works_i_guess() {
+    let path = std::path::PathBuf::from("Cargo.lock");
+
+    let supported = system_supports_compression().expect("system_supports_compression");
+
+    if supported && file_supports_compression(&path).expect("file_supports_compression") {
+        uncompress_file(&path).expect("uncompress_file");
+        assert_eq!(None, detect_compression(&path).expect("detect_compression"));
+        compress_file(&path, Compression::default()).expect("compress_file");
+        assert_eq!(
+            Some(Compression::default()),
+            detect_compression(&path).expect("detect_compression")
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! Predicting the size of files after WOF compression, without compressing
+//! them.
+//!
+//! WOF splits a file into chunks of the algorithm's chunk size and compresses
+//! each independently.  They're stored in an alternate data stream, preceded
+//! by a table of where each chunk after the first starts, with any chunk that
+//! doesn't shrink stored as-is.  The file's own data stream is emptied, so the
+//! space it uses on disk is the compressed stream rounded up to whole
+//! clusters.
+//!
+//! `simulate` does all of this with the pure-Rust codecs.  The accounting is
+//! exact, but the codecs aren't Microsoft's, so compressed chunk sizes may
+//! differ by a little either way.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use serde_derive::Serialize;
+
+use crate::codec::compress_chunk;
+use crate::compact::Compression;
+
+/// The cluster size of most NTFS volumes, for when the real one isn't known.
+pub const DEFAULT_CLUSTER_SIZE: u64 = 4096;
+
+// How much of a file `estimate` compresses.
+const ESTIMATE_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+/// What compressing a file would do.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Simulation {
+    pub compression: Compression,
+    pub logical_size: u64,
+    pub chunks: u64,
+    /// Chunks that wouldn't shrink, so would be stored uncompressed.
+    pub stored_chunks: u64,
+    /// The size of the chunk offset table.
+    pub table_size: u64,
+    /// The size of the compressed stream: the table and all the chunks.
+    pub compressed_size: u64,
+    /// Whether compression would save at least a cluster.  If not, WOF leaves
+    /// the file alone.
+    pub beneficial: bool,
+    /// The size on disk after compaction.
+    pub physical_size: u64,
+}
+
+/// Simulate compressing `data` with `compression`, on a volume with clusters
+/// of `cluster_size` bytes.
+///
+/// Data is read a chunk at a time, so this works just as well on a `File` as
+/// on a slice.
+pub fn simulate<R: Read>(
+    mut data: R,
+    compression: Compression,
+    cluster_size: u64,
+) -> io::Result<Simulation> {
+    let mut chunk = vec![0; compression.chunk_size()];
+    let mut sim = Simulation {
+        compression,
+        logical_size: 0,
+        chunks: 0,
+        stored_chunks: 0,
+        table_size: 0,
+        compressed_size: 0,
+        beneficial: false,
+        physical_size: 0,
+    };
+
+    loop {
+        let len = read_chunk(&mut data, &mut chunk)?;
+        if len == 0 {
+            break;
+        }
+
+        let compressed = compress_chunk(compression, &chunk[..len]).len();
+        if compressed >= len {
+            sim.stored_chunks += 1;
+        }
+
+        sim.logical_size += len as u64;
+        sim.compressed_size += compressed.min(len) as u64;
+        sim.chunks += 1;
+    }
+
+    sim.table_size = table_size(sim.logical_size, sim.chunks);
+    sim.compressed_size += sim.table_size;
+
+    let (beneficial, physical_size) =
+        physical_size(sim.logical_size, sim.compressed_size, cluster_size);
+    sim.beneficial = beneficial;
+    sim.physical_size = physical_size;
+
+    Ok(sim)
+}
+
+/// Estimate how well a file of `logical_size` bytes would compress with
+/// `compression`, as a fraction of its original size.
+///
+/// Up to a megabyte of evenly spaced chunks are compressed, with any that
+/// don't shrink counted as stored.  Empty files don't compress at all.
+pub fn estimate<R: Read + Seek>(
+    mut data: R,
+    logical_size: u64,
+    compression: Compression,
+) -> io::Result<f64> {
+    let chunk_size = compression.chunk_size() as u64;
+    let chunks = logical_size.div_ceil(chunk_size);
+    let samples = (ESTIMATE_SAMPLE_SIZE / chunk_size).min(chunks);
+
+    let mut chunk = vec![0; compression.chunk_size()];
+    let mut sample_size = 0;
+    let mut compressed_size = 0;
+
+    for i in 0..samples {
+        data.seek(SeekFrom::Start(i * chunks / samples * chunk_size))?;
+        let len = read_chunk(&mut data, &mut chunk)?;
+
+        sample_size += len as u64;
+        compressed_size += compress_chunk(compression, &chunk[..len]).len().min(len) as u64;
+    }
+
+    Ok(match sample_size {
+        0 => 1.0,
+        _ => compressed_size as f64 / sample_size as f64,
+    })
+}
+
+/// Estimate the size on disk of a file of `logical_size` bytes after
+/// compression, given a sample of its chunks.
+///
+/// The sample should be of whole chunks, `sample_size` bytes in total, that
+/// `simulate` found would take `sample_compressed_size` bytes once stored.
+pub fn extrapolate(
+    logical_size: u64,
+    sample_size: u64,
+    sample_compressed_size: u64,
+    compression: Compression,
+    cluster_size: u64,
+) -> u64 {
+    let chunks = logical_size.div_ceil(compression.chunk_size() as u64);
+    let data = match sample_size {
+        0 => logical_size,
+        _ => (u128::from(logical_size) * u128::from(sample_compressed_size))
+            .div_ceil(u128::from(sample_size)) as u64,
+    };
+
+    physical_size(
+        logical_size,
+        data + table_size(logical_size, chunks),
+        cluster_size,
+    )
+    .1
+}
+
+// Whether storing a file compressed would save a cluster, and its size on
+// disk either way.
+fn physical_size(logical_size: u64, compressed_size: u64, cluster_size: u64) -> (bool, u64) {
+    let original = round_up(logical_size, cluster_size);
+    let compressed = round_up(compressed_size, cluster_size);
+
+    if compressed < original {
+        (true, compressed)
+    } else {
+        (false, original)
+    }
+}
+
+// The size of the chunk offset table, which has an entry for the end of every
+// chunk but the last, wide enough to address the whole file.
+fn table_size(logical_size: u64, chunks: u64) -> u64 {
+    let entry = if logical_size > u64::from(u32::MAX) {
+        8
+    } else {
+        4
+    };
+
+    chunks.saturating_sub(1) * entry
+}
+
+fn round_up(size: u64, cluster_size: u64) -> u64 {
+    let cluster_size = cluster_size.max(1);
+    size.div_ceil(cluster_size) * cluster_size
+}
+
+// Fill `buf` from `data`, short only at the end.
+fn read_chunk<R: Read>(data: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match data.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+#[test]
+fn it_simulates_compressible_files() {
+    let data = b"It was the best of times, it was the worst of times. ".repeat(1000);
+
+    for &compression in &Compression::ALL {
+        let sim = simulate(&data[..], compression, 4096).unwrap();
+        let chunks = data.len().div_ceil(compression.chunk_size());
+
+        assert_eq!(sim.logical_size, data.len() as u64);
+        assert_eq!(sim.chunks, chunks as u64);
+        assert_eq!(sim.stored_chunks, 0);
+        assert_eq!(sim.table_size, 4 * (chunks as u64 - 1));
+        assert!(sim.beneficial);
+        assert_eq!(sim.physical_size % 4096, 0);
+        assert!(sim.physical_size >= sim.compressed_size);
+        assert!(sim.physical_size < sim.compressed_size + 4096);
+    }
+}
+
+#[test]
+fn it_stores_incompressible_chunks() {
+    use crate::codec::noise;
+
+    // Two incompressible chunks either side of a compressible one
+    let mut data = noise(8192, 1);
+    data.extend(vec![0; 8192]);
+    data.extend(noise(100, 2));
+
+    let sim = simulate(&data[..], Compression::Xpress8k, 4096).unwrap();
+    assert_eq!(sim.chunks, 3);
+    assert_eq!(sim.stored_chunks, 2);
+    assert_eq!(sim.table_size, 8);
+
+    let zeros = compress_chunk(Compression::Xpress8k, &[0; 8192]).len() as u64;
+    assert_eq!(sim.compressed_size, 8 + 8192 + zeros + 100);
+    assert!(sim.beneficial);
+    assert_eq!(sim.physical_size, 12288);
+
+    // Nothing to gain at all
+    let sim = simulate(&noise(10000, 3)[..], Compression::Lzx, 4096).unwrap();
+    assert_eq!(sim.stored_chunks, 1);
+    assert_eq!(sim.compressed_size, 10000);
+    assert!(!sim.beneficial);
+    assert_eq!(sim.physical_size, 12288);
+}
+
+#[test]
+fn it_rounds_to_clusters() {
+    let data = vec![0; 40000];
+
+    let small = simulate(&data[..], Compression::Xpress4k, 512).unwrap();
+    let large = simulate(&data[..], Compression::Xpress4k, 65536).unwrap();
+    assert_eq!(small.compressed_size, large.compressed_size);
+    assert_eq!(small.physical_size, round_up(small.compressed_size, 512));
+
+    // A single cluster either way, so not worth it
+    assert!(!large.beneficial);
+    assert_eq!(large.physical_size, 65536);
+
+    let empty = simulate(&[][..], Compression::Lzx, 4096).unwrap();
+    assert_eq!((empty.chunks, empty.compressed_size), (0, 0));
+    assert!(!empty.beneficial);
+    assert_eq!(empty.physical_size, 0);
+}
+
+#[test]
+fn it_extrapolates_from_samples() {
+    let data = b"It was the best of times, it was the worst of times. ".repeat(1000);
+    let sim = simulate(&data[..], Compression::Xpress8k, 4096).unwrap();
+    let stored = sim.compressed_size - sim.table_size;
+
+    // A sample of everything is exact
+    assert_eq!(
+        extrapolate(
+            sim.logical_size,
+            sim.logical_size,
+            stored,
+            Compression::Xpress8k,
+            4096
+        ),
+        sim.physical_size
+    );
+
+    // A tenth of a larger file
+    assert_eq!(
+        extrapolate(
+            10 * sim.logical_size,
+            sim.logical_size,
+            stored,
+            Compression::Xpress8k,
+            512
+        ),
+        round_up(10 * stored + table_size(10 * sim.logical_size, 65), 512)
+    );
+
+    // Incompressible, or unsampled
+    assert_eq!(
+        extrapolate(10000, 8192, 8192, Compression::Xpress8k, 4096),
+        12288
+    );
+    assert_eq!(extrapolate(10000, 0, 0, Compression::Xpress8k, 4096), 12288);
+}
+
+#[test]
+fn it_estimates_each_algorithm() {
+    use crate::codec::noise;
+    use std::io::Cursor;
+
+    // Repeats are too far apart for XPRESS 4K's chunks, but not LZX's
+    let data = noise(8192, 1).repeat(64);
+    let len = data.len() as u64;
+
+    let xpress = estimate(Cursor::new(&data), len, Compression::Xpress4k).unwrap();
+    let lzx = estimate(Cursor::new(&data), len, Compression::Lzx).unwrap();
+    assert!(xpress >= 1.0, "{}", xpress);
+    assert!(lzx < 0.5, "{}", lzx);
+
+    // Samples are spread over the whole file
+    let mut data = vec![0; 4 * 1024 * 1024];
+    data.extend(noise(4 * 1024 * 1024, 2));
+    let len = data.len() as u64;
+    let ratio = estimate(Cursor::new(&data), len, Compression::Xpress8k).unwrap();
+    assert!(0.4 < ratio && ratio < 0.6, "{}", ratio);
+
+    let empty = estimate(Cursor::new(&[]), 0, Compression::Lzx).unwrap();
+    assert_eq!(empty, 1.0);
+}
+
+#[test]
+fn it_widens_chunk_tables_for_huge_files() {
+    assert_eq!(table_size(0, 0), 0);
+    assert_eq!(table_size(4096, 1), 0);
+    assert_eq!(table_size(u64::from(u32::MAX), 131_072), 4 * 131_071);
+    assert_eq!(table_size(u64::from(u32::MAX) + 1, 131_072), 8 * 131_071);
+}
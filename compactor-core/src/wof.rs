@@ -0,0 +1,353 @@
+//! WOF's on-disk format, read without Windows.
+//!
+//! A file compressed by WOF has a reparse point tagged `IO_REPARSE_TAG_WOF`
+//! saying which provider backs it.  For the file provider, which is what
+//! Compactor uses, that includes the algorithm, and the contents move to a
+//! `WofCompressedData` alternate data stream: a table of where each chunk after
+//! the first starts, relative to the end of the table, then the chunks, each
+//! compressed independently or stored as-is if that didn't shrink it.  The
+//! file's own data stream keeps its size, but nothing else.
+//!
+//! Files backed by the WIM provider have their contents in a WIM image
+//! instead, so only their reparse point can be read here.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use crate::codec::{compress_chunk, decompress_chunk};
+use crate::compact::Compression;
+
+/// The reparse tag of WOF-backed files.
+pub const IO_REPARSE_TAG_WOF: u32 = 0x8000_0017;
+
+/// The name of the alternate data stream holding a compressed file.
+pub const WOF_STREAM: &str = "WofCompressedData";
+
+const WOF_CURRENT_VERSION: u32 = 1;
+const WOF_PROVIDER_WIM: u32 = 1;
+const WOF_PROVIDER_FILE: u32 = 2;
+const WIM_PROVIDER_CURRENT_VERSION: u32 = 1;
+const FILE_PROVIDER_CURRENT_VERSION: u32 = 1;
+
+/// What backs a WOF file, from its reparse point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// The contents are a resource in a WIM image.
+    Wim {
+        data_source_id: i64,
+        /// The SHA-1 of the resource.
+        resource_hash: [u8; 20],
+    },
+    /// The contents are compressed into the `WofCompressedData` stream.
+    File(Compression),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+impl Provider {
+    /// Parse a reparse point, as returned by `FSCTL_GET_REPARSE_POINT` or
+    /// stored in an NTFS `$REPARSE_POINT` attribute.
+    pub fn from_reparse_point(data: &[u8]) -> io::Result<Self> {
+        if data.len() < 8 || u32_at(data, 0) != IO_REPARSE_TAG_WOF {
+            return Err(invalid("not a WOF reparse point"));
+        }
+
+        let len = usize::from(u16::from_le_bytes([data[4], data[5]]));
+        let data = data
+            .get(8..8 + len)
+            .ok_or_else(|| invalid("truncated reparse point"))?;
+
+        if len < 8 || u32_at(data, 0) != WOF_CURRENT_VERSION {
+            return Err(invalid("unsupported WOF version"));
+        }
+
+        match (u32_at(data, 4), &data[8..]) {
+            (WOF_PROVIDER_FILE, info) if info.len() >= 8 => {
+                if u32_at(info, 0) != FILE_PROVIDER_CURRENT_VERSION {
+                    return Err(invalid("unsupported file provider version"));
+                }
+
+                Compression::try_from(u32_at(info, 4))
+                    .map(Provider::File)
+                    .map_err(|_| invalid("unknown compression algorithm"))
+            }
+            (WOF_PROVIDER_WIM, info) if info.len() >= 36 => {
+                if u32_at(info, 0) != WIM_PROVIDER_CURRENT_VERSION {
+                    return Err(invalid("unsupported WIM provider version"));
+                }
+
+                let mut id = [0; 8];
+                id.copy_from_slice(&info[8..16]);
+                let mut resource_hash = [0; 20];
+                resource_hash.copy_from_slice(&info[16..36]);
+
+                Ok(Provider::Wim {
+                    data_source_id: i64::from_le_bytes(id),
+                    resource_hash,
+                })
+            }
+            (WOF_PROVIDER_FILE, _) | (WOF_PROVIDER_WIM, _) => {
+                Err(invalid("truncated provider info"))
+            }
+            _ => Err(invalid("unknown WOF provider")),
+        }
+    }
+
+    /// Build the reparse point for a file backed by this provider.
+    pub fn to_reparse_point(&self) -> Vec<u8> {
+        let mut info: Vec<u8> = vec![];
+        let provider = match self {
+            Provider::Wim {
+                data_source_id,
+                resource_hash,
+            } => {
+                info.extend(&WIM_PROVIDER_CURRENT_VERSION.to_le_bytes());
+                info.extend(&0u32.to_le_bytes());
+                info.extend(&data_source_id.to_le_bytes());
+                info.extend(resource_hash);
+                WOF_PROVIDER_WIM
+            }
+            Provider::File(compression) => {
+                info.extend(&FILE_PROVIDER_CURRENT_VERSION.to_le_bytes());
+                info.extend(&u32::from(*compression).to_le_bytes());
+                WOF_PROVIDER_FILE
+            }
+        };
+
+        let mut data: Vec<u8> = vec![];
+        data.extend(&IO_REPARSE_TAG_WOF.to_le_bytes());
+        data.extend(&(8 + info.len() as u16).to_le_bytes());
+        data.extend(&[0, 0]);
+        data.extend(&WOF_CURRENT_VERSION.to_le_bytes());
+        data.extend(&provider.to_le_bytes());
+        data.extend(info);
+        data
+    }
+}
+
+// The width of chunk table entries for a file of `size` bytes.
+fn entry_size(size: u64) -> usize {
+    if size > u64::from(u32::MAX) {
+        8
+    } else {
+        4
+    }
+}
+
+/// Compress `data` into a `WofCompressedData` stream, as WOF would with
+/// `compression`.
+pub fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+    let entry = entry_size(data.len() as u64);
+    let mut table: Vec<u8> = vec![];
+    let mut chunks: Vec<u8> = vec![];
+
+    for chunk in data.chunks(compression.chunk_size()) {
+        if !chunks.is_empty() {
+            table.extend(&(chunks.len() as u64).to_le_bytes()[..entry]);
+        }
+
+        let compressed = compress_chunk(compression, chunk);
+        if compressed.len() < chunk.len() {
+            chunks.extend(compressed);
+        } else {
+            chunks.extend(chunk);
+        }
+    }
+
+    table.extend(chunks);
+    table
+}
+
+/// Reconstruct a file of `size` bytes compressed with `compression` from its
+/// `WofCompressedData` stream, writing it to `out`.
+///
+/// The stream is read sequentially, a chunk at a time.
+pub fn decompress<R: Read, W: Write>(
+    mut stream: R,
+    compression: Compression,
+    size: u64,
+    mut out: W,
+) -> io::Result<()> {
+    let chunk_size = compression.chunk_size() as u64;
+    let chunks = size.div_ceil(chunk_size);
+    let entry = entry_size(size);
+
+    // Chunk boundaries relative to the end of the table, save the last
+    let mut offsets = vec![0];
+    let mut bytes = [0; 8];
+    for _ in 1..chunks {
+        stream.read_exact(&mut bytes[..entry])?;
+        offsets.push(u64::from_le_bytes(bytes));
+    }
+
+    let mut buf = vec![];
+    for i in 0..chunks {
+        let expanded = (size - i * chunk_size).min(chunk_size);
+
+        buf.clear();
+        match offsets.get(i as usize + 1) {
+            Some(&end) => {
+                let len = end
+                    .checked_sub(offsets[i as usize])
+                    .filter(|&len| len <= expanded)
+                    .ok_or_else(|| invalid("bad chunk offset"))?;
+                (&mut stream).take(len).read_to_end(&mut buf)?;
+                if buf.len() as u64 != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            None => {
+                (&mut stream).take(expanded + 1).read_to_end(&mut buf)?;
+                if buf.len() as u64 > expanded {
+                    return Err(invalid("trailing data after last chunk"));
+                }
+            }
+        }
+
+        if buf.len() as u64 == expanded {
+            out.write_all(&buf)?;
+        } else {
+            out.write_all(&decompress_chunk(compression, &buf, expanded as usize)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_parses_reparse_points() {
+    for &compression in &Compression::ALL {
+        let provider = Provider::File(compression);
+        let data = provider.to_reparse_point();
+        assert_eq!(data.len(), 24);
+        assert_eq!(Provider::from_reparse_point(&data).unwrap(), provider);
+    }
+
+    // The layout Windows uses for an XPRESS8K file
+    let data = [
+        0x17, 0x00, 0x00, 0x80, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(
+        Provider::from_reparse_point(&data).unwrap(),
+        Provider::File(Compression::Xpress8k)
+    );
+
+    let wim = Provider::Wim {
+        data_source_id: 3,
+        resource_hash: [0xab; 20],
+    };
+    let data = wim.to_reparse_point();
+    assert_eq!(data.len(), 52);
+    assert_eq!(Provider::from_reparse_point(&data).unwrap(), wim);
+}
+
+#[test]
+fn it_rejects_bad_reparse_points() {
+    let good = Provider::File(Compression::Lzx).to_reparse_point();
+    let with = |pos: usize, byte: u8| {
+        let mut data = good.clone();
+        data[pos] = byte;
+        Provider::from_reparse_point(&data)
+    };
+
+    assert!(Provider::from_reparse_point(&good[..20]).is_err());
+    assert!(Provider::from_reparse_point(&[]).is_err());
+    // Tag, WOF version, provider, file provider version, algorithm
+    assert!(with(0, 0x0c).is_err());
+    assert!(with(8, 2).is_err());
+    assert!(with(12, 9).is_err());
+    assert!(with(16, 0).is_err());
+    assert!(with(20, 4).is_err());
+    // A WIM provider needs more info than a file one
+    assert!(with(12, 1).is_err());
+}
+
+#[test]
+fn it_round_trips_streams() {
+    use crate::codec::noise;
+
+    let mut data = b"It was the best of times, it was the worst of times. ".repeat(1000);
+    data.extend(noise(40000, 1));
+    data.extend(vec![0; 12345]);
+
+    for &compression in &Compression::ALL {
+        let stream = compress(&data, compression);
+        assert!(stream.len() < data.len());
+
+        let chunks = data.len().div_ceil(compression.chunk_size());
+        let first = compress_chunk(compression, &data[..compression.chunk_size()]);
+        assert_eq!(u32_at(&stream, 0) as usize, first.len());
+        assert_eq!(&stream[4 * (chunks - 1)..][..first.len()], &first[..]);
+
+        let mut out = vec![];
+        decompress(&stream[..], compression, data.len() as u64, &mut out).unwrap();
+        assert!(out == data, "{} mismatch", compression);
+    }
+
+    let mut out = vec![];
+    decompress(&[][..], Compression::Lzx, 0, &mut out).unwrap();
+    assert!(out.is_empty());
+}
+
+#[test]
+fn it_reads_stored_chunks() {
+    use crate::codec::noise;
+
+    // A stored chunk, a compressed one, and a short stored tail
+    let raw = noise(4096, 2);
+    let zeros = compress_chunk(Compression::Xpress4k, &[0; 4096]);
+    let tail = noise(10, 3);
+
+    let mut stream = vec![];
+    stream.extend(&4096u32.to_le_bytes());
+    stream.extend(&(4096 + zeros.len() as u32).to_le_bytes());
+    stream.extend(&raw);
+    stream.extend(&zeros);
+    stream.extend(&tail);
+    assert_eq!(
+        compress(
+            &[&raw[..], &[0; 4096], &tail].concat(),
+            Compression::Xpress4k
+        ),
+        stream
+    );
+
+    let mut out = vec![];
+    decompress(&stream[..], Compression::Xpress4k, 8202, &mut out).unwrap();
+    assert_eq!(&out[..4096], &raw[..]);
+    assert!(out[4096..8192].iter().all(|&b| b == 0));
+    assert_eq!(&out[8192..], &tail[..]);
+}
+
+#[test]
+fn it_rejects_corrupt_streams() {
+    let data = b"It was the best of times, it was the worst of times. ".repeat(500);
+    let stream = compress(&data, Compression::Xpress4k);
+    let size = data.len() as u64;
+    let check = |stream: &[u8], size| decompress(stream, Compression::Xpress4k, size, io::sink());
+
+    assert!(check(&stream, size).is_ok());
+    assert!(check(&stream[..stream.len() - 1], size).is_err());
+    assert!(check(&stream[..10], size).is_err());
+    assert!(check(&[&stream[..], &[0; 4096]].concat(), size).is_err());
+    assert!(check(&stream, size + 4096).is_err());
+
+    // Offsets running backwards
+    let mut bad = stream.clone();
+    bad[4..8].copy_from_slice(&0u32.to_le_bytes());
+    assert!(check(&bad, size).is_err());
+
+    // A chunk bigger than the chunk size
+    let mut bad = stream;
+    bad[0..4].copy_from_slice(&5000u32.to_le_bytes());
+    assert!(check(&bad, size).is_err());
+}
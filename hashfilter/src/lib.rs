@@ -1,19 +1,314 @@
-use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
-use std::hash::Hash;
+//! A persistent set of hashed keys, each with a `Fingerprint` and maybe an
+//! `Attempt`.
+//!
+//! # Format
+//!
+//! Files start with a 48-byte header: the magic `HashFltr`, a `u32` format
+//! version, a `u32` record size, the two `u64` SipHash keys used to hash keys,
+//! a `u64` generation that changes whenever the file is rewritten, and a
+//! SipHash of all that.  Blocks of up to 4096 records follow, with each
+//! `save()` appending at least one: a `u32` record count and a `u64` SipHash of
+//! the records, then the records themselves.  Everything is little-endian.
+//!
+//! Records are a tag byte, a 128-bit key and two `u64` fields whose meaning
+//! depends on the tag.  Later fingerprint records for a key replace earlier
+//! ones, and removals are recorded as tombstones.  An attempt record adds to
+//! the fingerprint record written just before it.  Records with unknown tags
+//! are ignored, so new kinds can be added without a version bump.  Anything
+//! else needs a new version, which older code will refuse to read or write.
+//!
+//! `load()` skips blocks that fail their checksum and reports them as
+//! `InvalidData` once everything else is loaded.  A block cut short by a crash,
+//! or with a damaged count, ends the file and is dropped by the next `save()`.
+//! A damaged header stops the file loading at all, and the next `save()`
+//! replaces it with whatever's in memory.
+//!
+//! `compact()` rewrites the file with only live entries, dropping replaced
+//! records, tombstones and damaged blocks.  Other instances notice the new
+//! generation and reload it from the start.
+//!
+//! # Upgrading
+//!
+//! Version 1 replaces headerless files of bare 16-byte keys hashed with
+//! SipHash's default keys, with nothing to detect damage or tell what changed.
+//! `migrate()` imports one of those into a new file as `Fingerprint::Legacy`
+//! records, leaving the original in place for older versions.  Their keys
+//! are of paths as given rather than normalised, so `get_file()` and
+//! `remove_file()` look for those too.
+
+use std::collections::HashMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod path;
 
 use fs2::FileExt;
+use siphasher::sip::SipHasher as SipHasher64;
 use siphasher::sip128::{Hasher128, SipHasher};
 
+const MAGIC: [u8; 8] = *b"HashFltr";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 48;
+
+// A record count and checksum.
+const BLOCK_HEADER_SIZE: u64 = 12;
+const MAX_BLOCK_RECORDS: usize = 4096;
+
+const RECORD_SIZE: u64 = 33;
+const TAG_LEGACY: u8 = 1;
+const TAG_METADATA: u8 = 2;
+const TAG_REMOVED: u8 = 3;
+const TAG_ATTEMPT: u8 = 4;
+
+// Headerless files of bare keys, from before version 1.
+const LEGACY_RECORD_SIZE: u64 = 16;
+
+/// What's remembered about a file, to tell if it's changed since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fingerprint {
+    /// Migrated from a file of bare keys, which recorded nothing else, so
+    /// anything modified since `recorded` is assumed to have changed.
+    Legacy { recorded: u64 },
+    /// Length and modification time.
+    Metadata { len: u64, modified: u64 },
+}
+
+impl Fingerprint {
+    pub fn of<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        std::fs::metadata(path).map(|metadata| Self::from(&metadata))
+    }
+
+    /// Whether a file fingerprinted as `current` is unchanged since this was
+    /// taken.
+    pub fn matches(&self, current: &Fingerprint) -> bool {
+        match (*self, *current) {
+            (Fingerprint::Legacy { recorded }, Fingerprint::Metadata { modified, .. }) => {
+                modified <= recorded
+            }
+            (stored, current) => stored == current,
+        }
+    }
+}
+
+impl From<&Metadata> for Fingerprint {
+    fn from(metadata: &Metadata) -> Self {
+        Fingerprint::Metadata {
+            len: metadata.len(),
+            modified: nanos(metadata.modified()),
+        }
+    }
+}
+
+/// What was tried on a file, and how well it went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attempt {
+    /// A caller-defined code for what was tried.
+    pub method: u32,
+    /// The result's size as a fraction of the original, or as near as was
+    /// estimated.
+    pub ratio: f32,
+}
+
+/// Everything remembered about a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub fingerprint: Fingerprint,
+    /// `None` for records from before attempts were recorded.
+    pub attempt: Option<Attempt>,
+}
+
+// A key and its record, or `None` if it's been removed.
+type Entry = (u128, Option<Record>);
+
+// What a single record in the file does to its key.
+enum Change {
+    Replace(Fingerprint),
+    Attempted(Attempt),
+    Remove,
+}
+
+// Append the one or two records for `entry` to `out`.
+fn encode((key, record): Entry, out: &mut Vec<u8>) {
+    let mut push = |tag: u8, a: u64, b: u64| {
+        out.push(tag);
+        out.extend_from_slice(&key.to_le_bytes());
+        out.extend_from_slice(&a.to_le_bytes());
+        out.extend_from_slice(&b.to_le_bytes());
+    };
+
+    let record = match record {
+        Some(record) => record,
+        None => return push(TAG_REMOVED, 0, 0),
+    };
+
+    match record.fingerprint {
+        Fingerprint::Legacy { recorded } => push(TAG_LEGACY, recorded, 0),
+        Fingerprint::Metadata { len, modified } => push(TAG_METADATA, len, modified),
+    }
+
+    if let Some(Attempt { method, ratio }) = record.attempt {
+        push(TAG_ATTEMPT, u64::from(method), u64::from(ratio.to_bits()));
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<(u128, Change)> {
+    let mut key = [0; 16];
+    key.copy_from_slice(&buf[1..17]);
+    let key = u128::from_le_bytes(key);
+    let (a, b) = (le_u64(&buf[17..25]), le_u64(&buf[25..33]));
+
+    let change = match buf[0] {
+        TAG_LEGACY => Change::Replace(Fingerprint::Legacy { recorded: a }),
+        TAG_METADATA => Change::Replace(Fingerprint::Metadata {
+            len: a,
+            modified: b,
+        }),
+        TAG_REMOVED => Change::Remove,
+        TAG_ATTEMPT => Change::Attempted(Attempt {
+            method: a as u32,
+            ratio: f32::from_bits(b as u32),
+        }),
+        _ => return None,
+    };
+
+    Some((key, change))
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hash = SipHasher64::new();
+    hash.write(data);
+    hash.finish()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Header {
+    keys: (u64, u64),
+    generation: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut buf = [0; HEADER_SIZE as usize];
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+        buf[16..24].copy_from_slice(&self.keys.0.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.keys.1.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.generation.to_le_bytes());
+        let sum = checksum(&buf[..40]);
+        buf[40..48].copy_from_slice(&sum.to_le_bytes());
+        buf
+    }
+
+    // `InvalidData` if the header's damaged, or `Unsupported` if it's from a
+    // version we don't know.
+    fn read(file: &mut File) -> io::Result<Self> {
+        let damaged = || io::Error::new(io::ErrorKind::InvalidData, "damaged header");
+        let mut buf = [0; HEADER_SIZE as usize];
+
+        file.seek(SeekFrom::Start(0))?;
+        match file.read_exact(&mut buf) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(damaged()),
+            Err(e) => return Err(e),
+        }
+
+        if buf[0..8] != MAGIC || checksum(&buf[..40]) != le_u64(&buf[40..48]) {
+            return Err(damaged());
+        }
+
+        let version = le_u32(&buf[8..12]);
+        if version != VERSION || u64::from(le_u32(&buf[12..16])) != RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported format version {}", version),
+            ));
+        }
+
+        Ok(Self {
+            keys: (le_u64(&buf[16..24]), le_u64(&buf[24..32])),
+            generation: le_u64(&buf[32..40]),
+        })
+    }
+}
+
+// The end of the last complete block, checking only their counts.
+fn valid_end(file: &mut File, len: u64) -> io::Result<u64> {
+    let mut pos = HEADER_SIZE;
+    let mut head = [0; BLOCK_HEADER_SIZE as usize];
+
+    while pos + BLOCK_HEADER_SIZE <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut head)?;
+
+        let count = le_u32(&head) as usize;
+        let next = pos + BLOCK_HEADER_SIZE + count as u64 * RECORD_SIZE;
+        if count > MAX_BLOCK_RECORDS || next > len {
+            break;
+        }
+        pos = next;
+    }
+
+    Ok(pos)
+}
+
+// Returns how many records were written.
+fn write_blocks<W: Write>(file: &mut W, entries: &[Entry]) -> io::Result<u64> {
+    let mut entries = entries.iter().peekable();
+    let mut data = Vec::new();
+    let mut written = 0;
+
+    while entries.peek().is_some() {
+        // Each entry's records share a block, so they're lost or kept together
+        data.clear();
+        while let Some(entry) =
+            entries.next_if(|_| data.len() <= (MAX_BLOCK_RECORDS - 2) * RECORD_SIZE as usize)
+        {
+            encode(*entry, &mut data);
+        }
+
+        let count = data.len() / RECORD_SIZE as usize;
+        file.write_all(&(count as u32).to_le_bytes())?;
+        file.write_all(&checksum(&data).to_le_bytes())?;
+        file.write_all(&data)?;
+        written += count as u64;
+    }
+
+    Ok(written)
+}
+
+// Nanoseconds since the Unix epoch, or 0 if the platform can't say.
+fn nanos(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
 #[derive(Debug, Default)]
 pub struct HashFilter {
     path: Option<PathBuf>,
     last_offset: u64,
-    filter: HashSet<u128>,
-    pending: Vec<u128>,
+    header: Header,
+    filter: HashMap<u128, Record>,
+    pending: Vec<Entry>,
+    // Records in the file as far as we know, live or not
+    file_records: u64,
 }
 
 impl HashFilter {
@@ -29,6 +324,47 @@ impl HashFilter {
         self.last_offset = 0;
     }
 
+    /// Import a file of bare keys if there's no backing file yet.
+    ///
+    /// Entries are kept for files that haven't been modified since `legacy`
+    /// was last written.  Returns whether anything was migrated.
+    pub fn migrate<P: AsRef<Path>>(&mut self, legacy: P) -> io::Result<bool> {
+        match &self.path {
+            Some(path) if !path.exists() => (),
+            _ => return Ok(false),
+        }
+
+        let file = match File::open(legacy) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        FileExt::lock_shared(&file)?;
+
+        let recorded = Fingerprint::Legacy {
+            recorded: nanos(file.metadata()?.modified()),
+        };
+
+        let mut file = BufReader::new(file);
+        let mut buf = [0; LEGACY_RECORD_SIZE as usize];
+        loop {
+            match file.read_exact(&mut buf) {
+                Ok(()) => self.insert_key(
+                    u128::from_le_bytes(buf),
+                    Record {
+                        fingerprint: recorded,
+                        attempt: None,
+                    },
+                ),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
     pub fn load(&mut self) -> io::Result<()> {
         if self.path.is_none() {
             return Ok(());
@@ -39,23 +375,23 @@ impl HashFilter {
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
             Err(e) => return Err(e),
         };
-        file.lock_shared()?;
+        FileExt::lock_shared(&file)?;
 
-        if self.last_offset > 0 {
-            file.seek(SeekFrom::Start(self.last_offset))?;
+        if file.metadata()?.len() == 0 {
+            return Ok(());
         }
 
-        let mut file = BufReader::new(file);
-        let mut buf = [0; 16];
-        loop {
-            match file.read_exact(&mut buf) {
-                Ok(()) => self.filter.insert(u128::from_le_bytes(buf)),
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
-                Err(e) => return Err(e),
-            };
+        let header = Header::read(&mut file)?;
+        let damaged = self.catch_up(&mut file, header)?;
 
-            self.last_offset += 16;
+        if damaged > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("skipped {} damaged blocks", damaged),
+            ));
         }
+
+        Ok(())
     }
 
     pub fn save(&mut self) -> io::Result<()> {
@@ -68,25 +404,55 @@ impl HashFilter {
         }
 
         let mut file = OpenOptions::new()
+            .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(self.path.as_ref().unwrap())?;
         file.lock_exclusive()?;
 
-        let end = file.metadata()?.len();
-        if end % 16 != 0 {
-            file.set_len(end - (end % 16))?;
+        let len = file.metadata()?.len();
+        let end = if len == 0 {
+            // Nothing in it but what we're about to write
+            self.header.generation = self.next_generation();
+            self.last_offset = HEADER_SIZE;
+            self.file_records = 0;
+            file.write_all(&self.header.encode())?;
+            HEADER_SIZE
+        } else {
+            match Header::read(&mut file) {
+                Ok(header) if header.keys != self.header.keys => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "hash keys differ from loaded entries",
+                    ))
+                }
+                Ok(header) => {
+                    // Rewritten since we last loaded it, so load it all again
+                    if header.generation != self.header.generation {
+                        self.last_offset = 0;
+                    }
+                    valid_end(&mut file, len)?
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return self.rewrite(file),
+                Err(e) => return Err(e),
+            }
+        };
+
+        if end < len {
+            file.set_len(end)?;
         }
-        file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(end))?;
 
         let mut file = BufWriter::new(file);
 
-        while let Some(key) = self.pending.pop() {
-            file.write_all(&key.to_le_bytes())?;
-        }
+        // In order, as later records win
+        self.file_records += write_blocks(&mut file, &self.pending)?;
+        self.pending.clear();
 
+        // Nothing's been missed if the file only had what we've loaded
         if end == self.last_offset {
-            self.last_offset = file.seek(SeekFrom::End(0))?;
+            self.last_offset = file.stream_position()?;
         }
 
         file.into_inner()?.sync_all()?;
@@ -94,23 +460,272 @@ impl HashFilter {
         Ok(())
     }
 
-    pub fn insert<H: Hash>(&mut self, data: H) -> bool {
-        let key = Self::key_for(data);
+    /// Rewrite the backing file with only live entries, including any saved
+    /// by other instances, and the new generation.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if path.exists() => path,
+            _ => return self.save(),
+        };
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        file.lock_exclusive()?;
+
+        // Whatever can't be read is dropped
+        match Header::read(&mut file) {
+            Ok(header) => {
+                self.catch_up(&mut file, header)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => (),
+            Err(e) => return Err(e),
+        }
+
+        self.rewrite(file)
+    }
+
+    /// Remember `data`, replacing any record it already had.
+    pub fn insert<H: Hash>(&mut self, data: H, fingerprint: Fingerprint) -> bool {
+        self.insert_record(
+            data,
+            Record {
+                fingerprint,
+                attempt: None,
+            },
+        )
+    }
+
+    /// Remember the file at `path` as it is now, and what was tried on it.
+    ///
+    /// The path is normalised first - see `path::normalise`.
+    pub fn insert_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        attempt: Option<Attempt>,
+    ) -> io::Result<bool> {
+        let fingerprint = Fingerprint::of(&path)?;
+        Ok(self.insert_record(
+            path::normalise(path.as_ref()),
+            Record {
+                fingerprint,
+                attempt,
+            },
+        ))
+    }
+
+    /// Remember `data` with a full record, replacing any it already had.
+    pub fn insert_record<H: Hash>(&mut self, data: H, record: Record) -> bool {
+        self.insert_key(self.key_for(data), record)
+    }
+
+    /// Forget `data`, returning whether it was known.
+    pub fn remove<H: Hash>(&mut self, data: H) -> bool {
+        let key = self.key_for(data);
 
-        if self.filter.insert(key) {
-            self.pending.push(key);
+        if self.filter.remove(&key).is_some() {
+            self.pending.push((key, None));
             return true;
         }
 
         false
     }
 
-    pub fn contains<H: Hash>(&self, data: H) -> bool {
-        self.filter.contains(&Self::key_for(data))
+    /// Forget the file at `path`, returning whether it was known.
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let path = path.as_ref();
+        let normalised = self.remove(path::normalise(path));
+        let legacy = self.remove(path::legacy(path));
+        normalised || legacy
+    }
+
+    /// Forget every entry whose record doesn't pass `keep`.
+    pub fn retain<F: FnMut(&Record) -> bool>(&mut self, mut keep: F) {
+        let pending = &mut self.pending;
+        self.filter.retain(|key, record| {
+            let kept = keep(record);
+            if !kept {
+                pending.push((*key, None));
+            }
+            kept
+        });
+    }
+
+    /// Whether `data` is known, with a fingerprint that still matches.
+    pub fn contains<H: Hash>(&self, data: H, current: &Fingerprint) -> bool {
+        self.get(data, current).is_some()
+    }
+
+    /// The record for `data`, if its fingerprint still matches.
+    pub fn get<H: Hash>(&self, data: H, current: &Fingerprint) -> Option<Record> {
+        self.filter
+            .get(&self.key_for(data))
+            .filter(|stored| stored.fingerprint.matches(current))
+            .copied()
+    }
+
+    /// The record for the file at `path`, if its fingerprint still matches.
+    pub fn get_file<P: AsRef<Path>>(&self, path: P, current: &Fingerprint) -> Option<Record> {
+        let path = path.as_ref();
+        self.get(path::normalise(path), current).or_else(|| {
+            self.get(path::legacy(path), current)
+                .filter(|record| matches!(record.fingerprint, Fingerprint::Legacy { .. }))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.filter.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filter.is_empty()
+    }
+
+    /// Records in the backing file that `compact()` would drop, as far as we
+    /// know.
+    pub fn dead_records(&self) -> u64 {
+        let live = self
+            .filter
+            .values()
+            .map(|record| 1 + record.attempt.is_some() as u64)
+            .sum();
+        self.file_records.saturating_sub(live)
     }
 
-    fn key_for<H: Hash>(data: H) -> u128 {
-        let mut hash = SipHasher::new();
+    // Read whatever's been added to `file` since we last looked, or all of it
+    // if it's new to us or has been rewritten.  Returns how many blocks were
+    // damaged.
+    fn catch_up(&mut self, file: &mut File, header: Header) -> io::Result<usize> {
+        let reload = self.last_offset == 0 || header.generation != self.header.generation;
+
+        if reload {
+            if header.keys != self.header.keys && !self.pending.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "hash keys differ from unsaved entries",
+                ));
+            }
+
+            self.header = header;
+            self.last_offset = HEADER_SIZE;
+            self.file_records = 0;
+            self.filter.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.last_offset))?;
+
+        let mut file = BufReader::new(file);
+        let mut head = [0; BLOCK_HEADER_SIZE as usize];
+        let mut records = vec![];
+        let mut damaged = 0;
+        loop {
+            match file.read_exact(&mut head) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            // Nothing after this can be found
+            let count = le_u32(&head) as usize;
+            if count > MAX_BLOCK_RECORDS {
+                damaged += 1;
+                break;
+            }
+
+            records.resize(count * RECORD_SIZE as usize, 0);
+            match file.read_exact(&mut records) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            if checksum(&records) == le_u64(&head[4..]) {
+                for record in records.chunks_exact(RECORD_SIZE as usize) {
+                    if let Some((key, change)) = decode(record) {
+                        self.apply(key, change);
+                    }
+                }
+            } else {
+                damaged += 1;
+            }
+
+            self.file_records += count as u64;
+            self.last_offset += BLOCK_HEADER_SIZE + records.len() as u64;
+        }
+
+        // Unsaved changes still win
+        if reload {
+            for (key, record) in &self.pending {
+                match record {
+                    Some(record) => self.filter.insert(*key, *record),
+                    None => self.filter.remove(key),
+                };
+            }
+        }
+
+        Ok(damaged)
+    }
+
+    // Replace the file with everything in memory, under a new generation.
+    fn rewrite(&mut self, mut file: File) -> io::Result<()> {
+        let entries = self
+            .filter
+            .iter()
+            .map(|(key, record)| (*key, Some(*record)))
+            .collect::<Vec<_>>();
+
+        self.header.generation = self.next_generation();
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut file = BufWriter::new(file);
+        file.write_all(&self.header.encode())?;
+        self.file_records = write_blocks(&mut file, &entries)?;
+        self.pending.clear();
+        self.last_offset = file.stream_position()?;
+
+        file.into_inner()?.sync_all()?;
+
+        Ok(())
+    }
+
+    // Something other instances won't have seen before.
+    fn next_generation(&self) -> u64 {
+        nanos(Ok(SystemTime::now())).max(self.header.generation + 1)
+    }
+
+    fn apply(&mut self, key: u128, change: Change) {
+        match change {
+            Change::Replace(fingerprint) => {
+                self.filter.insert(
+                    key,
+                    Record {
+                        fingerprint,
+                        attempt: None,
+                    },
+                );
+            }
+            Change::Attempted(attempt) => {
+                if let Some(record) = self.filter.get_mut(&key) {
+                    record.attempt = Some(attempt);
+                }
+            }
+            Change::Remove => {
+                self.filter.remove(&key);
+            }
+        }
+    }
+
+    fn insert_key(&mut self, key: u128, record: Record) -> bool {
+        if self.filter.insert(key, record) != Some(record) {
+            self.pending.push((key, Some(record)));
+            return true;
+        }
+
+        false
+    }
+
+    fn key_for<H: Hash>(&self, data: H) -> u128 {
+        let mut hash = SipHasher::new_with_keys(self.header.keys.0, self.header.keys.1);
         data.hash(&mut hash);
         let h = hash.finish128();
         (u128::from(h.h1) << 64) | u128::from(h.h2)
@@ -137,21 +752,310 @@ fn it_seems_to_work() {
         PathBuf::from("/path/to/some/file9"),
     ];
 
+    let fingerprint = Fingerprint::Metadata {
+        len: 1234,
+        modified: 5678,
+    };
+
     for p in &paths {
-        hf.insert(p);
+        hf.insert(p, fingerprint);
     }
 
     hf.save().unwrap();
 
     let mut hf2 = HashFilter::open(&db);
     hf2.load().unwrap();
-    hf2.insert(PathBuf::from("/path/to/some/file10"));
+    hf2.insert(PathBuf::from("/path/to/some/file10"), fingerprint);
     hf2.save().unwrap();
 
     for p in &paths {
-        assert!(hf2.contains(p));
+        assert!(hf2.contains(p, &fingerprint));
     }
 
     hf.load().unwrap();
-    assert!(hf.contains(PathBuf::from("/path/to/some/file10")));
+    assert!(hf.contains(PathBuf::from("/path/to/some/file10"), &fingerprint));
+}
+
+#[test]
+fn it_notices_changed_files() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+    let path = PathBuf::from("/path/to/some/file");
+    let old = Fingerprint::Metadata {
+        len: 1234,
+        modified: 5678,
+    };
+    let new = Fingerprint::Metadata {
+        len: 1234,
+        modified: 5679,
+    };
+
+    let mut hf = HashFilter::open(&db);
+    assert!(hf.insert(&path, old));
+    assert!(!hf.insert(&path, old));
+    assert!(hf.contains(&path, &old));
+    assert!(!hf.contains(&path, &new));
+    hf.save().unwrap();
+
+    assert!(hf.insert(&path, new));
+    hf.save().unwrap();
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    assert!(!hf2.contains(&path, &old));
+    assert!(hf2.contains(&path, &new));
+}
+
+#[test]
+fn it_migrates_legacy_files() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let legacy = dir.path().join("legacy.dat");
+    let db = dir.path().join("test.dat");
+    let path = PathBuf::from("/path/to/some/file");
+
+    let mut keys = HashFilter::default().key_for(&path).to_le_bytes().to_vec();
+    keys.extend_from_slice(&[0xff; 7]);
+    std::fs::write(&legacy, keys).unwrap();
+    let recorded = nanos(std::fs::metadata(&legacy).and_then(|m| m.modified()));
+
+    let mut hf = HashFilter::open(&db);
+    assert!(hf.migrate(&legacy).unwrap());
+    assert!(!hf.migrate(&legacy).unwrap());
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    let unchanged = Fingerprint::Metadata {
+        len: 1234,
+        modified: recorded,
+    };
+    let changed = Fingerprint::Metadata {
+        len: 1234,
+        modified: recorded + 1,
+    };
+    assert!(hf2.contains(&path, &unchanged));
+    assert!(!hf2.contains(&path, &changed));
+
+    // Found and forgotten by path, despite not being normalised
+    assert!(hf2.get_file(&path, &unchanged).is_some());
+    assert!(hf2.remove_file(&path));
+    assert!(hf2.get_file(&path, &unchanged).is_none());
+}
+
+#[cfg(test)]
+fn fingerprint(n: u64) -> Fingerprint {
+    Fingerprint::Metadata {
+        len: n,
+        modified: n,
+    }
+}
+
+#[test]
+fn it_skips_damaged_blocks() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+
+    let mut hf = HashFilter::open(&db);
+    hf.insert("first", fingerprint(1));
+    hf.save().unwrap();
+    hf.insert("second", fingerprint(2));
+    hf.save().unwrap();
+
+    let mut data = std::fs::read(&db).unwrap();
+    data[(HEADER_SIZE + BLOCK_HEADER_SIZE) as usize + 20] ^= 1;
+    std::fs::write(&db, data).unwrap();
+
+    let mut hf2 = HashFilter::open(&db);
+    let err = hf2.load().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(!hf2.contains("first", &fingerprint(1)));
+    assert!(hf2.contains("second", &fingerprint(2)));
+}
+
+#[test]
+fn it_drops_torn_blocks() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+
+    let mut hf = HashFilter::open(&db);
+    hf.insert("first", fingerprint(1));
+    hf.save().unwrap();
+
+    let mut file = OpenOptions::new().append(true).open(&db).unwrap();
+    file.write_all(&[3, 0, 0, 0, 1, 2, 3]).unwrap();
+    drop(file);
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    hf2.insert("second", fingerprint(2));
+    hf2.save().unwrap();
+
+    let mut hf3 = HashFilter::open(&db);
+    hf3.load().unwrap();
+    assert!(hf3.contains("first", &fingerprint(1)));
+    assert!(hf3.contains("second", &fingerprint(2)));
+    assert_eq!(
+        std::fs::metadata(&db).unwrap().len(),
+        HEADER_SIZE + 2 * (BLOCK_HEADER_SIZE + RECORD_SIZE)
+    );
+}
+
+#[test]
+fn it_replaces_damaged_headers() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+    std::fs::write(&db, [0xff; 100]).unwrap();
+
+    let mut hf = HashFilter::open(&db);
+    assert_eq!(hf.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    hf.insert("first", fingerprint(1));
+    hf.save().unwrap();
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    assert!(hf2.contains("first", &fingerprint(1)));
+}
+
+#[test]
+fn it_leaves_newer_versions_alone() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+
+    let mut header = Header::default().encode();
+    header[8] = 2;
+    let sum = checksum(&header[..40]);
+    header[40..48].copy_from_slice(&sum.to_le_bytes());
+    std::fs::write(&db, header).unwrap();
+
+    let mut hf = HashFilter::open(&db);
+    assert_eq!(hf.load().unwrap_err().kind(), io::ErrorKind::Unsupported);
+    hf.insert("first", fingerprint(1));
+    assert_eq!(hf.save().unwrap_err().kind(), io::ErrorKind::Unsupported);
+    assert_eq!(std::fs::read(&db).unwrap(), header.to_vec());
+}
+
+#[test]
+fn it_remembers_removals() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+
+    let mut hf = HashFilter::open(&db);
+    hf.insert("first", fingerprint(1));
+    hf.insert("second", fingerprint(2));
+    hf.save().unwrap();
+
+    assert!(hf.remove("first"));
+    assert!(!hf.remove("first"));
+    hf.retain(|record| record.fingerprint != fingerprint(2));
+    assert!(hf.is_empty());
+    hf.save().unwrap();
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    assert!(!hf2.contains("first", &fingerprint(1)));
+    assert!(!hf2.contains("second", &fingerprint(2)));
+    assert_eq!(hf2.dead_records(), 4);
+}
+
+#[test]
+fn it_compacts() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+
+    let mut hf = HashFilter::open(&db);
+    for n in 0..10 {
+        hf.insert(n, fingerprint(n));
+        hf.save().unwrap();
+    }
+    for n in 0..5u64 {
+        hf.remove(n);
+    }
+    hf.save().unwrap();
+
+    let mut other = HashFilter::open(&db);
+    other.load().unwrap();
+    assert_eq!(other.len(), 5);
+
+    hf.compact().unwrap();
+    assert_eq!(hf.dead_records(), 0);
+    assert_eq!(
+        std::fs::metadata(&db).unwrap().len(),
+        HEADER_SIZE + BLOCK_HEADER_SIZE + 5 * RECORD_SIZE
+    );
+
+    // Another instance's unsaved changes survive the rewrite
+    other.insert(10u64, fingerprint(10));
+    other.save().unwrap();
+    other.load().unwrap();
+    assert_eq!(other.len(), 6);
+    assert_eq!(other.dead_records(), 0);
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    for n in 0..5u64 {
+        assert!(!hf2.contains(n, &fingerprint(n)));
+    }
+    for n in 5..11 {
+        assert!(hf2.contains(n, &fingerprint(n)));
+    }
+}
+
+#[test]
+fn it_remembers_attempts() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let db = dir.path().join("test.dat");
+    let record = Record {
+        fingerprint: fingerprint(1),
+        attempt: Some(Attempt {
+            method: 3,
+            ratio: 0.97,
+        }),
+    };
+
+    let mut hf = HashFilter::open(&db);
+    assert!(hf.insert_record("first", record));
+    assert!(!hf.insert_record("first", record));
+    hf.insert("second", fingerprint(2));
+    hf.save().unwrap();
+
+    let mut hf2 = HashFilter::open(&db);
+    hf2.load().unwrap();
+    assert_eq!(hf2.get("first", &fingerprint(1)), Some(record));
+    assert_eq!(hf2.get("first", &fingerprint(2)), None);
+    assert_eq!(hf2.get("second", &fingerprint(2)).unwrap().attempt, None);
+    assert_eq!(hf2.dead_records(), 0);
+
+    // A plain fingerprint replaces the attempt along with the old one
+    hf2.insert("first", fingerprint(1));
+    hf2.save().unwrap();
+    hf2.compact().unwrap();
+
+    let mut hf3 = HashFilter::open(&db);
+    hf3.load().unwrap();
+    assert_eq!(hf3.get("first", &fingerprint(1)).unwrap().attempt, None);
+    assert_eq!(
+        std::fs::metadata(&db).unwrap().len(),
+        HEADER_SIZE + BLOCK_HEADER_SIZE + 2 * RECORD_SIZE
+    );
+}
+
+#[test]
+fn it_finds_files_by_path() {
+    let dir = tempdir::TempDir::new("hashfilter-test").unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, "data").unwrap();
+    let current = Fingerprint::of(&file).unwrap();
+
+    let mut hf = HashFilter::default();
+    assert!(hf.insert_file(&file, None).unwrap());
+    assert!(hf.get_file(&file, &current).is_some());
+    assert!(hf.get_file(dir.path().join("other"), &current).is_none());
+
+    assert!(hf.remove_file(&file));
+    assert!(hf.get_file(&file, &current).is_none());
+
+    // Only legacy records are looked up by their raw path
+    hf.insert(&file, current);
+    assert!(hf.get_file(&file, &current).is_none());
+    assert!(hf.remove_file(&file));
+    assert!(!hf.contains(&file, &current));
 }
@@ -0,0 +1,156 @@
+//! Normalising paths before they're hashed.
+//!
+//! Windows filesystems are usually case-insensitive and accept the same path
+//! in several forms, so `C:\Games\Foo.pak`, `c:/games/foo.pak` and
+//! `\\?\C:\Games\Foo.pak` all need to hash the same.  This is purely lexical:
+//! resolving junctions and symlinks is up to the caller.
+
+use std::path::{Path, PathBuf};
+
+/// The form of `path` that's hashed for its key.
+///
+/// On Windows verbatim prefixes are stripped, separators unified, empty and
+/// `.` components dropped and everything upper-cased.  Elsewhere paths are
+/// used as they are.
+pub fn normalise(path: &Path) -> String {
+    if cfg!(windows) {
+        normalise_windows(&path.to_string_lossy())
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// The path as keys were hashed before normalisation, for finding legacy
+/// records.
+///
+/// Those were hashed as given, which from the usual entry points lacked a
+/// verbatim prefix.
+pub fn legacy(path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return path.to_path_buf();
+    }
+
+    match strip_verbatim(&path.to_string_lossy()) {
+        Some((prefix, rest)) => PathBuf::from(format!("{}{}", prefix, rest)),
+        None => path.to_path_buf(),
+    }
+}
+
+// Split a verbatim prefix from `path`, returning what it should be replaced
+// with and the rest.
+fn strip_verbatim(path: &str) -> Option<(&'static str, &str)> {
+    for prefix in &[r"\\?\", r"\??\", "//?/"] {
+        if let Some(rest) = path.strip_prefix(prefix) {
+            // Slicing by bytes, so mind that the path needn't be ASCII
+            let unc = rest.get(..4).filter(|unc| {
+                unc.get(..3)
+                    .is_some_and(|name| name.eq_ignore_ascii_case("UNC"))
+                    && (unc.ends_with('\\') || unc.ends_with('/'))
+            });
+
+            return match unc {
+                Some(_) => Some((r"\\", &rest[4..])),
+                None => Some(("", rest)),
+            };
+        }
+    }
+
+    None
+}
+
+fn normalise_windows(path: &str) -> String {
+    let (prefix, rest) = match strip_verbatim(path) {
+        Some(split) => split,
+        None if path.starts_with(r"\\") || path.starts_with("//") => (r"\\", &path[2..]),
+        None if path.starts_with('\\') || path.starts_with('/') => (r"\", &path[1..]),
+        None => ("", path),
+    };
+
+    let mut out = String::with_capacity(path.len());
+    out.push_str(prefix);
+
+    let mut components = rest
+        .split(['\\', '/'])
+        .filter(|component| !component.is_empty() && *component != ".");
+    if let Some(first) = components.next() {
+        out.push_str(first);
+
+        // A drive needs its separator to stay absolute
+        if first.ends_with(':') && rest.len() > first.len() {
+            out.push('\\');
+        }
+    }
+    for (i, component) in components.enumerate() {
+        if i > 0 || !out.ends_with('\\') {
+            out.push('\\');
+        }
+        out.push_str(component);
+    }
+
+    // NTFS folds case one character at a time, so leave anything that would
+    // expand, like ß, as it is
+    out.chars()
+        .map(|c| {
+            let mut upper = c.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(u), None) => u,
+                _ => c,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn it_normalises_windows_paths() {
+    let same = [
+        r"C:\Games\Foo.pak",
+        r"c:\games\foo.pak",
+        r"\\?\C:\Games\Foo.pak",
+        r"\??\C:\Games\Foo.pak",
+        "C:/Games/Foo.pak",
+        r"C:\Games\\.\Foo.pak\",
+    ];
+    for path in &same {
+        assert_eq!(normalise_windows(path), r"C:\GAMES\FOO.PAK", "{}", path);
+    }
+
+    let unc = [
+        r"\\server\share\Foo.pak",
+        r"\\?\UNC\server\share\Foo.pak",
+        r"\\?\unc\SERVER\share\foo.pak",
+        "//server/share/Foo.pak",
+    ];
+    for path in &unc {
+        assert_eq!(
+            normalise_windows(path),
+            r"\\SERVER\SHARE\FOO.PAK",
+            "{}",
+            path
+        );
+    }
+
+    assert_eq!(normalise_windows(r"C:\"), r"C:\");
+    assert_eq!(normalise_windows("C:"), "C:");
+    assert_eq!(normalise_windows(r"\Games\Foo"), r"\GAMES\FOO");
+    assert_eq!(normalise_windows(r"Games\ß.pak"), r"GAMES\ß.PAK");
+    assert_eq!(
+        normalise_windows(r"\\?\C:\Jeux\Café.pak"),
+        r"C:\JEUX\CAFÉ.PAK"
+    );
+}
+
+#[test]
+fn it_strips_verbatim_prefixes() {
+    assert_eq!(strip_verbatim(r"\\?\C:\Games"), Some(("", r"C:\Games")));
+    assert_eq!(
+        strip_verbatim(r"\\?\UNC\server\share"),
+        Some((r"\\", r"server\share"))
+    );
+    assert_eq!(
+        strip_verbatim(r"\\?\UNCLE\Games"),
+        Some(("", r"UNCLE\Games"))
+    );
+    assert_eq!(strip_verbatim(r"C:\Games"), None);
+    assert_eq!(strip_verbatim(r"\\?\abé\x"), Some(("", r"abé\x")));
+    assert_eq!(strip_verbatim(r"\\?\UNé\share"), Some(("", r"UNé\share")));
+}
@@ -0,0 +1,1060 @@
+// Command-line interface, for scripting Compactor without the GUI.
+//
+// This drives the same FolderScan and Compaction jobs as the GUI, or an
+// ImageScan for disk images, using the saved settings unless they're
+// overridden on the command line.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use compactor_core::background::BackgroundHandle;
+use compactor_core::compact::{default_backend, Compression, CompressionBackend};
+use compactor_core::compaction::Compaction;
+use compactor_core::compare::{Comparison, FolderComparison, GroupComparison};
+use compactor_core::config::Config;
+use compactor_core::event::{ErrorInfo, Event, NdjsonWriter, Operation, Outcome};
+use compactor_core::folder::{FileKind, FolderInfo, FolderScan, FolderSummary, GroupSummary};
+use compactor_core::persistence::{self, config};
+
+use compactor_core::format::format_size;
+use compactor_core::ntfs::ImageScan;
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_UNSUPPORTED: i32 = 3;
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+const USAGE: &str = "\
+Usage: Compactor <command> [options] <paths>...
+
+Commands:
+    analyse      Report how much of each path is compressed and compressible
+    compare      Sample compressible files to compare each algorithm's savings
+                 and CPU time, by subfolder and extension
+    compress     Compress compressible files
+    decompress   Decompress compressed files
+    convert      Replace legacy NTFS compression with WOF compression
+    recompress   Switch compressed files to the chosen algorithm, listing the
+                 size change of each
+    query        List the compression state of each file
+    forget       Forget files previously found incompressible, so they're
+                 tried again
+
+Options:
+    -c, --compression <ALGORITHM>
+                      Compress with XPRESS4K, XPRESS8K, XPRESS16K or LZX
+    -x, --exclude <GLOB>
+                      Exclude paths matching GLOB, in addition to saved excludes
+        --no-excludes Ignore saved excludes
+    -j, --workers <N> Work on N files at once, instead of picking based on the
+                      drive
+    -p, --project     Read compressible files to project their size once
+                      compressed, which is much slower
+    -i, --image       Treat paths as raw NTFS disk images and read them directly,
+                      for analyse and query
+        --verify      With --image, decompress every WOF-compressed file to
+                      check it's intact
+    -q, --quiet       Don't display progress
+        --json        Write progress and results to stdout as JSON lines,
+                      instead of the usual output
+        --json-file <FILE>
+                      Write progress and results to FILE as JSON lines
+    -h, --help        Display this help
+    -V, --version     Display the version
+
+Without options, the compression algorithm, excludes and projection setting
+configured in the GUI are used.
+
+Exit status:
+    0    Success
+    1    Some paths or files could not be processed
+    2    Invalid usage
+    3    Compression is not supported on a path
+    130  Interrupted
+";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Analyse,
+    Compare,
+    Compress,
+    Decompress,
+    Convert,
+    Recompress,
+    Query,
+    Forget,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub mode: Mode,
+    pub paths: Vec<PathBuf>,
+    pub compression: Option<Compression>,
+    pub excludes: Vec<String>,
+    pub saved_excludes: bool,
+    pub quiet: bool,
+    pub workers: Option<usize>,
+    pub project: bool,
+    /// Whether paths are NTFS disk images.
+    pub image: bool,
+    pub verify: bool,
+    /// Where to write JSON events, with "-" meaning stdout.
+    pub events: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Parsed {
+    Run(Args),
+    Help,
+    Version,
+}
+
+fn parse_mode(s: &str) -> Result<Mode, String> {
+    match s {
+        "analyse" | "analyze" => Ok(Mode::Analyse),
+        "compare" => Ok(Mode::Compare),
+        "compress" | "compact" => Ok(Mode::Compress),
+        "decompress" | "uncompress" | "expand" => Ok(Mode::Decompress),
+        "convert" => Ok(Mode::Convert),
+        "recompress" => Ok(Mode::Recompress),
+        "query" => Ok(Mode::Query),
+        "forget" => Ok(Mode::Forget),
+        _ => Err(format!("unknown command '{}'", s)),
+    }
+}
+
+pub fn parse_args<I: IntoIterator<Item = OsString>>(args: I) -> Result<Parsed, String> {
+    let mut args = args.into_iter();
+    let mut mode = None;
+    let mut paths = vec![];
+    let mut compression = None;
+    let mut excludes = vec![];
+    let mut saved_excludes = true;
+    let mut quiet = false;
+    let mut workers = None;
+    let mut project = false;
+    let mut image = false;
+    let mut verify = false;
+    let mut events = None;
+    let mut only_paths = false;
+
+    while let Some(arg) = args.next() {
+        let s = arg.to_string_lossy().into_owned();
+
+        if only_paths || !s.starts_with('-') || s == "-" {
+            if mode.is_none() {
+                mode = Some(parse_mode(&s)?);
+            } else {
+                paths.push(PathBuf::from(arg));
+            }
+            continue;
+        }
+
+        // Allow --option=value as well as --option value
+        let (name, inline) = match s.find('=') {
+            Some(i) if s.starts_with("--") => (&s[..i], Some(s[i + 1..].to_string())),
+            _ => (&s[..], None),
+        };
+
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next().map(|a| a.to_string_lossy().into_owned()))
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+
+        match name {
+            "-c" | "--compression" => {
+                let alg = value()?;
+                compression = Some(
+                    alg.to_uppercase()
+                        .parse()
+                        .map_err(|_| format!("unknown compression algorithm '{}'", alg))?,
+                );
+            }
+            "-x" | "--exclude" => excludes.push(value()?),
+            "--no-excludes" => saved_excludes = false,
+            "-q" | "--quiet" => quiet = true,
+            "-j" | "--workers" => {
+                let n = value()?;
+                workers = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("invalid number of workers '{}'", n))?,
+                );
+            }
+            "-p" | "--project" => project = true,
+            "-i" | "--image" => image = true,
+            "--verify" => verify = true,
+            "--json" => events = Some(PathBuf::from("-")),
+            "--json-file" => events = Some(PathBuf::from(value()?)),
+            "-h" | "--help" => return Ok(Parsed::Help),
+            "-V" | "--version" => return Ok(Parsed::Version),
+            "--" => only_paths = true,
+            _ => return Err(format!("unknown option '{}'", s)),
+        }
+    }
+
+    let mode = mode.ok_or("no command given")?;
+
+    if paths.is_empty() {
+        return Err("no paths given".to_string());
+    }
+
+    if image && !matches!(mode, Mode::Analyse | Mode::Query) {
+        return Err("--image only works with analyse and query".to_string());
+    }
+
+    if image && project {
+        return Err("--project can't be used with --image".to_string());
+    }
+
+    if verify && !image {
+        return Err("--verify requires --image".to_string());
+    }
+
+    Ok(Parsed::Run(Args {
+        mode,
+        paths,
+        compression,
+        excludes,
+        saved_excludes,
+        quiet,
+        workers,
+        project,
+        image,
+        verify,
+        events,
+    }))
+}
+
+pub fn main(args: Vec<OsString>) -> i32 {
+    match parse_args(args) {
+        Ok(Parsed::Run(args)) => {
+            persistence::init();
+
+            match Cli::new(&args) {
+                Ok(mut cli) => cli.run(&args),
+                Err(status) => status,
+            }
+        }
+        Ok(Parsed::Help) => {
+            print!("{}", USAGE);
+            EXIT_SUCCESS
+        }
+        Ok(Parsed::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            EXIT_SUCCESS
+        }
+        Err(msg) => {
+            eprintln!("Error: {}\n\n{}", msg, USAGE);
+            EXIT_USAGE
+        }
+    }
+}
+
+// A single line of progress on stderr, if it's a terminal.
+struct Progress {
+    enabled: bool,
+    width: usize,
+    last_update: Instant,
+}
+
+impl Progress {
+    fn new(quiet: bool) -> Self {
+        Self {
+            enabled: !quiet && io::stderr().is_terminal(),
+            width: 0,
+            last_update: Instant::now() - Duration::from_secs(1),
+        }
+    }
+
+    fn update<S: AsRef<str>>(&mut self, msg: S) {
+        if !self.enabled || self.last_update.elapsed() < Duration::from_millis(100) {
+            return;
+        }
+
+        let msg: String = msg.as_ref().chars().take(79).collect();
+        let width = msg.chars().count();
+        eprint!("\r{}{}", msg, " ".repeat(self.width.saturating_sub(width)));
+        let _ = io::stderr().flush();
+
+        self.width = width;
+        self.last_update = Instant::now();
+    }
+
+    fn clear(&mut self) {
+        if self.width > 0 {
+            eprint!("\r{}\r", " ".repeat(self.width));
+            let _ = io::stderr().flush();
+            self.width = 0;
+        }
+    }
+}
+
+fn full_path(folder: &FolderInfo, path: &Path) -> PathBuf {
+    // Scanning a single file leaves it with an empty relative path
+    if path.as_os_str().is_empty() {
+        folder.path.clone()
+    } else {
+        folder.path.join(path)
+    }
+}
+
+struct Cli {
+    config: Config,
+    backend: Arc<dyn CompressionBackend>,
+    progress: Progress,
+    events: Option<NdjsonWriter<Box<dyn Write>>>,
+    // Whether to print human-readable output on stdout
+    text: bool,
+    // Whether paths are disk images, and if their WOF data should be checked
+    image: bool,
+    verify: bool,
+}
+
+impl Cli {
+    fn new(args: &Args) -> Result<Self, i32> {
+        let mut config = config().read().unwrap().current();
+
+        if let Some(compression) = args.compression {
+            config.compression = compression;
+        }
+
+        if args.workers.is_some() {
+            config.workers = args.workers;
+        }
+
+        if args.project {
+            config.project_savings = true;
+        }
+
+        if !args.saved_excludes {
+            config.excludes.clear();
+        }
+        config.excludes.extend(args.excludes.iter().cloned());
+
+        if let Err(msg) = config.globset() {
+            eprintln!("Error: invalid exclude: {}", msg);
+            return Err(EXIT_USAGE);
+        }
+
+        let stdout_events = args.events.as_deref() == Some(Path::new("-"));
+        let events: Option<Box<dyn Write>> = match &args.events {
+            None => None,
+            Some(_) if stdout_events => Some(Box::new(io::stdout())),
+            Some(path) => match File::create(path) {
+                Ok(file) => Some(Box::new(BufWriter::new(file))),
+                Err(e) => {
+                    eprintln!("Error: {}: {}", path.display(), e);
+                    return Err(EXIT_FAILURE);
+                }
+            },
+        };
+
+        Ok(Self {
+            config,
+            backend: default_backend(),
+            progress: Progress::new(args.quiet),
+            events: events.map(NdjsonWriter::new),
+            text: !stdout_events,
+            image: args.image,
+            verify: args.verify,
+        })
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(events) = &mut self.events {
+            if let Err(e) = events.emit(&event) {
+                self.progress.clear();
+                eprintln!("Error: writing events: {}", e);
+                self.events = None;
+            }
+        }
+    }
+
+    fn error(&mut self, path: &Path, e: &io::Error) {
+        self.progress.clear();
+        eprintln!("Error: {}: {}", path.display(), e);
+        self.emit(Event::Error {
+            path: path.to_path_buf(),
+            error: ErrorInfo::from(e),
+        });
+    }
+
+    fn run(&mut self, args: &Args) -> i32 {
+        let _ = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst));
+
+        let mut status = EXIT_SUCCESS;
+
+        for path in &args.paths {
+            if interrupted() {
+                break;
+            }
+
+            if let Err(e) = std::fs::metadata(path) {
+                self.error(path, &e);
+                status = status.max(EXIT_FAILURE);
+                continue;
+            }
+
+            let ret = match args.mode {
+                Mode::Analyse => self.analyse(path),
+                Mode::Compare => self.compare(path),
+                Mode::Compress => self.compact(path, Operation::Compress),
+                Mode::Decompress => self.compact(path, Operation::Decompress),
+                Mode::Convert => self.compact(path, Operation::Convert),
+                Mode::Recompress => self.compact(path, Operation::Recompress),
+                Mode::Query => self.query(path),
+                Mode::Forget => self.forget(path),
+            };
+
+            status = status.max(ret);
+        }
+
+        if interrupted() {
+            status = EXIT_INTERRUPTED;
+        }
+
+        status
+    }
+
+    fn scan(&mut self, path: &Path) -> Option<FolderInfo> {
+        let excludes = self.config.globset().expect("globs");
+        let task = if self.image {
+            let mut scanner = ImageScan::new(path, excludes);
+            if self.verify {
+                scanner = scanner.with_verification();
+            }
+            BackgroundHandle::spawn(scanner)
+        } else {
+            let mut scanner = FolderScan::new(path, excludes, self.backend.clone())
+                .with_compression(self.config.compression);
+            if self.config.project_savings {
+                scanner = scanner.with_projection(self.config.compression);
+            }
+            BackgroundHandle::spawn(scanner)
+        };
+        let mut last_summary = FolderSummary::default();
+        let mut last_event = Instant::now();
+
+        self.emit(Event::ScanStarted {
+            path: path.to_path_buf(),
+        });
+
+        loop {
+            if interrupted() {
+                task.cancel();
+            }
+
+            match task.wait_timeout(Duration::from_millis(50)) {
+                Some(Ok(info)) => {
+                    self.progress.clear();
+                    for e in &info.errors {
+                        let file = full_path(&info, &e.path);
+                        eprintln!("Error: {}: {}", file.display(), e.error.message);
+                        self.emit(Event::ScanError {
+                            path: file,
+                            operation: e.operation,
+                            error: e.error.clone(),
+                        });
+                    }
+                    self.emit(Event::ScanFinished {
+                        path: path.to_path_buf(),
+                        complete: true,
+                        summary: info.summary(),
+                    });
+                    return Some(info);
+                }
+                Some(Err(_)) => {
+                    self.progress.clear();
+                    self.emit(Event::ScanFinished {
+                        path: path.to_path_buf(),
+                        complete: false,
+                        summary: last_summary,
+                    });
+                    return None;
+                }
+                None => {
+                    if let Some((file, summary)) = task.status() {
+                        let count = summary.file_count();
+                        self.progress
+                            .update(format!("Scanning {}: {}", count, file.display()));
+
+                        if last_event.elapsed() >= Duration::from_millis(500) {
+                            self.emit(Event::ScanProgress {
+                                path: path.to_path_buf(),
+                                current: file,
+                                summary: summary.clone(),
+                            });
+                            last_event = Instant::now();
+                        }
+                        last_summary = summary;
+                    }
+                }
+            }
+        }
+    }
+
+    fn analyse(&mut self, path: &Path) -> i32 {
+        let start = Instant::now();
+
+        match self.scan(path) {
+            Some(info) => {
+                let summary = info.summary();
+                let status = if summary.errors == 0 {
+                    EXIT_SUCCESS
+                } else {
+                    EXIT_FAILURE
+                };
+                if self.text {
+                    print_summary(path, &summary, self.config.decimal);
+                }
+                self.emit(Event::Summary {
+                    path: path.to_path_buf(),
+                    operation: None,
+                    compression: None,
+                    files: summary.file_count(),
+                    failed: 0,
+                    old_physical_size: summary.physical_size,
+                    new_physical_size: summary.physical_size,
+                    elapsed_secs: start.elapsed().as_secs_f64(),
+                    interrupted: false,
+                    summary,
+                });
+                status
+            }
+            None => EXIT_INTERRUPTED,
+        }
+    }
+
+    fn compare(&mut self, path: &Path) -> i32 {
+        let info = match self.scan(path) {
+            Some(info) => info,
+            None => return EXIT_INTERRUPTED,
+        };
+
+        let task = BackgroundHandle::spawn(Comparison::new(&info));
+        let comparison = loop {
+            if interrupted() {
+                task.cancel();
+            }
+
+            match task.wait_timeout(Duration::from_millis(50)) {
+                Some(Some(comparison)) => break comparison,
+                Some(None) => {
+                    self.progress.clear();
+                    return EXIT_INTERRUPTED;
+                }
+                None => {
+                    if let Some((file, pct)) = task.status() {
+                        self.progress.update(format!(
+                            "Comparing {:.0}%: {}",
+                            pct * 100.0,
+                            file.display()
+                        ));
+                    }
+                }
+            }
+        };
+
+        self.progress.clear();
+        if self.text {
+            print_comparison(path, &comparison, self.config.decimal);
+        }
+        self.emit(Event::Comparison {
+            path: path.to_path_buf(),
+            comparison,
+        });
+
+        if info.errors.is_empty() {
+            EXIT_SUCCESS
+        } else {
+            EXIT_FAILURE
+        }
+    }
+
+    fn forget(&mut self, path: &Path) -> i32 {
+        let info = match self.scan(path) {
+            Some(info) => info,
+            None => return EXIT_INTERRUPTED,
+        };
+
+        let files = match persistence::forget_incompressible(&info) {
+            Ok(files) => files,
+            Err(e) => {
+                self.error(path, &e);
+                return EXIT_FAILURE;
+            }
+        };
+
+        if self.text {
+            println!("{}: forgot {} incompressible files", path.display(), files);
+        }
+        self.emit(Event::Forgotten {
+            path: path.to_path_buf(),
+            files,
+        });
+
+        if info.errors.is_empty() {
+            EXIT_SUCCESS
+        } else {
+            EXIT_FAILURE
+        }
+    }
+
+    fn query(&mut self, path: &Path) -> i32 {
+        let start = Instant::now();
+        let info = match self.scan(path) {
+            Some(info) => info,
+            None => return EXIT_INTERRUPTED,
+        };
+
+        let status = if info.errors.is_empty() {
+            EXIT_SUCCESS
+        } else {
+            EXIT_FAILURE
+        };
+        let mut files = info
+            .compressed
+            .files
+            .iter()
+            .map(|fi| (fi, FileKind::Compressed))
+            .chain(info.wim.files.iter().map(|fi| (fi, FileKind::Wim)))
+            .chain(info.sparse.files.iter().map(|fi| (fi, FileKind::Sparse)))
+            .chain(
+                info.ntfs_compressed
+                    .files
+                    .iter()
+                    .map(|fi| (fi, FileKind::NtfsCompressed)),
+            )
+            .chain(
+                info.compressible
+                    .files
+                    .iter()
+                    .map(|fi| (fi, FileKind::Compressible)),
+            )
+            .chain(info.skipped.files.iter().map(|fi| (fi, FileKind::Skipped)))
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+        for (fi, kind) in files {
+            let path = full_path(&info, &fi.path);
+            // The scan records each compressed file's algorithm, if it has one
+            let state = match (kind, fi.compression) {
+                (FileKind::Wim, _) => "WIM".to_string(),
+                (FileKind::Sparse, _) => "Sparse".to_string(),
+                (FileKind::NtfsCompressed, _) => "LZNT1".to_string(),
+                (_, Some(compression)) => compression.to_string(),
+                (_, None) => "-".to_string(),
+            };
+
+            if self.text {
+                println!(
+                    "{:<10} {:>11} {:>11}  {}",
+                    state,
+                    format_size(fi.physical_size, self.config.decimal),
+                    format_size(fi.logical_size, self.config.decimal),
+                    path.display()
+                );
+            }
+
+            self.emit(Event::FileState {
+                path,
+                compression: fi.compression,
+                logical_size: fi.logical_size,
+                physical_size: fi.physical_size,
+                error: None,
+            });
+        }
+
+        let summary = info.summary();
+        self.emit(Event::Summary {
+            path: path.to_path_buf(),
+            operation: None,
+            compression: None,
+            files: summary.file_count(),
+            failed: 0,
+            old_physical_size: summary.physical_size,
+            new_physical_size: summary.physical_size,
+            elapsed_secs: start.elapsed().as_secs_f64(),
+            interrupted: false,
+            summary,
+        });
+
+        status
+    }
+
+    fn compact(&mut self, path: &Path, operation: Operation) -> i32 {
+        match self.backend.supports_compression(path) {
+            Ok(true) => (),
+            Ok(false) => {
+                let e = io::Error::new(io::ErrorKind::Unsupported, "compression is not supported");
+                self.error(path, &e);
+                return EXIT_UNSUPPORTED;
+            }
+            Err(e) => {
+                self.error(path, &e);
+                return EXIT_UNSUPPORTED;
+            }
+        }
+
+        let folder = match self.scan(path) {
+            Some(info) => info,
+            None => return EXIT_INTERRUPTED,
+        };
+
+        let mut status = if folder.errors.is_empty() {
+            EXIT_SUCCESS
+        } else {
+            EXIT_FAILURE
+        };
+
+        let mut compaction = Compaction::new(
+            folder,
+            operation,
+            self.config.compression,
+            self.config.workers,
+            self.backend.clone(),
+        );
+        let compression = compaction.compression();
+        let verb = compaction.verb();
+
+        loop {
+            // Once interrupted, let the current files finish but start no more
+            while !interrupted() {
+                let (done, total) = (compaction.done(), compaction.total());
+                let (file, fi) = match compaction.start_next() {
+                    Some(started) => started,
+                    None => break,
+                };
+
+                self.progress.update(format!(
+                    "{} {}/{}: {}",
+                    verb,
+                    done,
+                    total,
+                    fi.path.display()
+                ));
+
+                let event = Event::FileStarted {
+                    path: file.to_path_buf(),
+                    operation,
+                    compression,
+                    logical_size: fi.logical_size,
+                    physical_size: fi.physical_size,
+                };
+                self.emit(event);
+            }
+
+            if compaction.in_flight().len() == 0 {
+                break;
+            }
+
+            let result = match compaction.next_result(Duration::from_millis(100)) {
+                Some(result) => result,
+                None => continue,
+            };
+
+            let error = result.error.as_ref().map(|e| {
+                self.progress.clear();
+                eprintln!("Error: {}: {}", result.full_path.display(), e);
+                status = EXIT_FAILURE;
+                ErrorInfo::from(e)
+            });
+
+            if self.text && operation == Operation::Recompress && result.outcome != Outcome::Failed
+            {
+                let algorithm = |compression: Option<Compression>| {
+                    compression.map_or_else(|| "-".to_string(), |c| c.to_string())
+                };
+
+                self.progress.clear();
+                println!(
+                    "{:<9} -> {:<9} {:>11} -> {:>11}  {}",
+                    algorithm(result.old_compression),
+                    algorithm(result.new_compression),
+                    format_size(result.old_physical_size, self.config.decimal),
+                    format_size(result.new_physical_size, self.config.decimal),
+                    result.full_path.display()
+                );
+            }
+
+            self.emit(Event::FileResult {
+                path: result.full_path,
+                operation,
+                compression,
+                outcome: result.outcome,
+                logical_size: result.logical_size,
+                old_physical_size: result.old_physical_size,
+                new_physical_size: result.new_physical_size,
+                error,
+            });
+        }
+
+        let (folder, summary) = compaction.finish();
+        self.progress.clear();
+
+        if self.text {
+            println!(
+                "{}: {}",
+                path.display(),
+                summary.message(self.config.decimal)
+            );
+        }
+
+        self.emit(Event::Summary {
+            path: path.to_path_buf(),
+            operation: Some(operation),
+            compression,
+            files: summary.files,
+            failed: summary.failed,
+            old_physical_size: summary.old_physical_size,
+            new_physical_size: summary.new_physical_size,
+            elapsed_secs: summary.elapsed.as_secs_f64(),
+            interrupted: interrupted(),
+            summary: folder.summary(),
+        });
+
+        status
+    }
+}
+
+fn print_summary(path: &Path, summary: &FolderSummary, decimal: bool) {
+    let row = |name: &str, group: &GroupSummary| {
+        println!(
+            "  {:<18} {:>9} {:>11} {:>11}",
+            name,
+            group.count,
+            format_size(group.logical_size, decimal),
+            format_size(group.physical_size, decimal)
+        );
+    };
+
+    println!("{}", path.display());
+    println!(
+        "  {:<18} {:>9} {:>11} {:>11}",
+        "", "Files", "Logical", "Physical"
+    );
+    row("Compressed", &summary.compressed);
+    for (compression, group) in &summary.algorithms {
+        row(&format!("  {}", compression), group);
+    }
+    if summary.wim.count > 0 {
+        row("WIM-backed", &summary.wim);
+    }
+    if summary.sparse.count > 0 {
+        row("Sparse", &summary.sparse);
+    }
+    if summary.ntfs_compressed.count > 0 {
+        row("NTFS compressed", &summary.ntfs_compressed);
+    }
+    row("Compressible", &summary.compressible);
+    if let Some(compression) = summary.projection {
+        row(
+            &format!("  as {}", compression),
+            &GroupSummary {
+                physical_size: summary.compressible.projected_size,
+                ..summary.compressible.clone()
+            },
+        );
+    }
+    row("Skipped", &summary.skipped);
+    for (reason, group) in &summary.skip_reasons {
+        row(&format!("  {}", reason), group);
+    }
+    row(
+        "Total",
+        &GroupSummary {
+            count: summary.file_count(),
+            logical_size: summary.logical_size,
+            physical_size: summary.physical_size,
+            ..GroupSummary::default()
+        },
+    );
+
+    if summary.errors > 0 {
+        println!("  {:<18} {:>9}", "Unreadable", summary.errors);
+    }
+}
+
+// Groups beyond this many are summarised in a single line.
+const COMPARISON_ROWS: usize = 10;
+
+// Shorten a name to fit a column, marking where it was cut.
+fn truncate(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        name.to_string()
+    } else {
+        name.chars().take(width - 1).chain(Some('…')).collect()
+    }
+}
+
+fn print_comparison(path: &Path, comparison: &FolderComparison, decimal: bool) {
+    let row = |name: &str, group: &GroupComparison| {
+        print!(
+            "  {:<18} {:>11}",
+            name,
+            format_size(group.physical_size, decimal)
+        );
+        for projection in &group.projections {
+            print!(
+                " {:>11} {:>6}",
+                format_size(projection.physical_size, decimal),
+                format!("{:.1}s", projection.cpu_secs)
+            );
+        }
+        match group.recommended {
+            Some(compression) => println!("  {}", compression),
+            None => println!("  -"),
+        }
+    };
+    let section = |title: &str, groups: &[GroupComparison]| {
+        println!("  {}", title);
+        for group in groups.iter().take(COMPARISON_ROWS) {
+            let name = match group.name.as_str() {
+                "" => "(none)",
+                name => name,
+            };
+            row(&format!("  {}", truncate(name, 16)), group);
+        }
+        if groups.len() > COMPARISON_ROWS {
+            println!("    ({} more)", groups.len() - COMPARISON_ROWS);
+        }
+    };
+
+    let total = &comparison.total;
+    println!("{}", path.display());
+    println!(
+        "  Sampled {} of {} in {} compressible files",
+        format_size(total.sample_size, decimal),
+        format_size(total.logical_size, decimal),
+        total.count
+    );
+    print!("  {:<18} {:>11}", "", "Physical");
+    for compression in &Compression::ALL {
+        print!(" {:>18}", compression.to_string());
+    }
+    println!("  Best");
+    row("Total", total);
+    section("By folder", &comparison.folders);
+    section("By extension", &comparison.extensions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Parsed, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn it_parses_commands() {
+        assert_eq!(
+            parse(&[
+                "compress",
+                "-c",
+                "lzx",
+                "--exclude=*.log",
+                "-q",
+                "--workers=4",
+                "--project",
+                "C:\\Games",
+                "D:\\"
+            ]),
+            Ok(Parsed::Run(Args {
+                mode: Mode::Compress,
+                paths: vec![PathBuf::from("C:\\Games"), PathBuf::from("D:\\")],
+                compression: Some(Compression::Lzx),
+                excludes: vec!["*.log".to_string()],
+                saved_excludes: true,
+                quiet: true,
+                workers: Some(4),
+                project: true,
+                image: false,
+                verify: false,
+                events: None,
+            }))
+        );
+
+        match parse(&["analyze", "--no-excludes", "--", "-weird-name"]) {
+            Ok(Parsed::Run(args)) => {
+                assert_eq!(args.mode, Mode::Analyse);
+                assert!(!args.saved_excludes);
+                assert_eq!(args.paths, vec![PathBuf::from("-weird-name")]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["compare", "--no-excludes", "C:\\Games"]) {
+            Ok(Parsed::Run(args)) => assert_eq!(args.mode, Mode::Compare),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["convert", "D:\\Archive"]) {
+            Ok(Parsed::Run(args)) => assert_eq!(args.mode, Mode::Convert),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["forget", "D:\\Archive"]) {
+            Ok(Parsed::Run(args)) => assert_eq!(args.mode, Mode::Forget),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["recompress", "-c", "lzx", "D:\\Archive"]) {
+            Ok(Parsed::Run(args)) => {
+                assert_eq!(args.mode, Mode::Recompress);
+                assert_eq!(args.compression, Some(Compression::Lzx));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["analyse", "--image", "--verify", "golden.img"]) {
+            Ok(Parsed::Run(args)) => assert!(args.image && args.verify),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["query", "--json", "C:\\"]) {
+            Ok(Parsed::Run(args)) => assert_eq!(args.events, Some(PathBuf::from("-"))),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse(&["query", "--json-file", "out.json", "C:\\"]) {
+            Ok(Parsed::Run(args)) => assert_eq!(args.events, Some(PathBuf::from("out.json"))),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(parse(&["query", "--help"]), Ok(Parsed::Help));
+        assert_eq!(parse(&["-V"]), Ok(Parsed::Version));
+    }
+
+    #[test]
+    fn it_rejects_nonsense() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["compress"]).is_err());
+        assert!(parse(&["squash", "C:\\"]).is_err());
+        assert!(parse(&["compress", "-c", "zstd", "C:\\"]).is_err());
+        assert!(parse(&["compress", "C:\\", "--compression"]).is_err());
+        assert!(parse(&["compress", "--frobnicate", "C:\\"]).is_err());
+        assert!(parse(&["compress", "C:\\", "--json-file"]).is_err());
+        assert!(parse(&["compress", "-j", "0", "C:\\"]).is_err());
+        assert!(parse(&["compress", "-j", "lots", "C:\\"]).is_err());
+        assert!(parse(&["compress", "-i", "golden.img"]).is_err());
+        assert!(parse(&["analyse", "-i", "-p", "golden.img"]).is_err());
+        assert!(parse(&["analyse", "--verify", "C:\\"]).is_err());
+    }
+}
@@ -6,11 +6,17 @@
 //
 // These functions enable that, primarily for the purposes of displaying Rust
 // panics.
+//
+// Other platforms have no such distinction, so there they're all no-ops that
+// report an attached console.
 
+#[cfg(windows)]
 use winapi::um::consoleapi::AllocConsole;
+#[cfg(windows)]
 use winapi::um::wincon::{AttachConsole, FreeConsole, GetConsoleWindow, ATTACH_PARENT_PROCESS};
 
 /// Check if we're attached to an existing Windows console
+#[cfg(windows)]
 pub fn is_attached() -> bool {
     unsafe { !GetConsoleWindow().is_null() }
 }
@@ -20,6 +26,7 @@ pub fn is_attached() -> bool {
 /// It's normally a no-brainer to call this - it just makes println! and friends
 /// work as expected, without cluttering the screen with a console in the general
 /// case.
+#[cfg(windows)]
 pub fn attach() -> bool {
     if is_attached() {
         return true;
@@ -29,6 +36,7 @@ pub fn attach() -> bool {
 }
 
 /// Try to attach to a console, and if not, allocate ourselves a new one.
+#[cfg(windows)]
 pub fn alloc() -> bool {
     if attach() {
         return true;
@@ -38,6 +46,25 @@ pub fn alloc() -> bool {
 }
 
 /// Free any allocated console, if any.
+#[cfg(windows)]
 pub fn free() {
     unsafe { FreeConsole() };
 }
+
+#[cfg(not(windows))]
+pub fn is_attached() -> bool {
+    true
+}
+
+#[cfg(not(windows))]
+pub fn attach() -> bool {
+    is_attached()
+}
+
+#[cfg(not(windows))]
+pub fn alloc() -> bool {
+    attach()
+}
+
+#[cfg(not(windows))]
+pub fn free() {}
@@ -2,16 +2,19 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use crossbeam_channel::{bounded, Receiver};
+use crossbeam_channel::bounded;
 use dirs_sys::known_folder;
 use serde_derive::{Deserialize, Serialize};
 use web_view::*;
 use winapi::um::knownfolders;
 
-use crate::backend::Backend;
-use crate::config::Config;
-use crate::folder::FolderSummary;
-use crate::persistence::{self, config};
+use compactor_core::backend::Backend;
+use compactor_core::compact;
+use compactor_core::compare::FolderComparison;
+use compactor_core::config::Config;
+use compactor_core::folder::FolderSummary;
+use compactor_core::frontend::{Command, Frontend, State};
+use compactor_core::persistence::{self, config};
 
 // messages received from the GUI
 #[derive(Deserialize, Debug, Clone)]
@@ -24,11 +27,17 @@ pub enum GuiRequest {
         decimal: bool,
         compression: String,
         excludes: String,
+        workers: Option<usize>,
+        project_savings: bool,
     },
     ResetConfig,
     ChooseFolder,
     Compress,
     Decompress,
+    Convert,
+    Recompress,
+    Compare,
+    Forget,
     Pause,
     Resume,
     Analyse,
@@ -36,6 +45,26 @@ pub enum GuiRequest {
     Quit,
 }
 
+impl GuiRequest {
+    // The equivalent Backend command, if there is one.
+    fn command(&self) -> Option<Command> {
+        match self {
+            GuiRequest::ChooseFolder => Some(Command::ChooseFolder),
+            GuiRequest::Compress => Some(Command::Compress),
+            GuiRequest::Decompress => Some(Command::Decompress),
+            GuiRequest::Convert => Some(Command::Convert),
+            GuiRequest::Recompress => Some(Command::Recompress),
+            GuiRequest::Compare => Some(Command::Compare),
+            GuiRequest::Forget => Some(Command::Forget),
+            GuiRequest::Pause => Some(Command::Pause),
+            GuiRequest::Resume => Some(Command::Resume),
+            GuiRequest::Analyse => Some(Command::Analyse),
+            GuiRequest::Stop => Some(Command::Stop),
+            _ => None,
+        }
+    }
+}
+
 // messages to send to the GUI
 #[derive(Serialize)]
 #[serde(tag = "type")]
@@ -48,6 +77,8 @@ pub enum GuiResponse {
         decimal: bool,
         compression: String,
         excludes: String,
+        workers: Option<usize>,
+        project_savings: bool,
     },
     Folder {
         path: PathBuf,
@@ -59,11 +90,15 @@ pub enum GuiResponse {
     FolderSummary {
         info: FolderSummary,
     },
+    Comparison {
+        comparison: FolderComparison,
+    },
     Paused,
     Resumed,
     Scanned,
     Stopped,
     Compacting,
+    Comparing,
 }
 
 pub struct GuiWrapper<T>(Handle<T>);
@@ -100,47 +135,46 @@ impl<T> GuiWrapper<T> {
             decimal: s.decimal,
             compression: s.compression.to_string(),
             excludes: s.excludes.join("\n"),
+            workers: s.workers,
+            project_savings: s.project_savings,
         });
     }
+}
 
-    pub fn summary(&self, info: FolderSummary) {
+impl<T> Frontend for GuiWrapper<T> {
+    fn summary(&self, info: FolderSummary) {
         self.send(&GuiResponse::FolderSummary { info });
     }
 
-    pub fn status<S: AsRef<str>>(&self, msg: S, val: Option<f32>) {
+    fn comparison(&self, comparison: FolderComparison) {
+        self.send(&GuiResponse::Comparison { comparison });
+    }
+
+    fn status(&self, msg: &str, val: Option<f32>) {
         self.send(&GuiResponse::Status {
-            status: msg.as_ref().to_owned(),
+            status: msg.to_owned(),
             pct: val,
         });
     }
 
-    pub fn folder<P: AsRef<Path>>(&self, path: P) {
+    fn folder(&self, path: &Path) {
         self.send(&GuiResponse::Folder {
-            path: path.as_ref().to_path_buf(),
+            path: path.to_path_buf(),
         });
     }
 
-    pub fn paused(&self) {
-        self.send(&GuiResponse::Paused);
-    }
-
-    pub fn resumed(&self) {
-        self.send(&GuiResponse::Resumed);
-    }
-
-    pub fn scanned(&self) {
-        self.send(&GuiResponse::Scanned);
-    }
-
-    pub fn stopped(&self) {
-        self.send(&GuiResponse::Stopped);
-    }
-
-    pub fn compacting(&self) {
-        self.send(&GuiResponse::Compacting);
+    fn state(&self, state: State) {
+        self.send(&match state {
+            State::Compacting => GuiResponse::Compacting,
+            State::Comparing => GuiResponse::Comparing,
+            State::Paused => GuiResponse::Paused,
+            State::Resumed => GuiResponse::Resumed,
+            State::Scanned => GuiResponse::Scanned,
+            State::Stopped => GuiResponse::Stopped,
+        });
     }
 
-    pub fn choose_folder(&self) -> Receiver<Option<PathBuf>> {
+    fn choose_folder(&self) -> Option<PathBuf> {
         let (tx, rx) = bounded::<Option<PathBuf>>(1);
         let _ = self.0.dispatch(move |_| {
             let folder = known_folder(&knownfolders::FOLDERID_ProgramFiles);
@@ -157,7 +191,7 @@ impl<T> GuiWrapper<T> {
             Ok(())
         });
 
-        rx
+        rx.recv().ok().flatten()
     }
 }
 
@@ -179,7 +213,7 @@ pub fn spawn_gui() {
         )
     );
 
-    let (from_gui, from_gui_rx) = bounded::<GuiRequest>(128);
+    let (from_gui, from_gui_rx) = bounded::<Command>(128);
 
     let mut webview = web_view::builder()
         .title("Compactor")
@@ -197,11 +231,15 @@ pub fn spawn_gui() {
                     decimal,
                     compression,
                     excludes,
+                    workers,
+                    project_savings,
                 }) => {
                     let s = Config {
                         decimal,
                         compression: compression.parse().unwrap_or_default(),
                         excludes: excludes.split('\n').map(str::to_owned).collect(),
+                        workers,
+                        project_savings,
                     };
 
                     if let Err(msg) = s.globset() {
@@ -217,6 +255,8 @@ pub fn spawn_gui() {
                                 decimal: s.decimal,
                                 compression: s.compression.to_string(),
                                 excludes: s.excludes.join("\n"),
+                                workers: s.workers,
+                                project_savings: s.project_savings,
                             },
                         );
                         let c = config();
@@ -240,6 +280,8 @@ pub fn spawn_gui() {
                             decimal: s.decimal,
                             compression: s.compression.to_string(),
                             excludes: s.excludes.join("\n"),
+                            workers: s.workers,
+                            project_savings: s.project_savings,
                         },
                     );
                     let c = config();
@@ -253,9 +295,10 @@ pub fn spawn_gui() {
                         );
                     }
                 }
-                Ok(msg) => {
-                    from_gui.send(msg).expect("GUI message queue");
-                }
+                Ok(msg) => match msg.command() {
+                    Some(command) => from_gui.send(command).expect("GUI message queue"),
+                    None => eprintln!("Ignored message: {:?}", msg),
+                },
                 Err(err) => {
                     eprintln!("Unhandled message {:?}: {:?}", arg, err);
                 }
@@ -269,7 +312,7 @@ pub fn spawn_gui() {
     persistence::init();
 
     let gui = GuiWrapper::new(webview.handle());
-    let mut backend = Backend::new(gui, from_gui_rx);
+    let mut backend = Backend::new(gui, from_gui_rx, compact::default_backend());
     let bg = std::thread::spawn(move || {
         backend.run();
     });
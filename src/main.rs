@@ -2,15 +2,12 @@
 #![cfg_attr(test, windows_subsystem = "console")]
 #![allow(non_snake_case)]
 
-mod backend;
-mod background;
-mod compact;
-mod compression;
-mod config;
+use std::ffi::OsString;
+
+mod cli;
 mod console;
-mod folder;
+#[cfg(windows)]
 mod gui;
-mod persistence;
 
 fn setup_panic() {
     std::panic::set_hook(Box::new(|e| {
@@ -35,7 +32,7 @@ App: {app}, Version: {ver}, Build Date: {date}, Hash: {hash}
             app = env!("CARGO_PKG_NAME"),
             website = env!("CARGO_PKG_HOMEPAGE"),
             ver = env!("VERGEN_SEMVER"),
-            date = env!("VERGEN_BUILD_DATE").to_string(),
+            date = env!("VERGEN_BUILD_DATE"),
             hash = env!("VERGEN_SHA_SHORT")
         );
 
@@ -61,10 +58,25 @@ App: {app}, Version: {ver}, Build Date: {date}, Hash: {hash}
 fn main() {
     setup_panic();
     console::attach();
-    let ret = std::panic::catch_unwind(gui::spawn_gui);
+    let args = std::env::args_os().skip(1).collect::<Vec<_>>();
+    let ret = std::panic::catch_unwind(|| run(args));
     console::free();
 
-    if ret.is_err() {
-        std::process::exit(1);
+    std::process::exit(ret.unwrap_or(cli::EXIT_FAILURE));
+}
+
+// Without arguments we're probably being launched from Explorer.
+#[cfg(windows)]
+fn run(args: Vec<OsString>) -> i32 {
+    if args.is_empty() {
+        gui::spawn_gui();
+        cli::EXIT_SUCCESS
+    } else {
+        cli::main(args)
     }
 }
+
+#[cfg(not(windows))]
+fn run(args: Vec<OsString>) -> i32 {
+    cli::main(args)
+}
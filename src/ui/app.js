@@ -172,6 +172,22 @@ var Action = (function() {
 			external.invoke(JSON.stringify({ type: 'Decompress' }));
 		},
 
+		convert: function() {
+			external.invoke(JSON.stringify({ type: 'Convert' }));
+		},
+
+		recompress: function() {
+			external.invoke(JSON.stringify({ type: 'Recompress' }));
+		},
+
+		forget: function() {
+			external.invoke(JSON.stringify({ type: 'Forget' }));
+		},
+
+		compare: function() {
+			external.invoke(JSON.stringify({ type: 'Compare' }));
+		},
+
 		pause: function() {
 			external.invoke(JSON.stringify({ type: 'Pause' }));
 		},
@@ -205,6 +221,8 @@ var Response = (function() {
 					Gui.set_decimal(msg.decimal);
 					Gui.set_compression(msg.compression);
 					Gui.set_excludes(msg.excludes);
+					Gui.set_workers(msg.workers);
+					Gui.set_project_savings(msg.project_savings);
 					break;
 
 				case "Folder":
@@ -224,9 +242,14 @@ var Response = (function() {
 				case "Stopped":
 				case "Scanned":
 				case "Compacting":
+				case "Comparing":
 					Gui[msg.type.toLowerCase()]();
 					break;
 
+				case "Comparison":
+					Gui.set_comparison(msg.comparison);
+					break;
+
 				case "FolderSummary":
 					Gui.set_folder_summary(msg.info);
 					break;
@@ -239,6 +262,48 @@ var Response = (function() {
 var Gui = (function() {
 	"use strict";
 
+	// Labels for FolderSummary's skip_reasons, in display order
+	var skip_reasons = {
+		excluded: "excluded",
+		incompressible: "incompressible",
+		small: "too small",
+		read_only: "read-only",
+		system: "system",
+		temporary: "temporary",
+		failed: "failed"
+	};
+
+	// Labels for FolderSummary's algorithms, in display order
+	var algorithms = {
+		Xpress4k: "XPRESS4K",
+		Xpress8k: "XPRESS8K",
+		Xpress16k: "XPRESS16K",
+		Lzx: "LZX"
+	};
+
+	// Algorithms used in the current folder, from the last summary
+	var folder_algorithms = {};
+
+	// Whether any files in the current folder were skipped as incompressible
+	var folder_incompressible = false;
+
+	// Rows of each group shown in the comparison table
+	var comparison_rows = 5;
+
+	var comparison_row = function(name, group, heading) {
+		var row = $("<tr>").toggleClass("heading", !!heading);
+		row.append($("<th>").text(name));
+		row.append($("<td>").text(Util.bytes_to_human(group.physical_size)));
+		group.projections.forEach(function(p) {
+			var cell = $("<td>").text(Util.bytes_to_human(p.physical_size));
+			cell.append($("<small>").text(" " + Util.format_number(p.cpu_secs, 1) + "s"));
+			cell.toggleClass("best", p.compression === group.recommended);
+			row.append(cell);
+		});
+		row.append($("<td>").text(group.recommended ? group.recommended.toUpperCase() : "-"));
+		return row;
+	};
+
 	return {
 		boot: function() {
 			$("a[href]").on("click", function(e) {
@@ -251,7 +316,9 @@ var Gui = (function() {
 				Action.save_config({
 				  decimal: $("#SI_Units").val() == "D",
 					compression: $("#Compression_Mode").val(),
-					excludes: $("#Excludes").val()
+					excludes: $("#Excludes").val(),
+					workers: parseInt($("#Workers").val(), 10) || null,
+					project_savings: $("#Project_Savings").val() == "1"
 				});
 			});
 
@@ -291,6 +358,14 @@ var Gui = (function() {
 			$("#Excludes").val(excludes);
 		},
 
+		set_workers: function(workers) {
+			$("#Workers").val(String(workers || 0));
+		},
+
+		set_project_savings: function(project) {
+			$("#Project_Savings").val(project ? "1" : "0");
+		},
+
 		set_folder: function(folder) {
 			var bits = folder.split(/:\\|\\/).map(function(x) { return document.createTextNode(x); });
 			var end = bits.pop();
@@ -323,8 +398,13 @@ var Gui = (function() {
 			$("#Button_Resume").hide();
 			$("#Button_Stop").show();
 			$("#Button_Analyse").hide();
+			$("#Button_Compare").hide();
 			$("#Button_Compress").hide();
 			$("#Button_Decompress").hide();
+			$("#Button_Convert").hide();
+			$("#Button_Recompress").hide();
+			$("#Button_Forget").hide();
+			$("#Comparison").hide();
 			$("#Command").show();
 		},
 
@@ -333,8 +413,17 @@ var Gui = (function() {
 			$("#Button_Resume").hide();
 			$("#Button_Stop").show();
 			$("#Button_Analyse").hide();
+			$("#Button_Compare").hide();
 			$("#Button_Compress").hide();
 			$("#Button_Decompress").hide();
+			$("#Button_Convert").hide();
+			$("#Button_Recompress").hide();
+			$("#Button_Forget").hide();
+			$("#Comparison").hide();
+		},
+
+		comparing: function() {
+			Gui.compacting();
 		},
 
 		paused: function() {
@@ -358,8 +447,10 @@ var Gui = (function() {
 			$("#Button_Analyse").show();
 
 			if ($("#File_Count_Compressible").text() != "0") {
+				$("#Button_Compare").show();
 				$("#Button_Compress").show();
 			} else {
+				$("#Button_Compare").hide();
 				$("#Button_Compress").hide();
 			}
 
@@ -368,15 +459,45 @@ var Gui = (function() {
 			} else {
 				$("#Button_Decompress").hide();
 			}
+
+			if ($("#File_Count_Ntfs_Compressed").text() != "0") {
+				$("#Button_Convert").show();
+			} else {
+				$("#Button_Convert").hide();
+			}
+
+			// Worth offering if anything uses an algorithm other than the chosen one
+			var compression = $("#Compression_Mode").val();
+			var recompressible = Object.keys(folder_algorithms).some(function(alg) {
+				return alg !== compression;
+			});
+			if (recompressible) {
+				$("#Button_Recompress").show();
+			} else {
+				$("#Button_Recompress").hide();
+			}
+
+			if (folder_incompressible) {
+				$("#Button_Forget").show();
+			} else {
+				$("#Button_Forget").hide();
+			}
 		},
 
 		reset_folder_summary: function() {
 			Gui.set_folder_summary({
 				logical_size: 0,
 				physical_size: 0,
-				compressed: {count: 0, logical_size: 0, physical_size: 0},
-				compressible: {count: 0, logical_size: 0, physical_size: 0},
-				skipped: {count: 0, logical_size: 0, physical_size: 0}
+				compressed: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				compressible: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				skipped: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				wim: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				sparse: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				ntfs_compressed: {count: 0, logical_size: 0, physical_size: 0, projected_size: 0},
+				skip_reasons: {},
+				algorithms: {},
+				errors: 0,
+				projection: null
 			});
 		},
 
@@ -406,6 +527,88 @@ var Gui = (function() {
 			$("#File_Count_Compressed").text(Util.format_number(data.compressed.count, 0));
 			$("#File_Count_Compressible").text(Util.format_number(data.compressible.count, 0));
 			$("#File_Count_Skipped").text(Util.format_number(data.skipped.count, 0));
+			var compressed = "";
+			for (var alg in algorithms) {
+				var group = data.algorithms[alg];
+				if (group) {
+					compressed += "<div>" + Util.bytes_to_human(group.physical_size) + " in " + Util.format_number(group.count, 0) + " with " + algorithms[alg] + "</div>";
+				}
+			}
+			document.getElementById("Algorithms").innerHTML = compressed;
+			folder_algorithms = data.algorithms;
+			folder_incompressible = 'incompressible' in data.skip_reasons;
+
+			if (data.wim.count > 0) {
+				$("#Wim_Size").text(Util.bytes_to_human(data.wim.physical_size));
+				$("#File_Count_Wim").text(Util.format_number(data.wim.count, 0));
+				$("#Analysis .wim").show();
+			} else {
+				$("#Analysis .wim").hide();
+			}
+
+			if (data.sparse.count > 0) {
+				$("#Sparse_Size").text(Util.bytes_to_human(data.sparse.physical_size));
+				$("#File_Count_Sparse").text(Util.format_number(data.sparse.count, 0));
+				$("#Analysis .sparse").show();
+			} else {
+				$("#Analysis .sparse").hide();
+			}
+
+			$("#File_Count_Ntfs_Compressed").text(Util.format_number(data.ntfs_compressed.count, 0));
+			if (data.ntfs_compressed.count > 0) {
+				$("#Ntfs_Compressed_Size").text(Util.bytes_to_human(data.ntfs_compressed.physical_size));
+				$("#Analysis .ntfs_compressed").show();
+			} else {
+				$("#Analysis .ntfs_compressed").hide();
+			}
+
+			var reasons = "";
+			for (var reason in skip_reasons) {
+				var group = data.skip_reasons[reason];
+				if (group) {
+					reasons += "<div>" + Util.bytes_to_human(group.physical_size) + " in " + Util.format_number(group.count, 0) + " " + skip_reasons[reason] + "</div>";
+				}
+			}
+			document.getElementById("Skip_Reasons").innerHTML = reasons;
+
+			if (data.projection) {
+				$("#Projected_Savings").text(Util.bytes_to_human(data.compressible.physical_size - data.compressible.projected_size));
+				$("#Projected_Compression").text(data.projection.toUpperCase());
+				$("#Analysis .projected").show();
+			} else {
+				$("#Analysis .projected").hide();
+			}
+
+			$("#File_Count_Unreadable").text(Util.format_number(data.errors, 0));
+
+			if (data.errors > 0) {
+				$("#Analysis .unreadable").show();
+			} else {
+				$("#Analysis .unreadable").hide();
+			}
+		},
+
+		set_comparison: function(data) {
+			var rows = $("#Comparison_Rows");
+			rows.empty();
+			rows.append(comparison_row("Total", data.total, true));
+
+			[["Folders", data.folders], ["Extensions", data.extensions]].forEach(function(section) {
+				var groups = section[1];
+				if (groups.length > 1) {
+					rows.append($("<tr class='heading'>").append($("<th colspan='7'>").text(section[0])));
+					groups.slice(0, comparison_rows).forEach(function(group) {
+						var name = group.name;
+						if (section[1] === data.extensions) {
+							name = name ? "." + name : "(none)";
+						}
+						rows.append(comparison_row(name, group));
+					});
+				}
+			});
+
+			$("#Comparison_Sample").text(Util.bytes_to_human(data.total.sample_size) + " of " + Util.bytes_to_human(data.total.logical_size) + " sampled; CPU times are estimates for a single core");
+			$("#Comparison").show();
 		},
 
 		analysis_complete: function() {